@@ -4,8 +4,8 @@ use std::rc::Rc;
 
 use crate::service::meta_command::{MetaCommandResult, MetaCommandService};
 use crate::service::prepare_statement::{PrepareResult, PrepareService};
-use crate::service::executor::{ExecuteResult, Executor};
-use crate::service::Statement;
+use crate::service::executor::{ExecuteResult, Executor, RowOutput};
+use crate::service::{Row, Statement};
 use crate::db::table::Table;
 
 use rustyline::error::ReadlineError;
@@ -39,7 +39,28 @@ impl Validator for EditHelper {
     }
 }
 
-pub fn run_loop(table: Rc<Table>)
+/* Prints a select result, the column names as a header above its first row */
+struct RowPrinter {
+    columns: Vec<String>,
+    header_printed: bool,
+}
+
+impl RowOutput for RowPrinter {
+    fn columns(&mut self, names: &[String]) {
+        self.columns = names.to_vec();
+        self.header_printed = false;
+    }
+
+    fn row(&mut self, row: &Row) {
+        if !self.header_printed {
+            println!("{}", self.columns.join("|"));
+            self.header_printed = true;
+        }
+        println!("{}", row);
+    }
+}
+
+pub fn run_loop(table: Rc<Table>, sort_memory: usize)
 {
     let mut rl = Editor::new();
     let edit_helper = EditHelper::new();
@@ -74,30 +95,73 @@ pub fn run_loop(table: Rc<Table>)
 
                 // prepare statement service
                 let prepare_service = PrepareService::new();
-                let mut stmt = Statement::new();
-                let res = prepare_service.prepare_statement(line.as_str(), &mut stmt);
+                let nodes = match prepare_service.parse_statements(line.as_str()) {
+                    Ok(nodes) => nodes,
+                    Err(res) => {
+                        println!("{}", res);
+                        continue;
+                    }
+                };
 
-                if !matches!(res, PrepareResult::PrepareSuccess) {
-                    println!("{}", res);
-                    continue;
-                }
-                // println!("{:?}", stmt);
+                /*
+                Prepare each statement right before it runs, so it sees
+                the schema left by the ones before it.
+                */
+                let executor = Executor::new(sort_memory);
+                for node in &nodes {
+                    let mut stmt = Statement::new();
+                    match prepare_service.prepare_statement(node, table.clone(), &mut stmt) {
+                        Ok(PrepareResult::PrepareSuccess) => {}
+                        Ok(res) => {
+                            println!("{}", res);
+                            break;
+                        }
+                        Err(e) => {
+                            println!("Unexpected error: {:?}", e);
+                            break;
+                        }
+                    }
 
-                // execute statement
-                let executor = Executor::new();
-                match executor.execute_statement(&stmt, table.clone()) {
-                    Ok(exec_res) => {
-                        match exec_res {
-                            ExecuteResult::ExecuteSuccess => {
-                                println!("Executed.");
-                            },
-                            ExecuteResult::ExecuteDuplicateKey => {
-                                println!()
+                    // execute statement
+                    let mut printer = RowPrinter {
+                        columns: Vec::new(),
+                        header_printed: false,
+                    };
+                    match executor.execute_statement(&stmt, table.clone(), &mut printer) {
+                        Ok(exec_res) => {
+                            match exec_res {
+                                ExecuteResult::ExecuteSuccess => {
+                                    println!("Executed.");
+                                },
+                                ExecuteResult::ExecuteDuplicateKey => {
+                                    println!("Error: Duplicate key.");
+                                }
+                                ExecuteResult::ExecuteTableFull => {
+                                    println!("Error: Table full.");
+                                }
+                                ExecuteResult::ExecuteRowsAffected(num_rows) => {
+                                    println!("Executed. {} row(s) affected.", num_rows);
+                                }
+                                ExecuteResult::ExecuteNestedTransaction => {
+                                    println!("Error: Cannot start a transaction within a transaction.");
+                                }
+                                ExecuteResult::ExecuteNoTransaction => {
+                                    println!("Error: No transaction is active.");
+                                }
+                                ExecuteResult::ExecuteNoSuchSavepoint(name) => {
+                                    println!("Error: No such savepoint: {}.", name);
+                                }
+                                ExecuteResult::ExecuteUniqueFailed(columns) => {
+                                    println!("Error: UNIQUE constraint failed: {}.", columns);
+                                }
+                                ExecuteResult::ExecuteInvalid(reason) => {
+                                    println!("Error: {}.", reason);
+                                }
                             }
                         }
-                    }
-                    Err(e) => {
-                        println!("Unexpected error: {:?}", e);
+                        Err(e) => {
+                            println!("Unexpected error: {:?}", e);
+                        }
                     }
                 }
             }
@@ -0,0 +1,132 @@
+use std::mem;
+
+use libc::c_void;
+
+use super::error::{DbError, DbResult};
+use super::header::{catalog_entries, catalog_num_entries, CATALOG_SPACE_FOR_ENTRIES};
+
+/*
+ * Catalog Entry Layout
+ *
+ * Entry type and root page, then the name, the table name and the sql
+ * of the entry, each as a length followed by that many bytes.
+ */
+const CATALOG_ENTRY_TYPE_SIZE: usize = mem::size_of::<u8>();
+const CATALOG_ENTRY_ROOT_PAGE_SIZE: usize = mem::size_of::<u32>();
+const CATALOG_ENTRY_LENGTH_SIZE: usize = mem::size_of::<u32>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatalogEntryType {
+    CatalogTable,
+    CatalogIndex,
+}
+
+/*
+One tree of the database, like a row of sqlite_master. For a table
+the table name is its own name, for an index the table it indexes.
+*/
+#[derive(Debug, Clone)]
+pub struct CatalogEntry {
+    pub entry_type: CatalogEntryType,
+    pub name: String,
+    pub table_name: String,
+    pub root_page_num: usize,
+    pub sql: String,
+}
+
+impl CatalogEntry {
+    fn size(&self) -> usize {
+        CATALOG_ENTRY_TYPE_SIZE
+            + CATALOG_ENTRY_ROOT_PAGE_SIZE
+            + 3 * CATALOG_ENTRY_LENGTH_SIZE
+            + self.name.len()
+            + self.table_name.len()
+            + self.sql.len()
+    }
+}
+
+fn corrupt<T>() -> DbResult<T> {
+    Err(DbError::Other("Catalog is corrupt.".to_string()))
+}
+
+/* Reads the entries of page 0 front to back */
+struct CatalogReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> CatalogReader<'a> {
+    fn take(&mut self, size: usize) -> DbResult<&'a [u8]> {
+        if self.offset + size > self.bytes.len() {
+            return corrupt();
+        }
+        let bytes = &self.bytes[self.offset..(self.offset + size)];
+        self.offset += size;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> DbResult<u32> {
+        Ok(u32::from_le_bytes(self.take(mem::size_of::<u32>())?.try_into().unwrap()))
+    }
+
+    fn read_string(&mut self) -> DbResult<String> {
+        let length = self.read_u32()? as usize;
+        match String::from_utf8(self.take(length)?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => corrupt(),
+        }
+    }
+}
+
+pub fn read_catalog(page: *mut c_void) -> DbResult<Vec<CatalogEntry>> {
+    let num_entries = unsafe { *catalog_num_entries(page) } as usize;
+    let mut reader = CatalogReader {
+        bytes: unsafe { std::slice::from_raw_parts(catalog_entries(page), CATALOG_SPACE_FOR_ENTRIES) },
+        offset: 0,
+    };
+
+    let mut entries = Vec::with_capacity(num_entries);
+    for _ in 0..num_entries {
+        let entry_type = match reader.take(CATALOG_ENTRY_TYPE_SIZE)?[0] {
+            0 => CatalogEntryType::CatalogTable,
+            1 => CatalogEntryType::CatalogIndex,
+            _ => return corrupt(),
+        };
+        let root_page_num = reader.read_u32()? as usize;
+        entries.push(CatalogEntry {
+            entry_type,
+            root_page_num,
+            name: reader.read_string()?,
+            table_name: reader.read_string()?,
+            sql: reader.read_string()?,
+        });
+    }
+    Ok(entries)
+}
+
+/* Rewrite the whole catalog, it has to fit in what page 0 has left */
+pub fn write_catalog(page: *mut c_void, entries: &[CatalogEntry]) -> DbResult<()> {
+    let size: usize = entries.iter().map(|entry| entry.size()).sum();
+    if size > CATALOG_SPACE_FOR_ENTRIES {
+        return Err(DbError::Other("Catalog is full.".to_string()));
+    }
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(size);
+    for entry in entries {
+        bytes.push(match entry.entry_type {
+            CatalogEntryType::CatalogTable => 0,
+            CatalogEntryType::CatalogIndex => 1,
+        });
+        bytes.extend_from_slice(&(entry.root_page_num as u32).to_le_bytes());
+        for string in [&entry.name, &entry.table_name, &entry.sql] {
+            bytes.extend_from_slice(&(string.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(string.as_bytes());
+        }
+    }
+
+    unsafe {
+        libc::memcpy(catalog_entries(page) as *mut c_void, bytes.as_ptr() as *const c_void, bytes.len());
+        *catalog_num_entries(page) = entries.len() as u32;
+    }
+    Ok(())
+}
@@ -1,30 +1,53 @@
 use core::num;
+use std::cmp::Ordering;
 use std::rc::Rc;
-use std::cell::RefCell;
 
 use libc::c_void;
 
 use super::table::Table;
 use crate::db::error::{DbError, DbResult};
 use crate::db::tree::*;
-use crate::service::Row;
 
+/*
+A cursor keeps the page it points at pinned in the page cache,
+so the page cannot be evicted while the cursor is alive. The keys of
+the tree it walks are ordered by its comparator.
+*/
 pub struct Cursor {
     table: Rc<Table>,
+    comparator: Rc<dyn KeyComparator>,
     pub page_num: usize,
     pub cell_num: usize,
     pub end_of_table: bool,
 }
 
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        self.table.pager.borrow_mut().unpin_page(self.page_num);
+    }
+}
+
 impl Cursor {
-    fn leaf_node_find(table: Rc<Table>, page_num: usize, key: u32) -> DbResult<Self> {
+    fn move_to_page(&mut self, page_num: usize) -> DbResult<()> {
+        let mut pager = self.table.pager.borrow_mut();
+        pager.pin_page(page_num)?;
+        pager.unpin_page(self.page_num);
+        self.page_num = page_num;
+        self.cell_num = 0;
+        Ok(())
+    }
+
+    fn leaf_node_find(table: Rc<Table>, comparator: Rc<dyn KeyComparator>, page_num: usize,
+        key: &[u8]) -> DbResult<Self> {
         let node = table.pager.borrow_mut().get_page(page_num)?;
         let num_cells = unsafe{
             *leaf_node_num_cells(node)
         };
 
+        table.pager.borrow_mut().pin_page(page_num)?;
         let mut cursor = Cursor {
             table: table,
+            comparator,
             page_num: page_num,
             cell_num: 0,
             end_of_table:false,
@@ -36,17 +59,14 @@ impl Cursor {
 
         while one_past_max_index != min_index {
             let index: u32 = min_index + (one_past_max_index - min_index) / 2;
-            let key_at_index: u32 = unsafe {
-                *leaf_node_key(node, index as usize)
-            };
-            if key == key_at_index {
-            cursor.cell_num = index as usize;
-            return Ok(cursor);
-            }
-            if key < key_at_index {
-            one_past_max_index = index;
-            } else {
-            min_index = index + 1;
+            let key_at_index = leaf_node_key(node, index as usize);
+            match cursor.comparator.compare(key, &key_at_index)? {
+                Ordering::Equal => {
+                    cursor.cell_num = index as usize;
+                    return Ok(cursor);
+                }
+                Ordering::Less => one_past_max_index = index,
+                Ordering::Greater => min_index = index + 1,
             }
         }
 
@@ -55,46 +75,98 @@ impl Cursor {
         Ok(cursor)
     }
 
-    fn internal_node_find(table: Rc<Table>, page_num: usize, key: u32) -> DbResult<Self> {
+    fn internal_node_find(table: Rc<Table>, comparator: Rc<dyn KeyComparator>, page_num: usize,
+        key: &[u8]) -> DbResult<Self> {
         let node = table.pager.borrow_mut().get_page(page_num)?;
       
-        let child_index: u32 = internal_node_find_child(node, key);
+        let child_index: u32 = internal_node_find_child(node, key, &*comparator)?;
         let child_num: u32 = unsafe {
             *internal_node_child(node, child_index as usize)?
         };
         let child = table.pager.borrow_mut().get_page(child_num as usize)?;
         match get_node_type(child) {
-            NodeType::NodeLeaf => Cursor::leaf_node_find(table, page_num, key),
-            NodeType::NodeInternal => Cursor::internal_node_find(table, child_num as usize, key)
+            NodeType::NodeLeaf => Cursor::leaf_node_find(table, comparator, child_num as usize, key),
+            NodeType::NodeInternal => Cursor::internal_node_find(table, comparator, child_num as usize, key)
         }
     }
 
-    pub fn table_find(table: Rc<Table>, key: u32) -> DbResult<Self> {
-        let root_page_num = table.root_page_num as usize;
+    pub fn tree_find(table: Rc<Table>, comparator: Rc<dyn KeyComparator>, root_page_num: usize,
+        key: &[u8]) -> DbResult<Self> {
         let root_node = table.pager.borrow_mut().get_page(root_page_num)?;
 
         match get_node_type(root_node as *const c_void) {
-            NodeType::NodeLeaf => Cursor::leaf_node_find(table, root_page_num, key),
-            NodeType::NodeInternal => Cursor::internal_node_find(table, root_page_num, key)
+            NodeType::NodeLeaf => Cursor::leaf_node_find(table, comparator, root_page_num, key),
+            NodeType::NodeInternal => Cursor::internal_node_find(table, comparator, root_page_num, key)
         }
     }
 
-    pub fn table_start(table: Rc<Table>) -> DbResult<Self> {
-        let mut cursor = Cursor::table_find(table.clone(), 0)?;
+    pub fn tree_seek(table: Rc<Table>, comparator: Rc<dyn KeyComparator>, root_page_num: usize,
+        key: &[u8]) -> DbResult<Self> {
+        /*
+        Position the cursor at the first cell whose key is >= key.
+        tree_find may stop one past the last cell of a leaf, in which
+        case the next leaf holds the wanted cell.
+        */
+        let mut cursor = Cursor::tree_find(table.clone(), comparator, root_page_num, key)?;
 
         let node = table.pager.borrow_mut().get_page(cursor.page_num)?;
-        let num_cells: u32 = unsafe{
-            *leaf_node_num_cells(node)
-        };
-        cursor.end_of_table = num_cells == 0;
+        let num_cells = unsafe { *leaf_node_num_cells(node) as usize };
+        if cursor.cell_num >= num_cells {
+            let next_page_num = unsafe { *leaf_node_next_leaf(node) as usize };
+            if next_page_num == 0 {
+                cursor.end_of_table = true;
+            } else {
+                cursor.move_to_page(next_page_num)?;
+            }
+        }
 
         Ok(cursor)
     }
 
-    pub fn cursor_value(&self) -> DbResult<*mut c_void> {
+    /* Find and seek in a table tree, by row id */
+    pub fn table_find(table: Rc<Table>, root_page_num: usize, rowid: i64) -> DbResult<Self> {
+        Cursor::tree_find(table, Rc::new(RowidComparator), root_page_num, &rowid_key(rowid))
+    }
+
+    pub fn table_seek(table: Rc<Table>, root_page_num: usize, rowid: i64) -> DbResult<Self> {
+        Cursor::tree_seek(table, Rc::new(RowidComparator), root_page_num, &rowid_key(rowid))
+    }
+
+    pub fn cursor_value(&self) -> DbResult<Vec<u8>> {
+        /* A large value is its local prefix followed by its overflow chain */
         let page_num = self.page_num;
         let page: *mut c_void = self.table.pager.borrow_mut().get_page(page_num)?;
-        Ok(leaf_node_value(page, self.cell_num))
+        let key_size = unsafe { *leaf_node_key_size(page, self.cell_num) } as usize;
+        let value_size = unsafe { *leaf_node_value_size(page, self.cell_num) } as usize;
+        let local_size = leaf_node_local_size(key_size, value_size);
+        let mut value = unsafe {
+            std::slice::from_raw_parts(leaf_node_value(page, self.cell_num) as *const u8, local_size).to_vec()
+        };
+        if leaf_node_has_overflow(key_size, value_size) {
+            let overflow_page_num = unsafe { *leaf_node_overflow_page(page, self.cell_num) };
+            value.extend(self.table.read_overflow(overflow_page_num, value_size - local_size)?);
+        }
+        Ok(value)
+    }
+
+    pub fn cursor_key(&self) -> DbResult<Vec<u8>> {
+        let page: *mut c_void = self.table.pager.borrow_mut().get_page(self.page_num)?;
+        Ok(leaf_node_key(page, self.cell_num))
+    }
+
+    /* Key of a cursor on a table tree */
+    pub fn cursor_rowid(&self) -> DbResult<i64> {
+        key_rowid(&self.cursor_key()?)
+    }
+
+    /* True when the cursor is on a cell with exactly this key */
+    pub fn cursor_matches(&self, key: &[u8]) -> DbResult<bool> {
+        let page: *mut c_void = self.table.pager.borrow_mut().get_page(self.page_num)?;
+        let num_cells = unsafe { *leaf_node_num_cells(page) } as usize;
+        if self.end_of_table || self.cell_num >= num_cells {
+            return Ok(false);
+        }
+        Ok(self.comparator.compare(&leaf_node_key(page, self.cell_num), key)? == Ordering::Equal)
     }
 
     pub fn cursor_advance(&mut self) -> DbResult<()> {
@@ -107,103 +179,254 @@ impl Cursor {
             if next_page_num == 0 {
                 self.end_of_table = true;
             } else {
-                self.page_num = next_page_num;
-                self.cell_num = 0;
+                self.move_to_page(next_page_num)?;
+            }
+        }
+
+        /* Long scans only ever hold the pinned cursor page */
+        self.table.pager.borrow_mut().shrink_cache()?;
+
+        Ok(())
+    }
+
+    /*
+    Move past count cells. Internal nodes know how many cells are below
+    them, so the cursor climbs to the root adding up the cells before it
+    and comes down to the cell count places further, without reading the
+    leaves in between. Returns how many cells were passed, fewer than
+    count when the table ends first.
+    */
+    pub fn cursor_skip(&mut self, count: u64) -> DbResult<u64> {
+        if self.end_of_table || count == 0 {
+            return Ok(0);
+        }
+
+        let mut pager = self.table.pager.borrow_mut();
+        let mut position = self.cell_num as u64;
+        let mut page_num = self.page_num;
+        let mut node = pager.get_page(page_num)?;
+        while !is_node_root(node) {
+            let parent_page_num = unsafe { *node_parent(node) } as usize;
+            let parent = pager.get_page(parent_page_num)?;
+            for i in 0..internal_node_child_index(parent, page_num as u32)? as usize {
+                let child_page_num = unsafe { *internal_node_child(parent, i)? };
+                position += node_num_cells(pager.get_page(child_page_num as usize)?) as u64;
+            }
+            page_num = parent_page_num;
+            node = parent;
+        }
+
+        let cells_left = (node_num_cells(node) as u64).saturating_sub(position);
+        if count >= cells_left {
+            drop(pager);
+            self.end_of_table = true;
+            return Ok(cells_left);
+        }
+
+        let mut position = position + count;
+        while let NodeType::NodeInternal = get_node_type(node) {
+            let num_keys = unsafe { *internal_node_num_keys(node) } as usize;
+            for i in 0..(num_keys + 1) {
+                page_num = unsafe { *internal_node_child(node, i)? } as usize;
+                let child = pager.get_page(page_num)?;
+                let child_num_cells = node_num_cells(child) as u64;
+                if position < child_num_cells || i == num_keys {
+                    node = child;
+                    break;
+                }
+                position -= child_num_cells;
             }
         }
+        drop(pager);
+
+        self.move_to_page(page_num)?;
+        self.cell_num = position as usize;
+        self.table.pager.borrow_mut().shrink_cache()?;
+        Ok(count)
+    }
+
+    fn build_cell(&self, key: &[u8], value: &[u8]) -> DbResult<Vec<u8>> {
+        /*
+        Image of the cell for key and value, the part of the value
+        that does not fit in it goes to new overflow pages.
+        */
+        if key.len() > MAX_KEY_SIZE {
+            return Err(DbError::Other(format!("Key of {} bytes is too large.", key.len())));
+        }
+        let local_size = leaf_node_local_size(key.len(), value.len());
+        let overflow_page_num = match leaf_node_has_overflow(key.len(), value.len()) {
+            true => self.table.write_overflow(&value[local_size..])?,
+            false => 0,
+        };
+        Ok(leaf_node_build_cell(key, value.len(), &value[..local_size], overflow_page_num))
+    }
 
+    fn free_cell_overflow(&self, node: *mut c_void) -> DbResult<()> {
+        let key_size = unsafe { *leaf_node_key_size(node, self.cell_num) } as usize;
+        let value_size = unsafe { *leaf_node_value_size(node, self.cell_num) } as usize;
+        if leaf_node_has_overflow(key_size, value_size) {
+            self.table.free_overflow(unsafe { *leaf_node_overflow_page(node, self.cell_num) })?;
+        }
         Ok(())
     }
 
-    pub fn leaf_node_split_and_insert(&self, key: u32, value: Rc<RefCell<Row>>) -> DbResult<()> {
+    fn leaf_node_split(&self, cells: &[Vec<u8>]) -> DbResult<()> {
         /*
-        Create a new node and move half the cells over.
-        Insert the new value in one of the two nodes.
+        Create a new node and divide the given cells between old (left)
+        and new (right) nodes, each getting about half the bytes.
         Update parent or create a new parent.
         */
       
-        let old_node = self.table.pager.borrow_mut().get_page(self.page_num)?;
-        let old_max = get_node_max_key(old_node);
-        let new_page_num = self.table.pager.borrow_mut().get_unused_page_num();
-        let new_node = self.table.pager.borrow_mut().get_page(new_page_num)?;
+        let old_node = self.table.pager.borrow_mut().get_page_mut(self.page_num)?;
+        let old_max = get_node_max_key(&mut self.table.pager.borrow_mut(), old_node)?;
+        let new_page_num = self.table.pager.borrow_mut().get_unused_page_num()?;
+        let new_node = self.table.pager.borrow_mut().get_page_mut(new_page_num)?;
         initialize_leaf_node(new_node);
         unsafe{
             *node_parent(new_node) = *node_parent(old_node);
             *leaf_node_next_leaf(new_node) = *leaf_node_next_leaf(old_node);
             *leaf_node_next_leaf(old_node) = new_page_num as u32;
         }
-        /*
-        All existing keys plus new key should should be divided
-        evenly between old (left) and new (right) nodes.
-        Starting from the right, move each key to correct position.
-        */
-        for i in (0..(LEAF_NODE_MAX_CELLS+1)).rev() {
-          let destination_node =  if i >= LEAF_NODE_LEFT_SPLIT_COUNT {
-              new_node
-          } else {
-              old_node
-          };
-
-          let index_within_node = i % LEAF_NODE_LEFT_SPLIT_COUNT;
-          let destination = leaf_node_cell(destination_node, index_within_node);
-      
-          if i == self.cell_num {
-            value.borrow().serialize_row(
-                          leaf_node_value(destination_node, index_within_node));
-            unsafe {
-                *leaf_node_key(destination_node, index_within_node) = key;
-            }
-          } else if i > self.cell_num {
-            unsafe {libc::memcpy(destination, leaf_node_cell(old_node, i - 1), LEAF_NODE_CELL_SIZE);}
-          } else {
-            unsafe {libc::memcpy(destination, leaf_node_cell(old_node, i), LEAF_NODE_CELL_SIZE);}
-          }
-        }
-      
-        /* Update cell count on both leaf nodes */
-        unsafe {
-            *(leaf_node_num_cells(old_node)) = LEAF_NODE_LEFT_SPLIT_COUNT as u32;
-            *(leaf_node_num_cells(new_node)) = LEAF_NODE_RIGHT_SPLIT_COUNT as u32; 
-        }
+
+        let left_split_count = leaf_node_split_point(cells);
+        leaf_node_fill(old_node, &cells[..left_split_count])?;
+        leaf_node_fill(new_node, &cells[left_split_count..])?;
       
         if is_node_root(old_node as *const c_void) {
-          return self.table.create_new_root(new_page_num);
+          return self.table.create_new_root(self.page_num, new_page_num);
         } else {
             let parent_page_num = unsafe{*node_parent(old_node)};
-            let new_max = get_node_max_key(old_node);
-            let parent = self.table.pager.borrow_mut().get_page(parent_page_num as usize)?;
+            let new_max = get_node_max_key(&mut self.table.pager.borrow_mut(), old_node)?;
+            let parent = self.table.pager.borrow_mut().get_page_mut(parent_page_num as usize)?;
         
-            update_internal_node_key(parent, old_max, new_max);
-            self.table.internal_node_insert(parent_page_num as usize, new_page_num)?;
+            update_internal_node_key(parent, &old_max, &new_max, &*self.comparator)?;
+            self.table.internal_node_insert(parent_page_num as usize, new_page_num, &*self.comparator)?;
         }
 
         Ok(())
     }
 
-    pub fn leaf_node_insert(&self, key: u32, value: Rc<RefCell<Row>>) -> DbResult<()> {
-        let node = self.table.pager.borrow_mut().get_page(self.page_num)?;
+    pub fn leaf_node_insert(&self, key: &[u8], value: &[u8]) -> DbResult<()> {
+        let cell = self.build_cell(key, value)?;
+        self.table.update_subtree_cells(self.page_num, true)?;
+        let node = self.table.pager.borrow_mut().get_page_mut(self.page_num)?;
       
-        let num_cells = unsafe{*leaf_node_num_cells(node)};
-        if num_cells as usize >= LEAF_NODE_MAX_CELLS {
+        if !leaf_node_insert_cell(node, self.cell_num, &cell) {
           // Node full
-          return self.leaf_node_split_and_insert(key, value);
+          let mut cells = leaf_node_cells(node);
+          cells.insert(self.cell_num, cell);
+          return self.leaf_node_split(&cells);
         }
-      
-        if self.cell_num < num_cells as usize {
-          // Make room for new cell
-          for i in ((self.cell_num + 1)..(num_cells as usize + 1)).rev() {
-              unsafe{
-                libc::memcpy(leaf_node_cell(node, i), leaf_node_cell(node, i-1), LEAF_NODE_CELL_SIZE);
-              }
-          }
-        }
-        
-        unsafe {
-            *(leaf_node_num_cells(node)) += 1;
-            *(leaf_node_key(node, self.cell_num)) = key;
-        }
-        value.borrow().serialize_row(leaf_node_value(node, self.cell_num));
 
         Ok(())
       }
+
+    pub fn leaf_node_update(&self, value: &[u8]) -> DbResult<()> {
+        /*
+        Replace the value under the cursor, keeping its key. The cell is
+        rebuilt in the page when there is room, else the leaf is split.
+        */
+        let node = self.table.pager.borrow_mut().get_page_mut(self.page_num)?;
+        let key = leaf_node_key(node, self.cell_num);
+        self.free_cell_overflow(node)?;
+        let cell = self.build_cell(&key, value)?;
+
+        if leaf_node_free_space(node) + leaf_node_cell_size(node, self.cell_num) >= cell.len() {
+            leaf_node_remove_cell(node, self.cell_num);
+            if !leaf_node_insert_cell(node, self.cell_num, &cell) {
+                return Err(DbError::Other("Updated cell does not fit in its leaf.".to_string()));
+            }
+            return Ok(());
+        }
+
+        let mut cells = leaf_node_cells(node);
+        cells[self.cell_num] = cell;
+        self.leaf_node_split(&cells)
+    }
+
+    pub fn leaf_node_delete(&self) -> DbResult<()> {
+        /*
+        Remove the cell under the cursor, keep the parent keys in step
+        with the new max key and rebalance the leaf if it became underfull.
+        */
+        let node = self.table.pager.borrow_mut().get_page_mut(self.page_num)?;
+        let num_cells = unsafe { *leaf_node_num_cells(node) } as usize;
+        let removed_key = leaf_node_key(node, self.cell_num);
+
+        self.free_cell_overflow(node)?;
+        leaf_node_remove_cell(node, self.cell_num);
+        self.table.update_subtree_cells(self.page_num, false)?;
+
+        if is_node_root(node) {
+            return Ok(());
+        }
+
+        if self.cell_num == num_cells - 1 && num_cells > 1 {
+            let new_max = get_node_max_key(&mut self.table.pager.borrow_mut(), node)?;
+            self.table.update_max_key(self.page_num, &removed_key, &new_max, &*self.comparator)?;
+        }
+
+        self.table.rebalance(self.page_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::{check_tree, delete_row, insert_row, rescrambled, scrambled, TempDb};
+
+    #[test]
+    fn skip_lands_where_advancing_would() {
+        let db = TempDb::new("cursor-skip");
+        let table = db.open();
+        let root_page_num = table.create_tree().unwrap();
+        for rowid in scrambled(6_000) {
+            insert_row(&table, root_page_num, rowid, &[0; 60]);
+        }
+        /* Deletes leave the leaves with uneven numbers of cells */
+        for rowid in rescrambled(6_000).filter(|rowid| rowid % 7 == 3 || (2_000..2_600).contains(rowid)) {
+            delete_row(&table, root_page_num, rowid);
+        }
+        let rowids: Vec<i64> = (0..6_000).filter(|rowid| rowid % 7 != 3 && !(2_000..2_600).contains(rowid)).collect();
+        assert_eq!(check_tree(&table, root_page_num).unwrap().num_cells, rowids.len());
+
+        for start in (0..rowids.len()).step_by(97) {
+            for count in [0, 1, 2, 5, 50, 333, 1_000, 4_999, 6_000, u64::MAX] {
+                let mut cursor = Cursor::table_seek(table.clone(), root_page_num, rowids[start]).unwrap();
+                let cells_left = (rowids.len() - start) as u64;
+                assert_eq!(cursor.cursor_skip(count).unwrap(), count.min(cells_left));
+                assert_eq!(cursor.end_of_table, count >= cells_left);
+                if !cursor.end_of_table {
+                    let expected = rowids[start + count as usize];
+                    assert_eq!(cursor.cursor_rowid().unwrap(), expected, "skipping {} from row {}", count, rowids[start]);
+                    cursor.cursor_advance().unwrap();
+                    assert_eq!(cursor.end_of_table, start + count as usize + 1 == rowids.len());
+                }
+            }
+        }
+        table.db_close().unwrap();
+    }
+
+    #[test]
+    fn skip_walks_the_whole_table_in_steps() {
+        let db = TempDb::new("cursor-skip-steps");
+        let table = db.open();
+        let root_page_num = table.create_tree().unwrap();
+        for rowid in 0..2_000 {
+            insert_row(&table, root_page_num, rowid, &[0; 60]);
+        }
+
+        let mut cursor = Cursor::table_seek(table.clone(), root_page_num, 0).unwrap();
+        let mut rowid = 0;
+        while !cursor.end_of_table {
+            assert_eq!(cursor.cursor_rowid().unwrap(), rowid);
+            let skipped = cursor.cursor_skip(3).unwrap();
+            rowid += skipped as i64;
+            if skipped < 3 {
+                assert!(cursor.end_of_table);
+                assert_eq!(rowid, 2_000);
+            }
+        }
+        table.db_close().unwrap();
+    }
 }
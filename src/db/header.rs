@@ -0,0 +1,148 @@
+use std::mem;
+
+use libc::c_void;
+
+use super::pager::PAGE_SIZE;
+
+/*
+ * Database File Header Layout (page 0)
+ */
+pub const DB_HEADER_MAGIC: &[u8; DB_HEADER_MAGIC_SIZE] = b"tiny-sqlite v10\0";
+const DB_HEADER_MAGIC_SIZE: usize = 16;
+const DB_HEADER_MAGIC_OFFSET: usize = 0;
+const DB_HEADER_PAGE_SIZE_SIZE: usize = mem::size_of::<u32>();
+const DB_HEADER_PAGE_SIZE_OFFSET: usize = DB_HEADER_MAGIC_OFFSET + DB_HEADER_MAGIC_SIZE;
+const DB_HEADER_FREELIST_TRUNK_SIZE: usize = mem::size_of::<u32>();
+const DB_HEADER_FREELIST_TRUNK_OFFSET: usize = DB_HEADER_PAGE_SIZE_OFFSET + DB_HEADER_PAGE_SIZE_SIZE;
+const DB_HEADER_FREELIST_COUNT_SIZE: usize = mem::size_of::<u32>();
+const DB_HEADER_FREELIST_COUNT_OFFSET: usize =
+    DB_HEADER_FREELIST_TRUNK_OFFSET + DB_HEADER_FREELIST_TRUNK_SIZE;
+const DB_HEADER_JOURNAL_MODE_SIZE: usize = mem::size_of::<u32>();
+const DB_HEADER_JOURNAL_MODE_OFFSET: usize =
+    DB_HEADER_FREELIST_COUNT_OFFSET + DB_HEADER_FREELIST_COUNT_SIZE;
+pub const DB_HEADER_SIZE: usize = DB_HEADER_JOURNAL_MODE_OFFSET + DB_HEADER_JOURNAL_MODE_SIZE;
+
+/*
+ * Catalog Layout (page 0, after the file header)
+ *
+ * The number of entries followed by the entries back to back, see
+ * catalog.rs for the layout of one entry.
+ */
+const CATALOG_NUM_ENTRIES_SIZE: usize = mem::size_of::<u32>();
+const CATALOG_NUM_ENTRIES_OFFSET: usize = DB_HEADER_SIZE;
+const CATALOG_ENTRIES_OFFSET: usize = CATALOG_NUM_ENTRIES_OFFSET + CATALOG_NUM_ENTRIES_SIZE;
+pub const CATALOG_SPACE_FOR_ENTRIES: usize = PAGE_SIZE - CATALOG_ENTRIES_OFFSET;
+
+/* Values of the journal mode field */
+pub const DB_JOURNAL_MODE_ROLLBACK: u32 = 0;
+pub const DB_JOURNAL_MODE_WAL: u32 = 1;
+
+/*
+ * Freelist Trunk Page Layout
+ *
+ * Trunk pages form a linked list starting at the header. Each trunk
+ * holds the numbers of free leaf pages; a trunk with no leaves left
+ * is itself the next page handed out.
+ */
+const FREELIST_TRUNK_NEXT_SIZE: usize = mem::size_of::<u32>();
+const FREELIST_TRUNK_NEXT_OFFSET: usize = 0;
+const FREELIST_TRUNK_NUM_LEAVES_SIZE: usize = mem::size_of::<u32>();
+const FREELIST_TRUNK_NUM_LEAVES_OFFSET: usize = FREELIST_TRUNK_NEXT_OFFSET + FREELIST_TRUNK_NEXT_SIZE;
+const FREELIST_TRUNK_HEADER_SIZE: usize = FREELIST_TRUNK_NEXT_SIZE + FREELIST_TRUNK_NUM_LEAVES_SIZE;
+const FREELIST_TRUNK_LEAF_SIZE: usize = mem::size_of::<u32>();
+pub const FREELIST_TRUNK_MAX_LEAVES: usize =
+    (PAGE_SIZE - FREELIST_TRUNK_HEADER_SIZE) / FREELIST_TRUNK_LEAF_SIZE;
+
+pub fn initialize_db_header(page: *mut c_void) {
+    unsafe {
+        libc::memset(page, 0, PAGE_SIZE);
+        libc::memcpy(
+            (page as *const u8).add(DB_HEADER_MAGIC_OFFSET) as *mut c_void,
+            DB_HEADER_MAGIC.as_ptr() as *const c_void,
+            DB_HEADER_MAGIC_SIZE,
+        );
+        *db_header_page_size(page) = PAGE_SIZE as u32;
+    }
+}
+
+pub fn is_valid_db_header(page: *mut c_void) -> bool {
+    unsafe {
+        let magic = std::slice::from_raw_parts(
+            (page as *const u8).add(DB_HEADER_MAGIC_OFFSET),
+            DB_HEADER_MAGIC_SIZE,
+        );
+        magic == DB_HEADER_MAGIC && *db_header_page_size(page) == PAGE_SIZE as u32
+    }
+}
+
+pub fn db_header_page_size(page: *mut c_void) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(DB_HEADER_PAGE_SIZE_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn db_header_freelist_trunk(page: *mut c_void) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(DB_HEADER_FREELIST_TRUNK_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn db_header_freelist_count(page: *mut c_void) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(DB_HEADER_FREELIST_COUNT_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn db_header_journal_mode(page: *mut c_void) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(DB_HEADER_JOURNAL_MODE_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn catalog_num_entries(page: *mut c_void) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(CATALOG_NUM_ENTRIES_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn catalog_entries(page: *mut c_void) -> *mut u8 {
+    unsafe {
+        (page as *const u8)
+            .add(CATALOG_ENTRIES_OFFSET)
+            as *mut u8
+    }
+}
+
+pub fn freelist_trunk_next(page: *mut c_void) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(FREELIST_TRUNK_NEXT_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn freelist_trunk_num_leaves(page: *mut c_void) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(FREELIST_TRUNK_NUM_LEAVES_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn freelist_trunk_leaf(page: *mut c_void, leaf_num: usize) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(FREELIST_TRUNK_HEADER_SIZE + leaf_num * FREELIST_TRUNK_LEAF_SIZE)
+            as *mut u32
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+
+use libc::c_void;
+
+use super::error::{DbError, DbResult};
+use super::pager::PAGE_SIZE;
+
+/*
+ * Rollback Journal Layout
+ *
+ * header: magic, number of pages of the database when the
+ *         transaction started
+ * record: page number, original page image, checksum
+ *
+ * Records are appended before the first change to a page and the
+ * journal is synced before any page of the database file is written.
+ * A journal left behind by a crash is hot: replaying its records and
+ * truncating the database to the recorded size undoes the transaction.
+ */
+const JOURNAL_MAGIC: &[u8; JOURNAL_MAGIC_SIZE] = b"tinyjrnl";
+const JOURNAL_MAGIC_SIZE: usize = 8;
+const JOURNAL_DB_NUM_PAGES_SIZE: usize = mem::size_of::<u32>();
+const JOURNAL_HEADER_SIZE: usize = JOURNAL_MAGIC_SIZE + JOURNAL_DB_NUM_PAGES_SIZE;
+const JOURNAL_RECORD_PAGE_NUM_SIZE: usize = mem::size_of::<u32>();
+const JOURNAL_RECORD_CHECKSUM_SIZE: usize = mem::size_of::<u32>();
+const JOURNAL_RECORD_SIZE: usize =
+    JOURNAL_RECORD_PAGE_NUM_SIZE + PAGE_SIZE + JOURNAL_RECORD_CHECKSUM_SIZE;
+
+pub fn page_checksum(page_num: u32, data: &[u8]) -> u32 {
+    /* Fletcher-style sum, enough to detect torn or partial records */
+    let mut a: u32 = page_num;
+    let mut b: u32 = 0;
+    for &byte in data {
+        a = a.wrapping_add(byte as u32);
+        b = b.wrapping_add(a);
+    }
+    b ^ a.rotate_left(16)
+}
+
+/* What a crashed transaction left behind */
+pub struct HotJournal {
+    /* None when not even the header reached the disk */
+    pub db_num_pages: Option<usize>,
+    pub pages: Vec<(usize, Vec<u8>)>,
+}
+
+pub struct Journal {
+    path: String,
+    file: Option<File>,
+    db_num_pages: usize,
+    page_offsets: HashMap<usize, u64>, // page number -> offset of its record
+    synced: bool,
+}
+
+impl Journal {
+    pub fn new(db_filename: &str) -> Self {
+        Self {
+            path: format!("{}-journal", db_filename),
+            file: None,
+            db_num_pages: 0,
+            page_offsets: HashMap::new(),
+            synced: true,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /* Number of pages the database had when the transaction started */
+    pub fn db_num_pages(&self) -> usize {
+        self.db_num_pages
+    }
+
+    pub fn journaled_pages(&self) -> Vec<usize> {
+        self.page_offsets.keys().copied().collect()
+    }
+
+    pub fn contains(&self, page_num: usize) -> bool {
+        self.page_offsets.contains_key(&page_num)
+    }
+
+    pub fn begin(&mut self, db_num_pages: usize) -> DbResult<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.path)?;
+
+        let mut header = Vec::with_capacity(JOURNAL_HEADER_SIZE);
+        header.extend_from_slice(JOURNAL_MAGIC);
+        header.extend_from_slice(&(db_num_pages as u32).to_le_bytes());
+        file.write_all(&header)?;
+
+        self.file = Some(file);
+        self.db_num_pages = db_num_pages;
+        self.page_offsets.clear();
+        self.synced = false;
+        Ok(())
+    }
+
+    /*
+    Save the original image of a page, must be called before
+    the page is modified for the first time in this transaction.
+    */
+    pub fn write_page(&mut self, page_num: usize, page: *const c_void) -> DbResult<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Err(DbError::Other("No active journal".to_owned())),
+        };
+
+        let data = unsafe { std::slice::from_raw_parts(page as *const u8, PAGE_SIZE) };
+        let offset = file.seek(SeekFrom::End(0))?;
+        let mut record = Vec::with_capacity(JOURNAL_RECORD_SIZE);
+        record.extend_from_slice(&(page_num as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        record.extend_from_slice(&page_checksum(page_num as u32, data).to_le_bytes());
+        file.write_all(&record)?;
+
+        self.page_offsets.insert(page_num, offset);
+        self.synced = false;
+        Ok(())
+    }
+
+    pub fn read_page(&mut self, page_num: usize, page: *mut c_void) -> DbResult<()> {
+        let offset = match (self.file.as_mut(), self.page_offsets.get(&page_num)) {
+            (Some(_), Some(&offset)) => offset,
+            _ => return Err(DbError::Other(format!("Page {} is not journaled", page_num))),
+        };
+        let file = self.file.as_mut().unwrap();
+
+        let data = unsafe { std::slice::from_raw_parts_mut(page as *mut u8, PAGE_SIZE) };
+        file.seek(SeekFrom::Start(offset + JOURNAL_RECORD_PAGE_NUM_SIZE as u64))?;
+        file.read_exact(data)?;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> DbResult<()> {
+        if let (false, Some(file)) = (self.synced, self.file.as_ref()) {
+            file.sync_all()?;
+            self.synced = true;
+        }
+        Ok(())
+    }
+
+    /*
+    Deleting the journal is the commit point of a transaction,
+    and the last step of a rollback.
+    */
+    pub fn finish(&mut self) -> DbResult<()> {
+        if self.file.take().is_some() {
+            fs::remove_file(&self.path)?;
+        }
+        self.page_offsets.clear();
+        self.synced = true;
+        Ok(())
+    }
+
+    /*
+    Read a hot journal left behind by an interrupted transaction,
+    keeping every intact page image up to the first torn record.
+    */
+    pub fn read_hot_journal(&self) -> DbResult<Option<HotJournal>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        if contents.len() < JOURNAL_HEADER_SIZE || &contents[..JOURNAL_MAGIC_SIZE] != JOURNAL_MAGIC {
+            /* The header never made it to disk, so neither did any page */
+            return Ok(Some(HotJournal {
+                db_num_pages: None,
+                pages: Vec::new(),
+            }));
+        }
+
+        let mut db_num_pages = [0u8; JOURNAL_DB_NUM_PAGES_SIZE];
+        db_num_pages.copy_from_slice(&contents[JOURNAL_MAGIC_SIZE..JOURNAL_HEADER_SIZE]);
+        let db_num_pages = u32::from_le_bytes(db_num_pages) as usize;
+
+        let mut pages = Vec::new();
+        for record in contents[JOURNAL_HEADER_SIZE..].chunks_exact(JOURNAL_RECORD_SIZE) {
+            let (page_num, rest) = record.split_at(JOURNAL_RECORD_PAGE_NUM_SIZE);
+            let (data, checksum) = rest.split_at(PAGE_SIZE);
+            let page_num = u32::from_le_bytes(page_num.try_into().unwrap());
+            let checksum = u32::from_le_bytes(checksum.try_into().unwrap());
+            if checksum != page_checksum(page_num, data) {
+                break;
+            }
+            pages.push((page_num as usize, data.to_vec()));
+        }
+
+        Ok(Some(HotJournal {
+            db_num_pages: Some(db_num_pages),
+            pages,
+        }))
+    }
+
+    pub fn remove_hot_journal(&self) -> DbResult<()> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
@@ -1,5 +1,11 @@
+pub mod catalog;
 pub mod cursor;
 pub mod error;
-mod pager;
+pub mod header;
+mod journal;
+pub mod pager;
 pub mod table;
 pub mod tree;
+#[cfg(test)]
+pub mod test_util;
+mod wal;
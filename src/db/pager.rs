@@ -1,18 +1,82 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::CString;
-use std::ptr;
 
 use crate::db::error::{DbError, DbResult};
+use crate::db::header::*;
+use crate::db::journal::Journal;
+use crate::db::wal::{Wal, WAL_AUTOCHECKPOINT};
 
 use libc::{c_char, c_int, c_void};
 
 pub const PAGE_SIZE: usize = 4096;
-pub const TABLE_MAX_PAGES: usize = 100;
+/* Number of pages kept in memory unless configured otherwise */
+pub const DEFAULT_CACHE_SIZE: usize = 256;
+/* Page 0 holds the file header, a trunk pointer of 0 ends the freelist */
+pub const DB_HEADER_PAGE_NUM: usize = 0;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    JournalRollback,
+    JournalWal,
+}
+
+/* Pages changed by the open transaction in WAL mode */
+struct WalTransaction {
+    db_num_pages: usize,
+    pages: HashSet<usize>,
+}
+
+/*
+A savepoint keeps the image of every page changed since it was taken,
+copied before the first change. Pages allocated after it need no image.
+*/
+struct Savepoint {
+    name: String,
+    db_num_pages: usize,
+    pages: HashMap<usize, Vec<u8>>,
+}
+
+struct CachedPage {
+    data: *mut c_void,
+    dirty: bool,
+    pin_count: usize,
+    last_used: u64,
+}
+
+/*
+Pages are cached in memory up to cache_capacity and evicted in least
+recently used order. Tree code keeps raw page pointers across several
+get_page calls, so eviction never happens inside get_page: the cache may
+grow past its capacity during one operation and is trimmed back by
+shrink_cache at points where no page pointers are held. Pinned pages,
+such as the page under a cursor, are never evicted.
+
+Changes reach the database file in one of two ways. In rollback mode the
+original images go to a journal and the file is written in place on
+commit. In WAL mode the file is left alone: committed pages are appended
+to the write-ahead log, reads look there first, and a checkpoint copies
+them back into the file.
+
+Normally every statement commits on its own. Between begin_transaction
+and end_transaction statements leave their changes in the cache, so
+the whole batch commits or rolls back as one unit. Savepoints inside
+a transaction form a stack, each able to undo the changes made after it.
+*/
 pub struct Pager {
     pub file_descripter: c_int,
     file_length: usize,
     pub num_pages: usize,
-    pub pages: [*mut c_void; TABLE_MAX_PAGES as usize],
+    cache: HashMap<usize, CachedPage>,
+    lru: BTreeMap<u64, usize>, // last use -> page number, oldest first
+    cache_capacity: usize,
+    clock: u64,
+    journal_mode: JournalMode,
+    journal: Journal,
+    wal: Wal,
+    wal_txn: Option<WalTransaction>,
+    autocommit: bool,
+    savepoints: Vec<Savepoint>,
+    savepoint_txn: bool, // transaction was opened by a savepoint
 }
 
 impl Default for Pager {
@@ -21,20 +85,31 @@ impl Default for Pager {
             file_descripter: 0,
             file_length: 0,
             num_pages: 0,
-            pages: [ptr::null_mut::<c_void>(); TABLE_MAX_PAGES],
+            cache: HashMap::new(),
+            lru: BTreeMap::new(),
+            cache_capacity: DEFAULT_CACHE_SIZE,
+            clock: 0,
+            journal_mode: JournalMode::JournalRollback,
+            journal: Journal::new(""),
+            wal: Wal::new(""),
+            wal_txn: None,
+            autocommit: true,
+            savepoints: Vec::new(),
+            savepoint_txn: false,
         }
     }
 }
 
 impl Pager {
-    pub fn new(filename: &str) -> DbResult<Self> {
+    pub fn new(filename: &str, cache_capacity: usize) -> DbResult<Self> {
         let c_filename_cstring = CString::new(filename).unwrap();
         let c_filename = c_filename_cstring.as_ptr() as *const c_char;
 
         let fd = unsafe {
             libc::open(
                 c_filename,
-                libc::O_RDWR | libc::O_CREAT | libc::S_IWUSR as i32 | libc::S_IRUSR as i32,
+                libc::O_RDWR | libc::O_CREAT,
+                (libc::S_IWUSR | libc::S_IRUSR) as libc::c_uint,
             )
         };
 
@@ -54,63 +129,723 @@ impl Pager {
             file_descripter: fd,
             file_length: file_length as usize,
             num_pages: file_length as usize / PAGE_SIZE,
-            pages: [ptr::null_mut::<c_void>(); TABLE_MAX_PAGES],
+            cache_capacity: cache_capacity.max(1),
+            journal: Journal::new(filename),
+            wal: Wal::new(filename),
+            ..Pager::default()
         };
 
         Ok(pager)
     }
 
     pub fn get_page(&mut self, page_num: usize) -> DbResult<*mut c_void> {
-        if page_num > TABLE_MAX_PAGES {
+        if page_num > u32::MAX as usize {
             return Err(DbError::Other(format!(
                 "Tried to fetch page number out of bounds. {} > {}",
-                page_num, TABLE_MAX_PAGES
+                page_num, u32::MAX
             )));
         }
 
-        if self.pages[page_num as usize].is_null() {
-            let page: *mut c_void = unsafe {
-                // Cache miss. Allocate memory and load from file.
-                libc::malloc(PAGE_SIZE as usize) as *mut c_void
+        self.clock += 1;
+        if let Some(cached) = self.cache.get_mut(&page_num) {
+            self.lru.remove(&cached.last_used);
+            cached.last_used = self.clock;
+            self.lru.insert(self.clock, page_num);
+            return Ok(cached.data);
+        }
+
+        // Cache miss. Allocate memory and load from file.
+        let page: *mut c_void = unsafe { libc::calloc(1, PAGE_SIZE) };
+        if page.is_null() {
+            return Err(DbError::Other("Out of memory".to_owned()));
+        }
+
+        let wal_frame = match self.journal_mode {
+            JournalMode::JournalWal => self.wal.find_frame(page_num),
+            JournalMode::JournalRollback => None,
+        };
+        let in_wal = match wal_frame.map(|frame| self.wal.read_frame(frame, page)) {
+            Some(Ok(in_wal)) => in_wal,
+            Some(Err(e)) => {
+                unsafe { libc::free(page) };
+                return Err(e);
+            }
+            None => false,
+        };
+        if wal_frame.is_some() && !in_wal {
+            /* The log was restarted under this connection, its pages are all in the file */
+            self.read_file_length();
+        }
+        let num_pages = self.file_length / PAGE_SIZE;
+        if !in_wal && page_num < num_pages {
+            unsafe {
+                libc::lseek(
+                    self.file_descripter,
+                    (page_num * PAGE_SIZE) as i64,
+                    libc::SEEK_SET,
+                );
+                let bytes_read: libc::ssize_t =
+                    libc::read(self.file_descripter, page, PAGE_SIZE);
+
+                if bytes_read == -1 {
+                    libc::free(page);
+                    return Err(DbError::Other("Error reading file".to_owned()));
+                }
+            }
+        }
+
+        self.cache.insert(page_num, CachedPage {
+            data: page,
+            dirty: false,
+            pin_count: 0,
+            last_used: self.clock,
+        });
+        self.lru.insert(self.clock, page_num);
+
+        if page_num >= self.num_pages {
+            self.num_pages = page_num + 1;
+        }
+
+        Ok(page)
+    }
+
+    /*
+    Fetch a page that is about to be modified. The first change to a page
+    in a transaction saves its original image to the rollback journal.
+    In WAL mode the first change of a transaction takes the writer lock.
+    Dirty pages reach the database file (or the log) on commit or when
+    evicted.
+    */
+    pub fn get_page_mut(&mut self, page_num: usize) -> DbResult<*mut c_void> {
+        if self.journal_mode == JournalMode::JournalWal && self.wal_txn.is_none() {
+            self.wal.begin_write()?;
+        }
+        let db_num_pages = self.num_pages;
+        let page = self.get_page(page_num)?;
+
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            if page_num < savepoint.db_num_pages && !savepoint.pages.contains_key(&page_num) {
+                let image = unsafe { std::slice::from_raw_parts(page as *const u8, PAGE_SIZE) };
+                savepoint.pages.insert(page_num, image.to_vec());
+            }
+        }
+
+        match self.journal_mode {
+            JournalMode::JournalRollback => {
+                if !self.journal.is_active() {
+                    self.journal.begin(self.file_length / PAGE_SIZE)?;
+                }
+                if page_num < self.journal.db_num_pages() && !self.journal.contains(page_num) {
+                    self.journal.write_page(page_num, page)?;
+                }
+            }
+            JournalMode::JournalWal => {
+                self.wal_txn
+                    .get_or_insert_with(|| WalTransaction {
+                        db_num_pages,
+                        pages: HashSet::new(),
+                    })
+                    .pages
+                    .insert(page_num);
+            }
+        }
+
+        if let Some(cached) = self.cache.get_mut(&page_num) {
+            cached.dirty = true;
+        }
+        Ok(page)
+    }
+
+    /*
+    Make every change since the first get_page_mut durable:
+    sync the journal, write and sync the dirty pages, then drop the journal.
+    */
+    pub fn commit(&mut self) -> DbResult<()> {
+        if let JournalMode::JournalWal = self.journal_mode {
+            return self.commit_wal();
+        }
+        if !self.journal.is_active() {
+            return Ok(());
+        }
+
+        self.journal.sync()?;
+
+        for page_num in self.dirty_page_nums() {
+            self.pager_flush(page_num)?;
+        }
+        self.sync_file()?;
+
+        self.journal.finish()
+    }
+
+    /*
+    In WAL mode a commit appends the dirty pages to the log, the last
+    frame carrying the commit marker, and syncs only the log before
+    letting other writers in.
+    */
+    fn commit_wal(&mut self) -> DbResult<()> {
+        if self.wal_txn.take().is_none() {
+            return Ok(());
+        }
+
+        let mut dirty_page_nums = self.dirty_page_nums();
+        if dirty_page_nums.is_empty() {
+            /* Every change was spilled already, a commit frame is still needed */
+            dirty_page_nums.push(DB_HEADER_PAGE_NUM);
+        }
+
+        let last = dirty_page_nums.len() - 1;
+        for (i, &page_num) in dirty_page_nums.iter().enumerate() {
+            let page = self.get_page(page_num)?;
+            let commit_db_num_pages = if i == last { Some(self.num_pages) } else { None };
+            self.wal.append_frame(page_num, page, commit_db_num_pages)?;
+            if let Some(cached) = self.cache.get_mut(&page_num) {
+                cached.dirty = false;
+            }
+        }
+        self.wal.sync()?;
+        self.wal.end_write()?;
+
+        if self.wal.frames_to_checkpoint() >= WAL_AUTOCHECKPOINT {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn dirty_page_nums(&self) -> Vec<usize> {
+        let mut dirty_page_nums: Vec<usize> = self
+            .cache
+            .iter()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(&page_num, _)| page_num)
+            .collect();
+        dirty_page_nums.sort_unstable();
+        dirty_page_nums
+    }
+
+    /*
+    Undo every change since the first get_page_mut. Original images go
+    back into the file, modified and appended pages leave the cache.
+    */
+    pub fn rollback(&mut self) -> DbResult<()> {
+        if let JournalMode::JournalWal = self.journal_mode {
+            return self.rollback_wal();
+        }
+        if !self.journal.is_active() {
+            return Ok(());
+        }
+
+        let db_num_pages = self.journal.db_num_pages();
+        let page: *mut c_void = unsafe { libc::malloc(PAGE_SIZE) };
+        for page_num in self.journal.journaled_pages() {
+            let restored = self
+                .journal
+                .read_page(page_num, page)
+                .and_then(|_| self.write_page_data(page_num, page));
+            if let Err(e) = restored {
+                unsafe { libc::free(page) };
+                return Err(e);
+            }
+        }
+        unsafe { libc::free(page) };
+
+        let stale_page_nums: Vec<usize> = self
+            .cache
+            .iter()
+            .filter(|(&page_num, cached)| {
+                cached.dirty || page_num >= db_num_pages || self.journal.contains(page_num)
+            })
+            .map(|(&page_num, _)| page_num)
+            .collect();
+        for page_num in stale_page_nums {
+            self.discard_page(page_num);
+        }
+
+        self.truncate_file(db_num_pages)?;
+        self.sync_file()?;
+
+        self.journal.finish()
+    }
+
+    /*
+    In WAL mode the file was never touched: forget the changed pages
+    and any frames spilled by the transaction.
+    */
+    fn rollback_wal(&mut self) -> DbResult<()> {
+        let txn = match self.wal_txn.take() {
+            Some(txn) => txn,
+            /* A commit may have failed after taking the transaction */
+            None => return self.wal.end_write(),
+        };
+
+        let stale_page_nums: Vec<usize> = self
+            .cache
+            .iter()
+            .filter(|(&page_num, cached)| {
+                cached.dirty || page_num >= txn.db_num_pages || txn.pages.contains(&page_num)
+            })
+            .map(|(&page_num, _)| page_num)
+            .collect();
+        for page_num in stale_page_nums {
+            self.discard_page(page_num);
+        }
+
+        self.wal.rollback();
+        self.num_pages = txn.db_num_pages;
+        self.wal.end_write()
+    }
+
+    /*
+    Copy the newest committed image of every page in the log back into
+    the database file, then restart the log. Frames newer than what
+    another connection is reading stay in the log only, and the log is
+    not restarted while another connection reads it. Returns whether
+    the log was restarted.
+    */
+    pub fn checkpoint(&mut self) -> DbResult<bool> {
+        if self.journal_mode != JournalMode::JournalWal {
+            return Ok(true);
+        }
+        if self.wal_txn.is_some() || !self.autocommit {
+            return Err(DbError::Other("Cannot checkpoint inside a transaction".to_owned()));
+        }
+
+        self.wal.sync()?;
+        /* Transactions other connections committed are copied back too */
+        let changed_page_nums = self.wal.refresh()?;
+        self.discard_stale_pages(changed_page_nums);
+
+        let (frames, limit, readers) = self.wal.checkpoint_frames()?;
+        let page: *mut c_void = unsafe { libc::malloc(PAGE_SIZE) };
+        for (page_num, frame) in frames {
+            let copied = match self.wal.read_frame(frame, page) {
+                Ok(true) => self.write_page_data(page_num, page).map(|_| true),
+                result => result,
             };
-            let mut num_pages = self.file_length / PAGE_SIZE;
+            if !matches!(copied, Ok(true)) {
+                /* A frame gone means another connection copied back everything and restarted */
+                unsafe { libc::free(page) };
+                return copied.map(|_| false);
+            }
+        }
+        unsafe { libc::free(page) };
+        self.sync_file()?;
+        self.wal.set_backfilled(limit);
+
+        if readers {
+            return Ok(false);
+        }
+        self.wal.reset()?;
+        Ok(true)
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        !self.autocommit
+    }
+
+    /* Hold changes in the pager until end_transaction */
+    pub fn begin_transaction(&mut self) {
+        self.autocommit = false;
+    }
+
+    /* Commit or throw away everything since begin_transaction */
+    pub fn end_transaction(&mut self, commit: bool) -> DbResult<()> {
+        self.autocommit = true;
+        self.savepoints.clear();
+        self.savepoint_txn = false;
+        if commit {
+            self.commit()
+        } else {
+            self.rollback()
+        }
+    }
+
+    /*
+    Statements inside a transaction run under a savepoint of their own,
+    with a name no user savepoint can have, so a failed one only undoes
+    itself. Outside a transaction a failed statement rolls back anyway.
+    */
+    pub fn begin_statement(&mut self) {
+        if !self.autocommit {
+            self.savepoints.push(Savepoint {
+                name: String::new(),
+                db_num_pages: self.num_pages,
+                pages: HashMap::new(),
+            });
+        }
+    }
+
+    pub fn end_statement(&mut self, commit: bool) -> DbResult<()> {
+        if !matches!(self.savepoints.last(), Some(savepoint) if savepoint.name.is_empty()) {
+            return Ok(());
+        }
+        if !commit {
+            self.rollback_to_savepoint("")?;
+        }
+        self.release_savepoint("")?;
+        Ok(())
+    }
+
+    /* Push a savepoint, opening a transaction when none is active */
+    pub fn savepoint(&mut self, name: &str) {
+        if self.autocommit {
+            self.begin_transaction();
+            self.savepoint_txn = true;
+        }
+        self.savepoints.push(Savepoint {
+            name: name.to_owned(),
+            db_num_pages: self.num_pages,
+            pages: HashMap::new(),
+        });
+    }
+
+    fn savepoint_index(&self, name: &str) -> Option<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.name.eq_ignore_ascii_case(name))
+    }
+
+    /*
+    Forget a savepoint and every one taken after it, keeping their
+    changes. Images move to the enclosing savepoint unless it already
+    has an older one. Releasing the savepoint that opened the
+    transaction commits it. Returns false for an unknown name.
+    */
+    pub fn release_savepoint(&mut self, name: &str) -> DbResult<bool> {
+        let index = match self.savepoint_index(name) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+
+        let released: Vec<Savepoint> = self.savepoints.drain(index..).collect();
+        if let Some(outer) = self.savepoints.last_mut() {
+            for savepoint in released {
+                for (page_num, image) in savepoint.pages {
+                    if page_num < outer.db_num_pages {
+                        outer.pages.entry(page_num).or_insert(image);
+                    }
+                }
+            }
+        } else if self.savepoint_txn {
+            self.end_transaction(true)?;
+        }
+        Ok(true)
+    }
 
-            // We might save a partial page at the end of the file
-            if self.file_length % PAGE_SIZE != 0 {
-                num_pages += 1;
+    /*
+    Undo every change made since a savepoint. Images are put back
+    newest savepoint first, so the oldest image of each page wins, and
+    pages allocated since are dropped. The savepoint itself stays on
+    the stack. Returns false for an unknown name.
+    */
+    pub fn rollback_to_savepoint(&mut self, name: &str) -> DbResult<bool> {
+        let index = match self.savepoint_index(name) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+
+        let undone: Vec<Savepoint> = self.savepoints.drain(index..).collect();
+        for savepoint in undone.iter().rev() {
+            for (&page_num, image) in &savepoint.pages {
+                let page = self.get_page(page_num)?;
+                unsafe {
+                    std::ptr::copy_nonoverlapping(image.as_ptr(), page as *mut u8, PAGE_SIZE);
+                }
+                if let Some(cached) = self.cache.get_mut(&page_num) {
+                    cached.dirty = true;
+                }
+            }
+        }
+
+        let db_num_pages = undone[0].db_num_pages;
+        let new_page_nums: Vec<usize> = self
+            .cache
+            .keys()
+            .copied()
+            .filter(|&page_num| page_num >= db_num_pages)
+            .collect();
+        for page_num in new_page_nums {
+            self.discard_page(page_num);
+        }
+        /* New pages may have been spilled, they must read back as empty */
+        match self.journal_mode {
+            JournalMode::JournalRollback => {
+                if self.file_length > db_num_pages * PAGE_SIZE {
+                    self.truncate_file(db_num_pages)?;
+                }
             }
+            JournalMode::JournalWal => self.wal.forget_pages(db_num_pages),
+        }
+        self.num_pages = db_num_pages;
+
+        self.savepoints.push(Savepoint {
+            name: undone[0].name.clone(),
+            db_num_pages,
+            pages: HashMap::new(),
+        });
+        Ok(true)
+    }
+
+    /*
+    Start of a statement. In WAL mode pick up transactions other
+    connections committed to the log and drop cached pages they replaced.
+    A checkpoint of theirs may have grown the file. An explicit
+    transaction keeps the snapshot it started with until end_read.
+    */
+    pub fn begin_read(&mut self) -> DbResult<()> {
+        if self.journal_mode != JournalMode::JournalWal || self.wal_txn.is_some() || !self.autocommit {
+            return Ok(());
+        }
+
+        let changed_page_nums = self.wal.begin_snapshot()?;
+        self.discard_stale_pages(changed_page_nums);
+        Ok(())
+    }
 
-            if page_num <= num_pages {
+    /*
+    Drop the cached pages other connections changed, every page when they
+    restarted the log, and pick up the size of the database they left.
+    */
+    fn discard_stale_pages(&mut self, changed_page_nums: Option<Vec<usize>>) {
+        let stale_page_nums: Vec<usize> = match changed_page_nums {
+            Some(changed_page_nums) => changed_page_nums,
+            None => self.cache.keys().copied().collect(),
+        };
+        for page_num in stale_page_nums {
+            self.discard_page(page_num);
+        }
+        self.read_file_length();
+        self.num_pages = (self.file_length / PAGE_SIZE).max(self.wal.db_num_pages());
+    }
+
+    /* End of a statement outside a transaction, or of a transaction: let go of the snapshot */
+    pub fn end_read(&mut self) -> DbResult<()> {
+        if self.journal_mode != JournalMode::JournalWal || self.wal_txn.is_some() || !self.autocommit {
+            return Ok(());
+        }
+        self.wal.end_snapshot()
+    }
+
+    /* The file only changes under this connection in WAL mode, by checkpoints of others */
+    fn read_file_length(&mut self) {
+        let file_length = unsafe { libc::lseek(self.file_descripter, 0, libc::SEEK_END) };
+        if file_length >= 0 {
+            self.file_length = file_length as usize;
+        }
+    }
+
+    /* Open the write-ahead log if the database was left in WAL mode */
+    pub fn load_journal_mode(&mut self) -> DbResult<()> {
+        let header = self.get_page(DB_HEADER_PAGE_NUM)?;
+        if unsafe { *db_header_journal_mode(header) } == DB_JOURNAL_MODE_WAL {
+            self.open_wal()?;
+        }
+        Ok(())
+    }
+
+    /*
+    Switch between rollback and WAL mode. The mode is stored in the
+    file header so the database reopens in the same mode.
+    */
+    pub fn set_journal_mode(&mut self, journal_mode: JournalMode) -> DbResult<()> {
+        if journal_mode == self.journal_mode {
+            return Ok(());
+        }
+        self.commit()?;
+
+        match journal_mode {
+            JournalMode::JournalWal => {
+                let header = self.get_page_mut(DB_HEADER_PAGE_NUM)?;
                 unsafe {
-                    libc::lseek(
-                        self.file_descripter,
-                        (page_num * PAGE_SIZE) as i64,
-                        libc::SEEK_SET,
-                    );
-                    let bytes_read: libc::ssize_t =
-                        libc::read(self.file_descripter, page, PAGE_SIZE as usize);
-
-                    if bytes_read == -1 {
-                        return Err(DbError::Other("Error reading file".to_owned()));
-                    }
+                    *db_header_journal_mode(header) = DB_JOURNAL_MODE_WAL;
+                }
+                self.commit()?;
+                self.open_wal()
+            }
+            JournalMode::JournalRollback => {
+                if !self.checkpoint()? {
+                    return Err(DbError::Other("Other connections are reading the write-ahead log".to_owned()));
+                }
+                self.wal.close(true)?;
+                self.journal_mode = JournalMode::JournalRollback;
+                let header = self.get_page_mut(DB_HEADER_PAGE_NUM)?;
+                unsafe {
+                    *db_header_journal_mode(header) = DB_JOURNAL_MODE_ROLLBACK;
                 }
+                self.commit()
             }
+        }
+    }
+
+    fn open_wal(&mut self) -> DbResult<()> {
+        self.wal.open()?;
+        self.journal_mode = JournalMode::JournalWal;
 
-            self.pages[page_num] = page;
+        /* Pages read so far came from the file, the log may hold newer images */
+        let page_nums: Vec<usize> = self.cache.keys().copied().collect();
+        for page_num in page_nums {
+            self.discard_page(page_num);
+        }
+        self.num_pages = self.num_pages.max(self.wal.db_num_pages());
+        Ok(())
+    }
+
+    /*
+    Commit what is pending, fold the log back into the file and
+    release every cached page. An unfinished explicit transaction is
+    rolled back. The emptied log is kept since other connections may
+    still have it open.
+    */
+    pub fn pager_close(&mut self) -> DbResult<()> {
+        self.end_transaction(self.autocommit)?;
+        self.checkpoint()?;
+        self.flush_cache()?;
+        self.wal.close(false)?;
+
+        let result = unsafe { libc::close(self.file_descripter) };
+        if result == -1 {
+            return Err(DbError::Other("Error closing db file.".to_owned()));
+        }
+        Ok(())
+    }
+
+    /* Drop a cached page without writing it back */
+    fn discard_page(&mut self, page_num: usize) {
+        if let Some(cached) = self.cache.remove(&page_num) {
+            self.lru.remove(&cached.last_used);
+            unsafe { libc::free(cached.data) };
+        }
+    }
+
+    /*
+    Undo a transaction interrupted by a crash, using the journal
+    it left next to the database file.
+    */
+    pub fn recover_journal(&mut self) -> DbResult<()> {
+        let hot_journal = match self.journal.read_hot_journal()? {
+            Some(hot_journal) => hot_journal,
+            None => return Ok(()),
+        };
 
-            if page_num >= self.num_pages {
-                self.num_pages += 1;
+        if let Some(db_num_pages) = hot_journal.db_num_pages {
+            for (page_num, data) in &hot_journal.pages {
+                self.write_page_data(*page_num, data.as_ptr() as *const c_void)?;
             }
+            self.truncate_file(db_num_pages)?;
+            self.sync_file()?;
         }
 
-        Ok(self.pages[page_num])
+        self.journal.remove_hot_journal()
+    }
+
+    fn truncate_file(&mut self, num_pages: usize) -> DbResult<()> {
+        let result = unsafe {
+            libc::ftruncate(self.file_descripter, (num_pages * PAGE_SIZE) as libc::off_t)
+        };
+        if result == -1 {
+            return Err(DbError::Other("Error truncating db file".to_owned()));
+        }
+        self.file_length = num_pages * PAGE_SIZE;
+        self.num_pages = num_pages;
+        Ok(())
+    }
+
+    fn sync_file(&self) -> DbResult<()> {
+        if unsafe { libc::fsync(self.file_descripter) } == -1 {
+            return Err(DbError::Other("Error syncing db file".to_owned()));
+        }
+        Ok(())
+    }
+
+    pub fn pin_page(&mut self, page_num: usize) -> DbResult<()> {
+        self.get_page(page_num)?;
+        if let Some(cached) = self.cache.get_mut(&page_num) {
+            cached.pin_count += 1;
+        }
+        Ok(())
+    }
+
+    pub fn unpin_page(&mut self, page_num: usize) {
+        if let Some(cached) = self.cache.get_mut(&page_num) {
+            cached.pin_count = cached.pin_count.saturating_sub(1);
+        }
+    }
+
+    /*
+    Evict least recently used, unpinned pages until the cache is back
+    within its capacity. Callers must not hold page pointers across it.
+    */
+    pub fn shrink_cache(&mut self) -> DbResult<()> {
+        if self.cache.len() <= self.cache_capacity {
+            return Ok(());
+        }
+
+        let mut victims: Vec<usize> = Vec::new();
+        let mut num_to_evict = self.cache.len() - self.cache_capacity;
+        for &page_num in self.lru.values() {
+            if num_to_evict == 0 {
+                break;
+            }
+            if self.cache[&page_num].pin_count == 0 {
+                victims.push(page_num);
+                num_to_evict -= 1;
+            }
+        }
+
+        for page_num in victims {
+            self.evict_page(page_num)?;
+        }
+
+        Ok(())
+    }
+
+    fn evict_page(&mut self, page_num: usize) -> DbResult<()> {
+        if self.cache[&page_num].dirty {
+            match self.journal_mode {
+                JournalMode::JournalRollback => {
+                    /* Original images must be on disk before the file changes */
+                    self.journal.sync()?;
+                    self.pager_flush(page_num)?;
+                }
+                JournalMode::JournalWal => {
+                    /* Spill into the log, the frame stays invisible until commit */
+                    let page = self.cache[&page_num].data;
+                    self.wal.append_frame(page_num, page, None)?;
+                }
+            }
+        }
+        self.discard_page(page_num);
+        Ok(())
+    }
+
+    /*
+    Write every dirty page back and drop the whole cache.
+    */
+    pub fn flush_cache(&mut self) -> DbResult<()> {
+        let mut page_nums: Vec<usize> = self.cache.keys().copied().collect();
+        page_nums.sort_unstable();
+        for page_num in page_nums {
+            self.evict_page(page_num)?;
+        }
+        Ok(())
     }
 
     pub fn pager_flush(&mut self, page_num: usize) -> DbResult<()> {
-        if self.pages[page_num].is_null() {
-            return Err(DbError::Other("Tried to flush null page".to_owned()));
+        let page = match self.cache.get(&page_num) {
+            Some(cached) => cached.data,
+            None => return Err(DbError::Other("Tried to flush null page".to_owned())),
+        };
+
+        self.write_page_data(page_num, page)?;
+
+        if let Some(cached) = self.cache.get_mut(&page_num) {
+            cached.dirty = false;
         }
 
+        Ok(())
+    }
+
+    fn write_page_data(&mut self, page_num: usize, page: *const c_void) -> DbResult<()> {
         let offset = unsafe {
             libc::lseek(
                 self.file_descripter,
@@ -124,20 +859,482 @@ impl Pager {
         }
 
         let bytes_written: libc::ssize_t =
-            unsafe { libc::write(self.file_descripter, self.pages[page_num], PAGE_SIZE) };
+            unsafe { libc::write(self.file_descripter, page, PAGE_SIZE) };
 
         if bytes_written == -1 {
             return Err(DbError::Other("Error writing".to_owned()));
         }
 
+        self.file_length = self.file_length.max((page_num + 1) * PAGE_SIZE);
+        Ok(())
+    }
+
+    /*
+    Return a page that is no longer part of any tree to the freelist.
+    It becomes a leaf of the first trunk page, or a new trunk when
+    there is none or the first one is full.
+    */
+    pub fn free_page(&mut self, page_num: usize) -> DbResult<()> {
+        let header = self.get_page_mut(DB_HEADER_PAGE_NUM)?;
+        let trunk_page_num = unsafe { *db_header_freelist_trunk(header) } as usize;
+
+        if trunk_page_num != DB_HEADER_PAGE_NUM {
+            let trunk = self.get_page_mut(trunk_page_num)?;
+            let num_leaves = unsafe { *freelist_trunk_num_leaves(trunk) } as usize;
+            if num_leaves < FREELIST_TRUNK_MAX_LEAVES {
+                unsafe {
+                    *freelist_trunk_leaf(trunk, num_leaves) = page_num as u32;
+                    *freelist_trunk_num_leaves(trunk) += 1;
+                    *db_header_freelist_count(header) += 1;
+                }
+                return Ok(());
+            }
+        }
+
+        let new_trunk = self.get_page_mut(page_num)?;
+        unsafe {
+            *freelist_trunk_next(new_trunk) = trunk_page_num as u32;
+            *freelist_trunk_num_leaves(new_trunk) = 0;
+            *db_header_freelist_trunk(header) = page_num as u32;
+            *db_header_freelist_count(header) += 1;
+        }
+
         Ok(())
     }
 
     /*
-    Until we start recycling free pages, new pages will always
-    go onto the end of the database file
+    Hand out a page from the freelist if there is one, otherwise
+    new pages go onto the end of the database file.
     */
-    pub fn get_unused_page_num(&self) -> usize { 
-        self.num_pages
+    pub fn get_unused_page_num(&mut self) -> DbResult<usize> {
+        let header = self.get_page_mut(DB_HEADER_PAGE_NUM)?;
+        let trunk_page_num = unsafe { *db_header_freelist_trunk(header) } as usize;
+
+        if trunk_page_num == DB_HEADER_PAGE_NUM {
+            return Ok(self.num_pages);
+        }
+
+        let trunk = self.get_page_mut(trunk_page_num)?;
+        let num_leaves = unsafe { *freelist_trunk_num_leaves(trunk) } as usize;
+        unsafe {
+            *db_header_freelist_count(header) -= 1;
+            if num_leaves > 0 {
+                *freelist_trunk_num_leaves(trunk) -= 1;
+                return Ok(*freelist_trunk_leaf(trunk, num_leaves - 1) as usize);
+            }
+            *db_header_freelist_trunk(header) = *freelist_trunk_next(trunk);
+        }
+
+        Ok(trunk_page_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::TempDb;
+
+    /* A pager on an empty database holding only its header page */
+    fn open_pager(db: &TempDb, cache_capacity: usize) -> Pager {
+        let mut pager = Pager::new(&db.path, cache_capacity).unwrap();
+        pager.recover_journal().unwrap();
+        if pager.num_pages == 0 {
+            initialize_db_header(pager.get_page_mut(DB_HEADER_PAGE_NUM).unwrap());
+            pager.commit().unwrap();
+        }
+        pager.load_journal_mode().unwrap();
+        pager
+    }
+
+    fn open_wal_pager(db: &TempDb, cache_capacity: usize) -> Pager {
+        let mut pager = open_pager(db, cache_capacity);
+        pager.set_journal_mode(JournalMode::JournalWal).unwrap();
+        pager
+    }
+
+    /* Fill pages and commit them as one statement does */
+    fn write_pages(pager: &mut Pager, page_nums: std::ops::Range<usize>, byte: u8) -> DbResult<()> {
+        pager.begin_read()?;
+        for page_num in page_nums {
+            let page = pager.get_page_mut(page_num)?;
+            unsafe { libc::memset(page, byte as c_int, PAGE_SIZE) };
+            pager.shrink_cache()?;
+        }
+        pager.commit()?;
+        pager.end_read()
+    }
+
+    fn allocate_page(pager: &mut Pager) -> usize {
+        let page_num = pager.get_unused_page_num().unwrap();
+        pager.get_page_mut(page_num).unwrap();
+        page_num
+    }
+
+    /* Pages of the tests are filled with one byte, differing from page to page */
+    fn fill_page(pager: &mut Pager, page_num: usize, byte: u8) {
+        let page = pager.get_page_mut(page_num).unwrap();
+        unsafe { libc::memset(page, byte as c_int, PAGE_SIZE) };
+    }
+
+    fn page_filled_with(pager: &mut Pager, page_num: usize, byte: u8) -> bool {
+        let page = pager.get_page(page_num).unwrap();
+        unsafe { std::slice::from_raw_parts(page as *const u8, PAGE_SIZE) }.iter().all(|&b| b == byte)
+    }
+
+    fn freelist_count(pager: &mut Pager) -> u32 {
+        unsafe { *db_header_freelist_count(pager.get_page(DB_HEADER_PAGE_NUM).unwrap()) }
+    }
+
+    #[test]
+    fn freed_pages_are_handed_out_again() {
+        let db = TempDb::new("freelist");
+        let mut pager = open_pager(&db, DEFAULT_CACHE_SIZE);
+        /* Enough pages for the freelist to need several trunks */
+        let num_freed = 2 * FREELIST_TRUNK_MAX_LEAVES + 10;
+        let page_nums: Vec<usize> = (0..num_freed).map(|_| allocate_page(&mut pager)).collect();
+        for &page_num in &page_nums {
+            pager.free_page(page_num).unwrap();
+        }
+        pager.commit().unwrap();
+        assert_eq!(freelist_count(&mut pager) as usize, num_freed);
+
+        let num_pages = pager.num_pages;
+        let mut reused: Vec<usize> = (0..num_freed).map(|_| allocate_page(&mut pager)).collect();
+        reused.sort_unstable();
+        assert_eq!(reused, page_nums);
+        assert_eq!(pager.num_pages, num_pages);
+        assert_eq!(freelist_count(&mut pager), 0);
+
+        /* With the freelist empty the file grows again */
+        assert_eq!(allocate_page(&mut pager), num_pages);
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn cache_stays_within_its_capacity() {
+        let db = TempDb::new("cache-capacity");
+        let mut pager = open_pager(&db, 8);
+        for page_num in 1..500 {
+            fill_page(&mut pager, page_num, page_num as u8);
+            pager.shrink_cache().unwrap();
+            assert!(pager.cache.len() <= 8);
+        }
+        pager.commit().unwrap();
+
+        for page_num in (1..500).rev() {
+            assert!(page_filled_with(&mut pager, page_num, page_num as u8), "page {} changed", page_num);
+            pager.shrink_cache().unwrap();
+        }
+        pager.pager_close().unwrap();
+
+        let mut pager = open_pager(&db, 8);
+        assert_eq!(pager.num_pages, 500);
+        assert!(page_filled_with(&mut pager, 200, 200));
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn pinned_pages_are_not_evicted() {
+        let db = TempDb::new("cache-pinned");
+        let mut pager = open_pager(&db, 4);
+        fill_page(&mut pager, 1, 1);
+        pager.pin_page(1).unwrap();
+        let page = pager.get_page(1).unwrap();
+        for page_num in 2..100 {
+            fill_page(&mut pager, page_num, page_num as u8);
+            pager.shrink_cache().unwrap();
+        }
+        assert_eq!(pager.get_page(1).unwrap(), page);
+
+        /* Once unpinned it goes like any other page */
+        pager.unpin_page(1);
+        for page_num in 2..100 {
+            pager.get_page(page_num).unwrap();
+        }
+        pager.shrink_cache().unwrap();
+        assert!(!pager.cache.contains_key(&1));
+        assert!(page_filled_with(&mut pager, 1, 1));
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn rollback_restores_pages_evicted_mid_transaction() {
+        let db = TempDb::new("cache-rollback");
+        let mut pager = open_pager(&db, 8);
+        for page_num in 1..200 {
+            fill_page(&mut pager, page_num, 1);
+            pager.shrink_cache().unwrap();
+        }
+        pager.commit().unwrap();
+
+        /* Most changed pages reach the file before the transaction ends */
+        for page_num in 1..300 {
+            fill_page(&mut pager, page_num, 2);
+            pager.shrink_cache().unwrap();
+        }
+        pager.rollback().unwrap();
+        assert_eq!(pager.num_pages, 200);
+        for page_num in 1..200 {
+            assert!(page_filled_with(&mut pager, page_num, 1), "page {} was not restored", page_num);
+            pager.shrink_cache().unwrap();
+        }
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn hot_journal_is_replayed_after_a_crash() {
+        let db = TempDb::new("hot-journal");
+        let mut pager = open_pager(&db, 8);
+        for page_num in 1..50 {
+            fill_page(&mut pager, page_num, 1);
+            pager.shrink_cache().unwrap();
+        }
+        pager.commit().unwrap();
+        assert!(!db.file_exists("-journal"));
+
+        /* Evictions write the changes into the file, then the process dies without a commit */
+        for page_num in 1..80 {
+            fill_page(&mut pager, page_num, 2);
+            pager.shrink_cache().unwrap();
+        }
+        drop(pager);
+        assert!(db.file_exists("-journal"));
+        assert!(std::fs::metadata(&db.path).unwrap().len() > 50 * PAGE_SIZE as u64);
+
+        let mut pager = open_pager(&db, 8);
+        assert!(!db.file_exists("-journal"));
+        assert_eq!(pager.num_pages, 50);
+        for page_num in 1..50 {
+            assert!(page_filled_with(&mut pager, page_num, 1), "page {} was not restored", page_num);
+            pager.shrink_cache().unwrap();
+        }
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn committed_changes_survive_a_crash() {
+        let db = TempDb::new("crash-after-commit");
+        let mut pager = open_pager(&db, 8);
+        for page_num in 1..50 {
+            fill_page(&mut pager, page_num, 3);
+            pager.shrink_cache().unwrap();
+        }
+        pager.commit().unwrap();
+        drop(pager);
+
+        let mut pager = open_pager(&db, 8);
+        assert_eq!(pager.num_pages, 50);
+        for page_num in 1..50 {
+            assert!(page_filled_with(&mut pager, page_num, 3));
+        }
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn wal_is_replayed_after_a_crash() {
+        let db = TempDb::new("wal-replay");
+        let mut pager = open_wal_pager(&db, 8);
+        write_pages(&mut pager, 1..50, 1).unwrap();
+        write_pages(&mut pager, 1..30, 2).unwrap();
+        /* Spilled frames of a transaction that never commits */
+        pager.begin_read().unwrap();
+        for page_num in 1..60 {
+            fill_page(&mut pager, page_num, 3);
+            pager.shrink_cache().unwrap();
+        }
+        drop(pager);
+        assert_eq!(std::fs::metadata(&db.path).unwrap().len(), PAGE_SIZE as u64);
+
+        let mut pager = open_pager(&db, 8);
+        assert_eq!(pager.journal_mode, JournalMode::JournalWal);
+        assert_eq!(pager.num_pages, 50);
+        for page_num in 1..50 {
+            let byte = if page_num < 30 { 2 } else { 1 };
+            assert!(page_filled_with(&mut pager, page_num, byte), "page {} is not the committed one", page_num);
+            pager.shrink_cache().unwrap();
+        }
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn checkpoint_copies_the_log_into_the_file() {
+        let db = TempDb::new("wal-checkpoint");
+        let mut pager = open_wal_pager(&db, 8);
+        write_pages(&mut pager, 1..20, 1).unwrap();
+        write_pages(&mut pager, 10..20, 2).unwrap();
+        assert_eq!(std::fs::metadata(&db.path).unwrap().len(), PAGE_SIZE as u64);
+
+        assert!(pager.checkpoint().unwrap());
+        assert_eq!(pager.wal.frames_to_checkpoint(), 0);
+        let file = std::fs::read(&db.path).unwrap();
+        assert_eq!(file.len(), 20 * PAGE_SIZE);
+        assert!(file[5 * PAGE_SIZE..6 * PAGE_SIZE].iter().all(|&b| b == 1));
+        assert!(file[15 * PAGE_SIZE..16 * PAGE_SIZE].iter().all(|&b| b == 2));
+
+        /* The restarted log takes new commits on top of the file */
+        write_pages(&mut pager, 15..25, 3).unwrap();
+        pager.pager_close().unwrap();
+        let mut pager = open_pager(&db, 8);
+        assert!(page_filled_with(&mut pager, 5, 1));
+        assert!(page_filled_with(&mut pager, 12, 2));
+        assert!(page_filled_with(&mut pager, 20, 3));
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn wal_readers_keep_their_snapshot() {
+        let db = TempDb::new("wal-snapshot");
+        let mut writer = open_wal_pager(&db, 8);
+        write_pages(&mut writer, 1..10, 1).unwrap();
+        let mut reader = open_pager(&db, 8);
+        reader.begin_read().unwrap();
+        reader.begin_transaction();
+        assert!(page_filled_with(&mut reader, 1, 1));
+
+        write_pages(&mut writer, 1..10, 2).unwrap();
+        /* The writer cannot restart the log under the reader */
+        assert!(!writer.checkpoint().unwrap());
+        assert!(page_filled_with(&mut reader, 5, 1));
+        assert!(reader.get_page_mut(5).is_err(), "a stale snapshot was written to");
+
+        reader.end_transaction(false).unwrap();
+        reader.end_read().unwrap();
+        reader.begin_read().unwrap();
+        assert!(page_filled_with(&mut reader, 5, 2));
+        reader.end_read().unwrap();
+        assert!(writer.checkpoint().unwrap());
+        reader.pager_close().unwrap();
+        writer.pager_close().unwrap();
+    }
+
+    #[test]
+    fn wal_allows_one_writer_at_a_time() {
+        let db = TempDb::new("wal-writer");
+        let mut first = open_wal_pager(&db, 8);
+        let mut second = open_pager(&db, 8);
+        first.begin_read().unwrap();
+        fill_page(&mut first, 1, 1);
+
+        second.begin_read().unwrap();
+        assert!(second.get_page_mut(2).is_err(), "two connections write at once");
+        second.end_read().unwrap();
+
+        first.commit().unwrap();
+        first.end_read().unwrap();
+        write_pages(&mut second, 2..3, 2).unwrap();
+        first.begin_read().unwrap();
+        assert!(page_filled_with(&mut first, 2, 2));
+        first.end_read().unwrap();
+        first.pager_close().unwrap();
+        second.pager_close().unwrap();
+    }
+
+    /* Pages 1 to 9 hold 1 when the transaction starts, page 2 is changed after the rollback */
+    fn roll_back_to_a_savepoint(pager: &mut Pager) {
+        write_pages(pager, 1..10, 1).unwrap();
+        pager.begin_read().unwrap();
+        pager.begin_transaction();
+        fill_page(pager, 1, 5);
+        pager.savepoint("a");
+        for page_num in 1..20 {
+            fill_page(pager, page_num, 2);
+            pager.shrink_cache().unwrap();
+        }
+        pager.savepoint("b");
+        fill_page(pager, 3, 3);
+        assert!(pager.rollback_to_savepoint("A").unwrap());
+        assert!(!pager.rollback_to_savepoint("b").unwrap());
+
+        assert_eq!(pager.num_pages, 10);
+        assert!(page_filled_with(pager, 1, 5));
+        for page_num in 2..10 {
+            assert!(page_filled_with(pager, page_num, 1), "page {} was not rolled back", page_num);
+        }
+        fill_page(pager, 2, 4);
+        assert!(pager.release_savepoint("a").unwrap());
+        pager.end_transaction(true).unwrap();
+        pager.end_read().unwrap();
+    }
+
+    fn check_rolled_back_pages(pager: &mut Pager) {
+        assert_eq!(pager.num_pages, 10);
+        assert!(page_filled_with(pager, 1, 5));
+        assert!(page_filled_with(pager, 2, 4));
+        assert!(page_filled_with(pager, 3, 1));
+    }
+
+    #[test]
+    fn rollback_to_savepoint_undoes_later_changes() {
+        let db = TempDb::new("savepoint-rollback");
+        let mut pager = open_pager(&db, 4);
+        roll_back_to_a_savepoint(&mut pager);
+        pager.pager_close().unwrap();
+        let mut pager = open_pager(&db, 4);
+        check_rolled_back_pages(&mut pager);
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn rollback_to_savepoint_in_wal_mode() {
+        let db = TempDb::new("savepoint-wal");
+        let mut pager = open_wal_pager(&db, 4);
+        roll_back_to_a_savepoint(&mut pager);
+        pager.pager_close().unwrap();
+        let mut pager = open_pager(&db, 4);
+        check_rolled_back_pages(&mut pager);
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn released_savepoints_roll_back_with_the_outer_one() {
+        let db = TempDb::new("savepoint-release");
+        let mut pager = open_pager(&db, 8);
+        write_pages(&mut pager, 1..5, 1).unwrap();
+        pager.savepoint("outer");
+        assert!(pager.in_transaction());
+        fill_page(&mut pager, 1, 2);
+        pager.savepoint("inner");
+        fill_page(&mut pager, 1, 3);
+        fill_page(&mut pager, 2, 3);
+        assert!(pager.release_savepoint("inner").unwrap());
+        assert!(page_filled_with(&mut pager, 2, 3));
+
+        assert!(pager.rollback_to_savepoint("outer").unwrap());
+        assert!(page_filled_with(&mut pager, 1, 1));
+        assert!(page_filled_with(&mut pager, 2, 1));
+        /* Releasing the savepoint that opened the transaction commits it */
+        fill_page(&mut pager, 4, 4);
+        assert!(pager.release_savepoint("outer").unwrap());
+        assert!(!pager.in_transaction());
+        assert!(!db.file_exists("-journal"));
+        pager.pager_close().unwrap();
+
+        let mut pager = open_pager(&db, 8);
+        assert!(page_filled_with(&mut pager, 1, 1));
+        assert!(page_filled_with(&mut pager, 4, 4));
+        pager.pager_close().unwrap();
+    }
+
+    #[test]
+    fn failed_statement_only_undoes_itself() {
+        let db = TempDb::new("savepoint-statement");
+        let mut pager = open_pager(&db, 8);
+        write_pages(&mut pager, 1..5, 1).unwrap();
+        pager.begin_transaction();
+        pager.savepoint("import");
+        pager.begin_statement();
+        fill_page(&mut pager, 1, 2);
+        pager.end_statement(true).unwrap();
+        pager.begin_statement();
+        fill_page(&mut pager, 1, 3);
+        fill_page(&mut pager, 2, 3);
+        pager.end_statement(false).unwrap();
+
+        assert!(page_filled_with(&mut pager, 1, 2));
+        assert!(page_filled_with(&mut pager, 2, 1));
+        assert!(pager.rollback_to_savepoint("import").unwrap());
+        assert!(page_filled_with(&mut pager, 1, 1));
+        pager.end_transaction(true).unwrap();
+        pager.pager_close().unwrap();
     }
 }
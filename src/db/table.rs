@@ -1,64 +1,145 @@
 use std::rc::Rc;
 use std::cell::RefCell; 
+use std::cmp::Ordering;
 
+use crate::db::catalog::{read_catalog, write_catalog, CatalogEntry};
 use crate::db::error::{DbError, DbResult};
-use crate::db::pager::{Pager, TABLE_MAX_PAGES, PAGE_SIZE};
+use crate::db::header::*;
+use crate::db::pager::{JournalMode, Pager, DB_HEADER_PAGE_NUM, PAGE_SIZE};
 use crate::db::tree::*;
 use libc::{self, c_void};
 
+/*
+The database file. Every table and index is a tree of its own, found
+through the catalog in page 0.
+*/
 #[derive(Default)]
 pub struct Table {
     pub pager: Rc<RefCell<Pager>>,
-    pub root_page_num: usize,
+    catalog: RefCell<Vec<CatalogEntry>>,
 }
 
 impl Table {
-    pub fn db_open(filename: &str) -> DbResult<Self> {
-        let mut pager = Pager::new(filename)?;
+    pub fn db_open(filename: &str, cache_size: usize,
+        journal_mode: Option<JournalMode>) -> DbResult<Self> {
+        let mut pager = Pager::new(filename, cache_size)?;
+        pager.recover_journal()?;
 
         if pager.num_pages == 0 {
-            let root_node: *mut c_void = pager.get_page(0)?;
-            initialize_leaf_node(root_node);
-            set_node_root(root_node, true);
+            let header: *mut c_void = pager.get_page_mut(DB_HEADER_PAGE_NUM)?;
+            initialize_db_header(header);
+            pager.commit()?;
+        } else if !is_valid_db_header(pager.get_page(DB_HEADER_PAGE_NUM)?) {
+            return Err(DbError::Other("File is not a database.".to_string()));
         }
 
-        return Ok(Self {
+        pager.load_journal_mode()?;
+        if let Some(journal_mode) = journal_mode {
+            pager.set_journal_mode(journal_mode)?;
+        }
+
+        let table = Self {
             pager: Rc::new(RefCell::new(pager)),
-            root_page_num: 0,
-        });
+            catalog: RefCell::new(Vec::new()),
+        };
+        table.load_catalog()?;
+        Ok(table)
     }
 
     pub fn db_close(&self) -> DbResult<()> {
-        let pager = self.pager.clone();
-        let num_pages = pager.borrow_mut().num_pages;
+        self.pager.borrow_mut().pager_close()
+    }
 
-        for i in 0..num_pages {
-            if pager.borrow_mut().pages[i].is_null() {
-                continue;
-            }
-            let _ = pager.borrow_mut().pager_flush(i)?;
+    /*
+    Read the catalog again at the start of a statement. A rollback or
+    another connection may have changed it since it was last read.
+    */
+    pub fn load_catalog(&self) -> DbResult<()> {
+        let mut pager = self.pager.borrow_mut();
+        pager.begin_read()?;
+        let header = pager.get_page(DB_HEADER_PAGE_NUM)?;
+        *self.catalog.borrow_mut() = read_catalog(header)?;
+        pager.end_read()
+    }
+
+    pub fn catalog(&self) -> Vec<CatalogEntry> {
+        self.catalog.borrow().clone()
+    }
+
+    pub fn add_catalog_entry(&self, entry: CatalogEntry) -> DbResult<()> {
+        let mut catalog = self.catalog.borrow_mut();
+        if catalog.iter().any(|other| other.name.eq_ignore_ascii_case(&entry.name)) {
+            return Err(DbError::Other(format!("{} already exists.", entry.name)));
+        }
+
+        let mut entries = catalog.clone();
+        entries.push(entry);
+        let header = self.pager.borrow_mut().get_page_mut(DB_HEADER_PAGE_NUM)?;
+        write_catalog(header, &entries)?;
+        *catalog = entries;
+        Ok(())
+    }
+
+    /* Set up an empty tree, returns its root page */
+    pub fn create_tree(&self) -> DbResult<usize> {
+        let mut pager = self.pager.borrow_mut();
+        let root_page_num = pager.get_unused_page_num()?;
+        let root_node: *mut c_void = pager.get_page_mut(root_page_num)?;
+        initialize_leaf_node(root_node);
+        set_node_root(root_node, true);
+        Ok(root_page_num)
+    }
+
+    /* Store data in a chain of overflow pages, returns the first one */
+    pub fn write_overflow(&self, data: &[u8]) -> DbResult<u32> {
+        /* Fill the chain back to front so each page knows the next one */
+        let mut pager = self.pager.borrow_mut();
+        let mut next_page_num: u32 = 0;
+        for chunk in data.chunks(OVERFLOW_PAGE_SPACE_FOR_DATA).rev() {
+            let page_num = pager.get_unused_page_num()?;
+            let page = pager.get_page_mut(page_num)?;
             unsafe {
-                libc::free(pager.borrow_mut().pages[i]);
+                *overflow_page_next(page) = next_page_num;
+                libc::memcpy(overflow_page_data(page), chunk.as_ptr() as *const c_void, chunk.len());
             }
-            pager.borrow_mut().pages[i] = std::ptr::null_mut::<c_void>();
+            next_page_num = page_num as u32;
         }
+        Ok(next_page_num)
+    }
 
-        let result = unsafe {libc::close(self.pager.borrow().file_descripter)};
-        if result == -1 {
-            return Err(DbError::Other("Error closing db file.".to_owned()));
+    /* The size bytes held by the chain starting at page_num */
+    pub fn read_overflow(&self, page_num: u32, size: usize) -> DbResult<Vec<u8>> {
+        let mut pager = self.pager.borrow_mut();
+        let mut data: Vec<u8> = Vec::with_capacity(size);
+        let mut page_num = page_num;
+        while data.len() < size {
+            if page_num == 0 {
+                return Err(DbError::Other("Overflow chain is corrupt.".to_string()));
+            }
+            let page = pager.get_page(page_num as usize)?;
+            let chunk_size = (size - data.len()).min(OVERFLOW_PAGE_SPACE_FOR_DATA);
+            unsafe {
+                data.extend_from_slice(std::slice::from_raw_parts(overflow_page_data(page) as *const u8, chunk_size));
+                page_num = *overflow_page_next(page);
+            }
         }
+        Ok(data)
+    }
 
-        for i in 0..TABLE_MAX_PAGES {
-            let page: *mut c_void = self.pager.borrow_mut().pages[i];
-            if !page.is_null() {
-                unsafe{libc::free(page)};
-                self.pager.borrow_mut().pages[i] = std::ptr::null_mut::<c_void>();
-            }
+    /* Return every page of the chain starting at page_num to the freelist */
+    pub fn free_overflow(&self, page_num: u32) -> DbResult<()> {
+        let mut pager = self.pager.borrow_mut();
+        let mut page_num = page_num;
+        while page_num != 0 {
+            let page = pager.get_page(page_num as usize)?;
+            let next_page_num = unsafe { *overflow_page_next(page) };
+            pager.free_page(page_num as usize)?;
+            page_num = next_page_num;
         }
-         Ok(())
+        Ok(())
     }
 
-    pub fn create_new_root(&self, right_child_page_num: usize) -> DbResult<()> {
+    pub fn create_new_root(&self, root_page_num: usize, right_child_page_num: usize) -> DbResult<()> {
         /*
         Handle splitting the root.
         Old root copied to new page, becomes left child.
@@ -66,65 +147,89 @@ impl Table {
         Re-initialize root page to contain the new root node.
         New root node points to two children.
         */
-        let root = self.pager.borrow_mut().get_page(self.root_page_num)?;
-        let right_child = self.pager.borrow_mut().get_page(right_child_page_num)?;
-        let left_child_page_num: usize = self.pager.borrow_mut().get_unused_page_num();
-        let left_child = self.pager.borrow_mut().get_page(left_child_page_num)?;
-      
+        let mut pager = self.pager.borrow_mut();
+        let root = pager.get_page_mut(root_page_num)?;
+        let right_child = pager.get_page_mut(right_child_page_num)?;
+        let left_child_page_num: usize = pager.get_unused_page_num()?;
+        let left_child = pager.get_page_mut(left_child_page_num)?;
+
         /* Left child has data copied from old root */
         unsafe{
             libc::memcpy(left_child, root as *const c_void, PAGE_SIZE);
         }
         set_node_root(left_child, false);
-      
+
+        /* Children of an internal root now hang off the copied left child */
+        if let NodeType::NodeInternal = get_node_type(left_child) {
+            let num_keys = unsafe { *internal_node_num_keys(left_child) };
+            for i in 0..(num_keys + 1) {
+                let child_page_num = unsafe { *internal_node_child(left_child, i as usize)? };
+                let child = pager.get_page_mut(child_page_num as usize)?;
+                unsafe {
+                    *node_parent(child) = left_child_page_num as u32;
+                }
+            }
+        }
+
         /* Root node is a new internal node with one key and two children */
         initialize_internal_node(root);
         set_node_root(root, true);
+        let left_child_max_key = get_node_max_key(&mut pager, left_child)?;
         unsafe {
             *internal_node_num_keys(root) = 1;
             *(internal_node_child(root, 0)?) = left_child_page_num as u32;
-            let left_child_max_key: u32 = get_node_max_key(left_child);
-            *internal_node_key(root, 0) = left_child_max_key;
+            set_internal_node_key(root, 0, &left_child_max_key);
             *internal_node_right_child(root) = right_child_page_num as u32;
-            *node_parent(left_child) = self.root_page_num as u32;
-            *node_parent(right_child) = self.root_page_num as u32;
+            *internal_node_num_cells(root) = node_num_cells(left_child) + node_num_cells(right_child);
+            *node_parent(left_child) = root_page_num as u32;
+            *node_parent(right_child) = root_page_num as u32;
         }
 
         Ok(())
     }
-    
+
     pub fn internal_node_insert(&self, parent_page_num: usize,
-        child_page_num: usize) -> DbResult<()> {
+        child_page_num: usize, comparator: &dyn KeyComparator) -> DbResult<()> {
         /*
         Add a new child/key pair to parent that corresponds to child
         */
-        let parent =self.pager.borrow_mut().get_page(parent_page_num as usize)?;
-        let child = self.pager.borrow_mut().get_page(child_page_num as usize)?;
-        let child_max_key: u32 = get_node_max_key(child);
-        let index: u32 = internal_node_find_child(parent, child_max_key);
-        
+        let parent = self.pager.borrow_mut().get_page_mut(parent_page_num)?;
+        let child = self.pager.borrow_mut().get_page(child_page_num)?;
+        let child_max_key = get_node_max_key(&mut self.pager.borrow_mut(), child)?;
+        let index: u32 = internal_node_find_child(parent, &child_max_key, comparator)?;
+
         let original_num_keys: u32 = unsafe {
-                *internal_node_num_keys(parent)
-            };
+            *internal_node_num_keys(parent)
+        };
+
+        let right_child_page_num: u32 = unsafe {
+            *internal_node_right_child(parent)
+        };
+
+        /* An empty internal node takes its first child as right child */
+        if right_child_page_num == INVALID_PAGE_NUM {
             unsafe {
-                *internal_node_num_keys(parent) = original_num_keys + 1;
+                *internal_node_right_child(parent) = child_page_num as u32;
             }
+            return self.count_subtree_cells(parent_page_num);
+        }
 
-        if (original_num_keys as usize >= INTERNAL_NODE_MAX_CELLS) {
-            return Err(DbError::Other("Need to implement splitting internal node".to_string()));
+        if original_num_keys as usize >= INTERNAL_NODE_MAX_CELLS {
+            return self.internal_node_split_and_insert(parent_page_num, child_page_num, comparator);
         }
 
-        let right_child_page_num: u32 = unsafe {
-            *internal_node_right_child(parent)
-        };
         let right_child = self.pager.borrow_mut().get_page(right_child_page_num as usize)?;
+        let right_child_max_key = get_node_max_key(&mut self.pager.borrow_mut(), right_child)?;
+
+        unsafe {
+            *internal_node_num_keys(parent) = original_num_keys + 1;
+        }
 
-        if child_max_key > get_node_max_key(right_child) {
+        if comparator.compare(&child_max_key, &right_child_max_key)? == Ordering::Greater {
             /* Replace right child */
             unsafe {
                 *(internal_node_child(parent, original_num_keys as usize)?) = right_child_page_num;
-                *internal_node_key(parent, original_num_keys as usize) =
-                get_node_max_key(right_child);
+                set_internal_node_key(parent, original_num_keys as usize, &right_child_max_key);
                 *internal_node_right_child(parent) = child_page_num as u32;
             }
         } else {
@@ -137,9 +242,478 @@ impl Table {
 
             unsafe {
                 *(internal_node_child(parent, index as usize)?) = child_page_num as u32;
-                *internal_node_key(parent, index as usize) = child_max_key;
             }
-        }   
+            set_internal_node_key(parent, index as usize, &child_max_key);
+        }
+        Ok(())
+    }
+
+    fn internal_node_split_and_insert(&self, old_page_num: usize,
+        child_page_num: usize, comparator: &dyn KeyComparator) -> DbResult<()> {
+        /*
+        Split a full internal node in two and insert the new child.
+        The old node keeps the lower half of the children, a new node
+        takes the upper half. The max key of the old node becomes the
+        separator pushed up into the parent, which may split in turn.
+        */
+        let old_node = self.pager.borrow_mut().get_page_mut(old_page_num)?;
+        let child = self.pager.borrow_mut().get_page(child_page_num)?;
+        let child_max_key = get_node_max_key(&mut self.pager.borrow_mut(), child)?;
+        let old_max_key = get_node_max_key(&mut self.pager.borrow_mut(), old_node)?;
+
+        /* Collect every (child, max key) pair including the new child, in key order */
+        let num_keys = unsafe { *internal_node_num_keys(old_node) } as usize;
+        let mut entries: Vec<(u32, Vec<u8>)> = Vec::with_capacity(num_keys + 2);
+        for i in 0..num_keys {
+            unsafe {
+                entries.push((*internal_node_child(old_node, i)?, internal_node_key(old_node, i)));
+            }
+        }
+        let right_child_page_num = unsafe { *internal_node_right_child(old_node) };
+        entries.push((right_child_page_num, old_max_key));
+
+        let mut index = entries.len();
+        for (i, (_, key)) in entries.iter().enumerate() {
+            if comparator.compare(&child_max_key, key)? == Ordering::Less {
+                index = i;
+                break;
+            }
+        }
+        entries.insert(index, (child_page_num as u32, child_max_key));
+
+        let new_page_num = self.pager.borrow_mut().get_unused_page_num()?;
+        let new_node = self.pager.borrow_mut().get_page_mut(new_page_num)?;
+        initialize_internal_node(new_node);
+
+        let left_count = entries.len().div_ceil(2);
+        let (left_entries, right_entries) = entries.split_at(left_count);
+        self.fill_internal_node(old_page_num, left_entries)?;
+        self.fill_internal_node(new_page_num, right_entries)?;
+
+        if is_node_root(old_node) {
+            return self.create_new_root(old_page_num, new_page_num);
+        }
+
+        let parent_page_num = unsafe { *node_parent(old_node) };
+        let parent = self.pager.borrow_mut().get_page_mut(parent_page_num as usize)?;
+        let old_node_index = internal_node_child_index(parent, old_page_num as u32)?;
+        if old_node_index < unsafe { *internal_node_num_keys(parent) } {
+            set_internal_node_key(parent, old_node_index as usize, &left_entries[left_entries.len() - 1].1);
+        }
+        unsafe {
+            *node_parent(new_node) = parent_page_num;
+        }
+        self.internal_node_insert(parent_page_num as usize, new_page_num, comparator)
+    }
+
+    fn fill_internal_node(&self, page_num: usize, entries: &[(u32, Vec<u8>)]) -> DbResult<()> {
+        /*
+        Rewrite the children of an internal node from (child, max key) pairs.
+        The last pair becomes the right child. Every child is re-parented.
+        */
+        let node = self.pager.borrow_mut().get_page_mut(page_num)?;
+        let num_keys = entries.len() - 1;
+        unsafe {
+            *internal_node_num_keys(node) = num_keys as u32;
+            for (i, (child_page_num, key)) in entries[..num_keys].iter().enumerate() {
+                *internal_node_cell(node, i) = *child_page_num;
+                set_internal_node_key(node, i, key);
+            }
+            *internal_node_right_child(node) = entries[num_keys].0;
+        }
+
+        for &(child_page_num, _) in entries {
+            let child = self.pager.borrow_mut().get_page_mut(child_page_num as usize)?;
+            unsafe {
+                *node_parent(child) = page_num as u32;
+            }
+        }
+        self.count_subtree_cells(page_num)
+    }
+
+    /* Recount the cells under an internal node whose children changed */
+    fn count_subtree_cells(&self, page_num: usize) -> DbResult<()> {
+        let mut pager = self.pager.borrow_mut();
+        let node = pager.get_page_mut(page_num)?;
+        let num_keys = unsafe { *internal_node_num_keys(node) } as usize;
+        let mut num_cells: u32 = 0;
+        for i in 0..(num_keys + 1) {
+            let child_page_num = unsafe { *internal_node_child(node, i)? };
+            num_cells += node_num_cells(pager.get_page(child_page_num as usize)?);
+        }
+        unsafe {
+            *internal_node_num_cells(node) = num_cells;
+        }
+        Ok(())
+    }
+
+    /*
+    A leaf gained or lost a cell, every internal node above it counts it.
+    Splits and merges below move cells around without changing the counts
+    of the nodes above them.
+    */
+    pub fn update_subtree_cells(&self, page_num: usize, added: bool) -> DbResult<()> {
+        let mut pager = self.pager.borrow_mut();
+        let mut node = pager.get_page(page_num)?;
+        while !is_node_root(node) {
+            let parent_page_num = unsafe { *node_parent(node) } as usize;
+            node = pager.get_page_mut(parent_page_num)?;
+            unsafe {
+                let num_cells = internal_node_num_cells(node);
+                *num_cells = if added { *num_cells + 1 } else { *num_cells - 1 };
+            }
+        }
+        Ok(())
+    }
+
+    pub fn update_max_key(&self, page_num: usize, old_max: &[u8], new_max: &[u8],
+        comparator: &dyn KeyComparator) -> DbResult<()> {
+        /*
+        The max key of a node changed. Its key lives in the first ancestor
+        where the path does not go through the right child.
+        */
+        let mut page_num = page_num;
+        loop {
+            let node = self.pager.borrow_mut().get_page(page_num)?;
+            if is_node_root(node) {
+                return Ok(());
+            }
+            let parent_page_num = unsafe { *node_parent(node) };
+            let parent = self.pager.borrow_mut().get_page_mut(parent_page_num as usize)?;
+            update_internal_node_key(parent, old_max, new_max, comparator)?;
+            if internal_node_child_index(parent, page_num as u32)? < unsafe { *internal_node_num_keys(parent) } {
+                return Ok(());
+            }
+            page_num = parent_page_num as usize;
+        }
+    }
+
+    pub fn rebalance(&self, page_num: usize) -> DbResult<()> {
+        /*
+        Restore the minimum fill of a node after a removal.
+        An underfull node borrows from a sibling when the sibling can spare
+        some, otherwise the two are merged and the parent is rebalanced.
+        Leaves are filled by bytes, internal nodes by keys.
+        */
+        let node = self.pager.borrow_mut().get_page(page_num)?;
+        if is_node_root(node) {
+            return self.collapse_root(page_num);
+        }
+
+        let underfull = match get_node_type(node) {
+            NodeType::NodeLeaf => leaf_node_used_space(node) < LEAF_NODE_MIN_USED_SPACE,
+            NodeType::NodeInternal => (unsafe { *internal_node_num_keys(node) } as usize) < INTERNAL_NODE_MIN_KEYS,
+        };
+        if !underfull {
+            return Ok(());
+        }
+
+        let parent_page_num = unsafe { *node_parent(node) } as usize;
+        let parent = self.pager.borrow_mut().get_page_mut(parent_page_num)?;
+        let index = internal_node_child_index(parent, page_num as u32)? as usize;
+
+        /* Prefer the left sibling, the left-most child only has a right one */
+        let (left_index, right_index) = if index > 0 { (index - 1, index) } else { (index, index + 1) };
+        let left_page_num = unsafe { *internal_node_child(parent, left_index)? } as usize;
+        let right_page_num = unsafe { *internal_node_child(parent, right_index)? } as usize;
+        let sibling = self.pager.borrow_mut().get_page(
+            if index > 0 { left_page_num } else { right_page_num })?;
+
+        /* Two leaves that do not fit in one page can share their cells instead */
+        let can_borrow = match get_node_type(sibling) {
+            NodeType::NodeLeaf => leaf_node_used_space(node) + leaf_node_used_space(sibling) > LEAF_NODE_SPACE_FOR_CELLS,
+            NodeType::NodeInternal => (unsafe { *internal_node_num_keys(sibling) } as usize) > INTERNAL_NODE_MIN_KEYS,
+        };
+
+        if can_borrow {
+            return match get_node_type(node) {
+                NodeType::NodeLeaf => self.leaf_node_redistribute(parent, left_index, left_page_num, right_page_num),
+                NodeType::NodeInternal => self.internal_node_borrow(parent, left_index, left_page_num, right_page_num, index > 0),
+            };
+        }
+
+        match get_node_type(node) {
+            NodeType::NodeLeaf => self.leaf_node_merge(left_page_num, right_page_num)?,
+            NodeType::NodeInternal => self.internal_node_merge(parent, left_index, left_page_num, right_page_num)?,
+        }
+
+        /* The left node absorbed the right one, drop it from the parent */
+        if right_index < unsafe { *internal_node_num_keys(parent) } as usize {
+            set_internal_node_key(parent, left_index, &internal_node_key(parent, right_index));
+        }
+        internal_node_remove_child(parent, right_index)?;
+        self.pager.borrow_mut().free_page(right_page_num)?;
+
+        self.rebalance(parent_page_num)
+    }
+
+    fn collapse_root(&self, root_page_num: usize) -> DbResult<()> {
+        /*
+        An internal root left with a single child is replaced by that child,
+        shrinking the tree by one level.
+        */
+        let root = self.pager.borrow_mut().get_page_mut(root_page_num)?;
+        if let NodeType::NodeLeaf = get_node_type(root) {
+            return Ok(());
+        }
+        if unsafe { *internal_node_num_keys(root) } > 0 {
+            return Ok(());
+        }
+
+        let child_page_num = unsafe { *internal_node_right_child(root) } as usize;
+        let child = self.pager.borrow_mut().get_page(child_page_num)?;
+        unsafe {
+            libc::memcpy(root, child as *const c_void, PAGE_SIZE);
+        }
+        set_node_root(root, true);
+
+        if let NodeType::NodeInternal = get_node_type(root) {
+            let num_keys = unsafe { *internal_node_num_keys(root) };
+            for i in 0..(num_keys + 1) {
+                let grandchild_page_num = unsafe { *internal_node_child(root, i as usize)? };
+                let grandchild = self.pager.borrow_mut().get_page_mut(grandchild_page_num as usize)?;
+                unsafe {
+                    *node_parent(grandchild) = root_page_num as u32;
+                }
+            }
+        }
+        self.pager.borrow_mut().free_page(child_page_num)?;
+
+        Ok(())
+    }
+
+    fn leaf_node_redistribute(&self, parent: *mut c_void, left_index: usize,
+        left_page_num: usize, right_page_num: usize) -> DbResult<()> {
+        /*
+        Share the cells of two adjacent leaves so both hold about the
+        same number of bytes, and fix the separator key between them.
+        */
+        let left = self.pager.borrow_mut().get_page_mut(left_page_num)?;
+        let right = self.pager.borrow_mut().get_page_mut(right_page_num)?;
+        let mut cells = leaf_node_cells(left);
+        cells.extend(leaf_node_cells(right));
+
+        let left_split_count = leaf_node_split_point(&cells);
+        leaf_node_fill(left, &cells[..left_split_count])?;
+        leaf_node_fill(right, &cells[left_split_count..])?;
+        set_internal_node_key(parent, left_index, &leaf_node_cell_key(&cells[left_split_count - 1]));
+
+        Ok(())
+    }
+
+    fn internal_node_borrow(&self, parent: *mut c_void, left_index: usize,
+        left_page_num: usize, right_page_num: usize, from_left: bool) -> DbResult<()> {
+        /*
+        Rotate one child through the parent between two adjacent internal
+        nodes. The separator in the parent is the max key of the left node.
+        */
+        let left = self.pager.borrow_mut().get_page_mut(left_page_num)?;
+        let right = self.pager.borrow_mut().get_page_mut(right_page_num)?;
+        let left_num_keys = unsafe { *internal_node_num_keys(left) } as usize;
+        let right_num_keys = unsafe { *internal_node_num_keys(right) } as usize;
+        let separator = internal_node_key(parent, left_index);
+
+        let moved_child_page_num = unsafe {
+            if from_left {
+                /* Right child of the left node becomes the first child of the right node */
+                let moved = *internal_node_right_child(left);
+                for i in (1..(right_num_keys + 1)).rev() {
+                    libc::memcpy(
+                        internal_node_cell(right, i) as *mut c_void,
+                        internal_node_cell(right, i - 1) as *const c_void,
+                        INTERNAL_NODE_CELL_SIZE,
+                    );
+                }
+                *internal_node_cell(right, 0) = moved;
+                set_internal_node_key(right, 0, &separator);
+                *internal_node_num_keys(right) += 1;
+
+                set_internal_node_key(parent, left_index, &internal_node_key(left, left_num_keys - 1));
+                internal_node_remove_child(left, left_num_keys)?;
+                moved
+            } else {
+                /* First child of the right node becomes the right child of the left node */
+                let moved = *internal_node_child(right, 0)?;
+                let moved_max = internal_node_key(right, 0);
+                *internal_node_cell(left, left_num_keys) = *internal_node_right_child(left);
+                set_internal_node_key(left, left_num_keys, &separator);
+                *internal_node_num_keys(left) += 1;
+                *internal_node_right_child(left) = moved;
+
+                set_internal_node_key(parent, left_index, &moved_max);
+                internal_node_remove_child(right, 0)?;
+                moved
+            }
+        };
+
+        let moved_child = self.pager.borrow_mut().get_page_mut(moved_child_page_num as usize)?;
+        unsafe {
+            *node_parent(moved_child) = if from_left { right_page_num } else { left_page_num } as u32;
+        }
+
+        self.count_subtree_cells(left_page_num)?;
+        self.count_subtree_cells(right_page_num)
+    }
+
+    fn leaf_node_merge(&self, left_page_num: usize, right_page_num: usize) -> DbResult<()> {
+        /*
+        Append every cell of the right leaf to the left leaf.
+        */
+        let left = self.pager.borrow_mut().get_page_mut(left_page_num)?;
+        let right = self.pager.borrow_mut().get_page(right_page_num)?;
+        let mut cells = leaf_node_cells(left);
+        cells.extend(leaf_node_cells(right));
+        leaf_node_fill(left, &cells)?;
+        unsafe {
+            *leaf_node_next_leaf(left) = *leaf_node_next_leaf(right);
+        }
+
         Ok(())
     }
+
+    fn internal_node_merge(&self, parent: *mut c_void, left_index: usize,
+        left_page_num: usize, right_page_num: usize) -> DbResult<()> {
+        /*
+        Pull the separator down and append the right node to the left one.
+        */
+        let left = self.pager.borrow_mut().get_page_mut(left_page_num)?;
+        let right = self.pager.borrow_mut().get_page(right_page_num)?;
+        let separator = internal_node_key(parent, left_index);
+        let left_num_keys = unsafe { *internal_node_num_keys(left) } as usize;
+        let right_num_keys = unsafe { *internal_node_num_keys(right) } as usize;
+
+        unsafe {
+            *internal_node_cell(left, left_num_keys) = *internal_node_right_child(left);
+            set_internal_node_key(left, left_num_keys, &separator);
+            libc::memcpy(
+                internal_node_cell(left, left_num_keys + 1) as *mut c_void,
+                internal_node_cell(right, 0) as *const c_void,
+                right_num_keys * INTERNAL_NODE_CELL_SIZE,
+            );
+            *internal_node_num_keys(left) = (left_num_keys + 1 + right_num_keys) as u32;
+            *internal_node_right_child(left) = *internal_node_right_child(right);
+        }
+
+        for i in (left_num_keys + 1)..(left_num_keys + right_num_keys + 2) {
+            let child_page_num = unsafe { *internal_node_child(left, i)? };
+            let child = self.pager.borrow_mut().get_page_mut(child_page_num as usize)?;
+            unsafe {
+                *node_parent(child) = left_page_num as u32;
+            }
+        }
+
+        self.count_subtree_cells(left_page_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::cursor::Cursor;
+    use crate::db::tree::rowid_key;
+    use crate::db::test_util::{check_tree, delete_row, insert_row, rescrambled, scrambled, TempDb, TreeShape};
+
+    #[test]
+    fn insert_splits_internal_nodes_at_depth() {
+        let db = TempDb::new("insert-splits");
+        let table = db.open();
+        let root_page_num = table.create_tree().unwrap();
+        for rowid in scrambled(10_000) {
+            insert_row(&table, root_page_num, rowid, &[rowid as u8; 100]);
+        }
+        table.pager.borrow_mut().commit().unwrap();
+
+        let shape = check_tree(&table, root_page_num).unwrap();
+        assert_eq!(shape.num_cells, 10_000);
+        assert!(shape.depth > 3, "tree of depth {} never split an internal node below the root", shape.depth);
+
+        /* The parent pointers written to disk hold up after a reopen */
+        table.db_close().unwrap();
+        let table = db.open();
+        assert_eq!(check_tree(&table, root_page_num).unwrap(), shape);
+        table.db_close().unwrap();
+    }
+
+    #[test]
+    fn sequential_inserts_keep_the_tree_balanced() {
+        let db = TempDb::new("sequential-inserts");
+        let table = db.open();
+        let root_page_num = table.create_tree().unwrap();
+        for rowid in (0..5_000).rev() {
+            insert_row(&table, root_page_num, rowid, &[0; 200]);
+        }
+        for rowid in 5_000..10_000 {
+            insert_row(&table, root_page_num, rowid, &[0; 200]);
+        }
+        assert_eq!(check_tree(&table, root_page_num).unwrap().num_cells, 10_000);
+        table.db_close().unwrap();
+    }
+
+    #[test]
+    fn delete_merges_nodes_down_to_an_empty_root() {
+        let db = TempDb::new("delete-merges");
+        let table = db.open();
+        let root_page_num = table.create_tree().unwrap();
+        for rowid in scrambled(10_000) {
+            insert_row(&table, root_page_num, rowid, &[rowid as u8; 100]);
+        }
+
+        for (i, rowid) in rescrambled(10_000).enumerate() {
+            delete_row(&table, root_page_num, rowid);
+            if i % 500 == 0 {
+                assert_eq!(check_tree(&table, root_page_num).unwrap().num_cells, 10_000 - i - 1);
+            }
+        }
+        table.pager.borrow_mut().commit().unwrap();
+        assert_eq!(check_tree(&table, root_page_num).unwrap(), TreeShape { num_cells: 0, depth: 1 });
+        table.db_close().unwrap();
+    }
+
+    #[test]
+    fn delete_keeps_the_remaining_rows() {
+        let db = TempDb::new("delete-keeps");
+        let table = db.open();
+        let root_page_num = table.create_tree().unwrap();
+        for rowid in scrambled(10_000) {
+            insert_row(&table, root_page_num, rowid, &[rowid as u8; 100]);
+        }
+        /* Every third row goes, leaving nodes to borrow from their siblings */
+        for rowid in rescrambled(10_000).filter(|rowid| rowid % 3 != 0) {
+            delete_row(&table, root_page_num, rowid);
+        }
+        table.pager.borrow_mut().commit().unwrap();
+        assert_eq!(check_tree(&table, root_page_num).unwrap().num_cells, 3_334);
+
+        for rowid in 0..10_000 {
+            let cursor = Cursor::table_find(table.clone(), root_page_num, rowid).unwrap();
+            assert_eq!(cursor.cursor_matches(&rowid_key(rowid)).unwrap(), rowid % 3 == 0);
+            if rowid % 3 == 0 {
+                assert_eq!(cursor.cursor_value().unwrap(), vec![rowid as u8; 100]);
+            }
+        }
+        table.db_close().unwrap();
+    }
+
+    #[test]
+    fn pages_freed_by_deletes_are_reused() {
+        let db = TempDb::new("delete-reuse");
+        let table = db.open();
+        let root_page_num = table.create_tree().unwrap();
+        for rowid in scrambled(5_000) {
+            insert_row(&table, root_page_num, rowid, &[0; 100]);
+        }
+        for rowid in rescrambled(5_000) {
+            delete_row(&table, root_page_num, rowid);
+        }
+        table.pager.borrow_mut().commit().unwrap();
+        let num_pages = table.pager.borrow().num_pages;
+
+        /* The same rows need the same pages, all of them from the freelist */
+        for rowid in scrambled(5_000) {
+            insert_row(&table, root_page_num, rowid, &[0; 100]);
+        }
+        table.pager.borrow_mut().commit().unwrap();
+        assert_eq!(table.pager.borrow().num_pages, num_pages);
+        assert_eq!(check_tree(&table, root_page_num).unwrap().num_cells, 5_000);
+        table.db_close().unwrap();
+    }
 }
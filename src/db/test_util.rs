@@ -0,0 +1,182 @@
+/*
+Helpers for the unit tests of the storage engine: database files in the
+temp directory and a walk of a whole tree checking its invariants.
+*/
+use std::cmp::Ordering;
+use std::fs;
+use std::rc::Rc;
+
+use crate::db::cursor::Cursor;
+use crate::db::error::{DbError, DbResult};
+use crate::db::pager::DEFAULT_CACHE_SIZE;
+use crate::db::table::Table;
+use crate::db::tree::*;
+
+/* A database file of one test, removed with its journal and log when dropped */
+pub struct TempDb {
+    pub path: String,
+}
+
+impl TempDb {
+    pub fn new(name: &str) -> TempDb {
+        let path = std::env::temp_dir().join(format!("tiny-sqlite-{}-{}.db", std::process::id(), name));
+        let db = TempDb {
+            path: path.to_string_lossy().into_owned(),
+        };
+        db.remove_files();
+        db
+    }
+
+    pub fn open(&self) -> Rc<Table> {
+        Rc::new(Table::db_open(&self.path, DEFAULT_CACHE_SIZE, None).unwrap())
+    }
+
+    pub fn file_exists(&self, suffix: &str) -> bool {
+        fs::metadata(format!("{}{}", self.path, suffix)).is_ok()
+    }
+
+    fn remove_files(&self) {
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", self.path, suffix));
+        }
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        self.remove_files();
+    }
+}
+
+/* Rowids 0 to n - 1 in an order that touches every part of the tree */
+pub fn scrambled(n: i64) -> impl Iterator<Item = i64> {
+    (0..n).map(move |i| (i * 7919) % n)
+}
+
+/* Another such order, so deletes do not follow the inserts */
+pub fn rescrambled(n: i64) -> impl Iterator<Item = i64> {
+    (0..n).map(move |i| (i * 3571 + 17) % n)
+}
+
+/* Insert a row into a table tree the way an insert statement does */
+pub fn insert_row(table: &Rc<Table>, root_page_num: usize, rowid: i64, value: &[u8]) {
+    let cursor = Cursor::table_find(table.clone(), root_page_num, rowid).unwrap();
+    cursor.leaf_node_insert(&rowid_key(rowid), value).unwrap();
+    drop(cursor);
+    table.pager.borrow_mut().shrink_cache().unwrap();
+}
+
+pub fn delete_row(table: &Rc<Table>, root_page_num: usize, rowid: i64) {
+    let cursor = Cursor::table_find(table.clone(), root_page_num, rowid).unwrap();
+    assert!(cursor.cursor_matches(&rowid_key(rowid)).unwrap(), "row {} is missing", rowid);
+    cursor.leaf_node_delete().unwrap();
+    drop(cursor);
+    table.pager.borrow_mut().shrink_cache().unwrap();
+}
+
+/* Shape of a tree found by check_tree */
+#[derive(Debug, PartialEq)]
+pub struct TreeShape {
+    pub num_cells: usize,
+    pub depth: usize,
+}
+
+/*
+Walk a table tree and check everything the tree code relies on: parent
+pointers and the root flag, keys in order and within the keys of the
+parents, internal keys equal to the max key of their child, nodes no
+fuller or emptier than allowed, leaves all at the same depth and chained
+in key order, and the cell counts of internal nodes.
+*/
+pub fn check_tree(table: &Rc<Table>, root_page_num: usize) -> DbResult<TreeShape> {
+    let mut leaves = Vec::new();
+    let (num_cells, depth) = check_node(table, root_page_num, None, None, None, &mut leaves)?;
+
+    let mut pager = table.pager.borrow_mut();
+    for (i, &page_num) in leaves.iter().enumerate() {
+        let next_leaf = unsafe { *leaf_node_next_leaf(pager.get_page(page_num)?) } as usize;
+        let expected = leaves.get(i + 1).copied().unwrap_or(0);
+        if next_leaf != expected {
+            return Err(DbError::Other(format!("Leaf {} is followed by {} instead of {}", page_num, next_leaf, expected)));
+        }
+    }
+    Ok(TreeShape { num_cells, depth })
+}
+
+/* Cells under a node and the depth of its leaves, below is the root */
+fn check_node(table: &Rc<Table>, page_num: usize, parent: Option<usize>, low: Option<&[u8]>,
+    high: Option<&[u8]>, leaves: &mut Vec<usize>) -> DbResult<(usize, usize)> {
+    let corrupt = |what: String| Err(DbError::Other(format!("Page {}: {}", page_num, what)));
+    let comparator = RowidComparator;
+    let node = table.pager.borrow_mut().get_page(page_num)?;
+
+    match parent {
+        None if !is_node_root(node) => return corrupt("root is not flagged".to_string()),
+        Some(_) if is_node_root(node) => return corrupt("child is flagged root".to_string()),
+        Some(parent) if unsafe { *node_parent(node) } as usize != parent => {
+            return corrupt(format!("parent is {} instead of {}", unsafe { *node_parent(node) }, parent));
+        }
+        _ => {}
+    }
+
+    if let NodeType::NodeLeaf = get_node_type(node) {
+        let num_cells = unsafe { *leaf_node_num_cells(node) } as usize;
+        if parent.is_some() && leaf_node_used_space(node) < LEAF_NODE_MIN_USED_SPACE {
+            return corrupt(format!("leaf uses {} bytes", leaf_node_used_space(node)));
+        }
+        let mut previous = low.map(|low| low.to_vec());
+        for i in 0..num_cells {
+            let key = leaf_node_key(node, i);
+            if let Some(previous) = &previous {
+                if comparator.compare(previous, &key)? != Ordering::Less {
+                    return corrupt(format!("key {} out of order", key_rowid(&key)?));
+                }
+            }
+            if let Some(high) = high {
+                if comparator.compare(&key, high)? == Ordering::Greater {
+                    return corrupt(format!("key {} above its parent key", key_rowid(&key)?));
+                }
+            }
+            previous = Some(key);
+        }
+        leaves.push(page_num);
+        return Ok((num_cells, 1));
+    }
+
+    let num_keys = unsafe { *internal_node_num_keys(node) } as usize;
+    let min_keys = if parent.is_some() { INTERNAL_NODE_MIN_KEYS.max(1) } else { 1 };
+    if num_keys < min_keys || num_keys > INTERNAL_NODE_MAX_CELLS {
+        return corrupt(format!("internal node has {} keys", num_keys));
+    }
+
+    let mut num_cells = 0;
+    let mut depth = None;
+    let mut child_low = low.map(|low| low.to_vec());
+    for i in 0..(num_keys + 1) {
+        let child_page_num = unsafe { *internal_node_child(node, i)? } as usize;
+        let child_high = match i < num_keys {
+            true => Some(internal_node_key(node, i)),
+            false => high.map(|high| high.to_vec()),
+        };
+        let (child_cells, child_depth) = check_node(table, child_page_num, Some(page_num),
+            child_low.as_deref(), child_high.as_deref(), leaves)?;
+        if *depth.get_or_insert(child_depth) != child_depth {
+            return corrupt("leaves at different depths".to_string());
+        }
+        if i < num_keys {
+            let mut pager = table.pager.borrow_mut();
+            let child = pager.get_page(child_page_num)?;
+            let max_key = get_node_max_key(&mut pager, child)?;
+            if child_high.as_deref() != Some(&max_key[..]) {
+                return corrupt(format!("key {} is not the max key of its child", i));
+            }
+        }
+        num_cells += child_cells;
+        child_low = child_high;
+    }
+
+    if unsafe { *internal_node_num_cells(node) } as usize != num_cells {
+        return corrupt(format!("counts {} cells instead of {}", unsafe { *internal_node_num_cells(node) }, num_cells));
+    }
+    Ok((num_cells, depth.unwrap_or(0) + 1))
+}
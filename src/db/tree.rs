@@ -1,36 +1,17 @@
+use std::cmp::Ordering;
 use std::mem;
 
 use super::error::{DbError, DbResult};
+use super::header::DB_HEADER_SIZE;
 use super::pager::{PAGE_SIZE, Pager};
-use crate::service::Row;
 
 use libc::c_void;
 
-macro_rules! field_size {
-    ($t:ident :: $field:ident) => {{
-        let m = core::mem::MaybeUninit::<$t>::uninit();
-        let p = unsafe { core::ptr::addr_of!((*(&m as *const _ as *const $t)).$field) };
-
-        const fn size_of_raw<T>(_: *const T) -> usize {
-            core::mem::size_of::<T>()
-        }
-        size_of_raw(p)
-    }};
-}
-
 pub enum NodeType {
     NodeInternal,
     NodeLeaf,
 }
 
-pub const ID_SIZE: usize = field_size!(Row::id);
-pub const USERNAME_SIZE: usize = field_size!(Row::username);
-pub const EMAIL_SIZE: usize = field_size!(Row::email);
-pub const ID_OFFSET: usize = 0;
-pub const USERNAME_OFFSET: usize = ID_OFFSET + ID_SIZE;
-pub const EMAIL_OFFSET: usize = USERNAME_OFFSET + USERNAME_SIZE;
-pub const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
-
 /*
  * Common Node Header Layout
  */
@@ -38,9 +19,12 @@ const NODE_TYPE_SIZE: usize = mem::size_of::<u8>();
 const NODE_TYPE_OFFSET: usize = 0;
 const IS_ROOT_SIZE: usize = mem::size_of::<u8>();
 const IS_ROOT_OFFSET: usize = NODE_TYPE_SIZE;
+/* Padding keeps every u32 field below 4-byte aligned */
+const COMMON_NODE_HEADER_PADDING_SIZE: usize = 2;
 const PARENT_POINTER_SIZE: usize = mem::size_of::<u32>();
-const PARENT_POINTER_OFFSET: usize = IS_ROOT_OFFSET + IS_ROOT_SIZE;
-const COMMON_NODE_HEADER_SIZE: usize = NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
+const PARENT_POINTER_OFFSET: usize = IS_ROOT_OFFSET + IS_ROOT_SIZE + COMMON_NODE_HEADER_PADDING_SIZE;
+const COMMON_NODE_HEADER_SIZE: usize =
+    NODE_TYPE_SIZE + IS_ROOT_SIZE + COMMON_NODE_HEADER_PADDING_SIZE + PARENT_POINTER_SIZE;
 
 /*
  * Internal Node Header Layout
@@ -50,17 +34,45 @@ const INTERNAL_NODE_NUM_KEYS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 const INTERNAL_NODE_RIGHT_CHILD_SIZE: usize = mem::size_of::<u32>();
 const INTERNAL_NODE_RIGHT_CHILD_OFFSET: usize =
     INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE;
-const INTERNAL_NODE_HEADER_SIZE: usize =
-    COMMON_NODE_HEADER_SIZE + INTERNAL_NODE_NUM_KEYS_SIZE + INTERNAL_NODE_RIGHT_CHILD_SIZE;
+/* Cells in all the leaves below the node, so whole subtrees can be skipped */
+const INTERNAL_NODE_NUM_CELLS_SIZE: usize = mem::size_of::<u32>();
+const INTERNAL_NODE_NUM_CELLS_OFFSET: usize =
+    INTERNAL_NODE_RIGHT_CHILD_OFFSET + INTERNAL_NODE_RIGHT_CHILD_SIZE;
+const INTERNAL_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE
+    + INTERNAL_NODE_NUM_KEYS_SIZE
+    + INTERNAL_NODE_RIGHT_CHILD_SIZE
+    + INTERNAL_NODE_NUM_CELLS_SIZE;
+
+/*
+Keys are byte strings kept whole in leaves and internal nodes alike, so
+their size is bounded. A table tree is keyed by the 4 bytes of a row id,
+an index tree by the record of the indexed columns and the row id.
+*/
+pub const MAX_KEY_SIZE: usize = 512;
 
 /*
  * Internal Node Body Layout
+ *
+ * A cell is a child page, the size of the max key of that child and the
+ * key, with room for a key of MAX_KEY_SIZE bytes.
  */
-const INTERNAL_NODE_KEY_SIZE: usize = mem::size_of::<u32>();
 const INTERNAL_NODE_CHILD_SIZE: usize = mem::size_of::<u32>();
-pub const INTERNAL_NODE_CELL_SIZE: usize = INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE;
-/* Keep this small for testing */
+const INTERNAL_NODE_KEY_SIZE_SIZE: usize = mem::size_of::<u32>();
+const INTERNAL_NODE_KEY_SIZE_OFFSET: usize = INTERNAL_NODE_CHILD_SIZE;
+const INTERNAL_NODE_KEY_OFFSET: usize = INTERNAL_NODE_KEY_SIZE_OFFSET + INTERNAL_NODE_KEY_SIZE_SIZE;
+pub const INTERNAL_NODE_CELL_SIZE: usize = INTERNAL_NODE_KEY_OFFSET + MAX_KEY_SIZE;
+/* As many cells as fit in a page, only a few in tests so small trees grow deep */
+#[cfg(not(test))]
+pub const INTERNAL_NODE_MAX_CELLS: usize = (PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE) / INTERNAL_NODE_CELL_SIZE;
+#[cfg(test)]
 pub const INTERNAL_NODE_MAX_CELLS: usize = 3;
+/* Non-root internal nodes with fewer keys must borrow or merge */
+pub const INTERNAL_NODE_MIN_KEYS: usize = INTERNAL_NODE_MAX_CELLS / 2;
+/*
+An uninitialized right child of 0 would look like a pointer to the
+file header page. Use an impossible page number instead.
+*/
+pub const INVALID_PAGE_NUM: u32 = u32::MAX;
 
 /*
  * Leaf Node Header Layout
@@ -69,32 +81,118 @@ const LEAF_NODE_NUM_CELLS_SIZE: usize = mem::size_of::<u32>();
 const LEAF_NODE_NUM_CELLS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 const LEAF_NODE_NEXT_LEAF_SIZE: usize = mem::size_of::<u32>();
 const LEAF_NODE_NEXT_LEAF_OFFSET: usize = LEAF_NODE_NUM_CELLS_OFFSET + LEAF_NODE_NUM_CELLS_SIZE;
-const LEAF_NODE_HEADER_SIZE: usize =
-    COMMON_NODE_HEADER_SIZE + LEAF_NODE_NUM_CELLS_SIZE + LEAF_NODE_NEXT_LEAF_SIZE;
+/* Start of the cell content area, which grows down from the page end */
+const LEAF_NODE_CONTENT_START_SIZE: usize = mem::size_of::<u32>();
+const LEAF_NODE_CONTENT_START_OFFSET: usize = LEAF_NODE_NEXT_LEAF_OFFSET + LEAF_NODE_NEXT_LEAF_SIZE;
+/* First free block inside the content area, 0 represents none */
+const LEAF_NODE_FIRST_FREE_BLOCK_SIZE: usize = mem::size_of::<u32>();
+const LEAF_NODE_FIRST_FREE_BLOCK_OFFSET: usize =
+    LEAF_NODE_CONTENT_START_OFFSET + LEAF_NODE_CONTENT_START_SIZE;
+/* Bytes in free blocks plus fragments too small to be one */
+const LEAF_NODE_FREE_BYTES_SIZE: usize = mem::size_of::<u32>();
+const LEAF_NODE_FREE_BYTES_OFFSET: usize =
+    LEAF_NODE_FIRST_FREE_BLOCK_OFFSET + LEAF_NODE_FIRST_FREE_BLOCK_SIZE;
+const LEAF_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE
+    + LEAF_NODE_NUM_CELLS_SIZE
+    + LEAF_NODE_NEXT_LEAF_SIZE
+    + LEAF_NODE_CONTENT_START_SIZE
+    + LEAF_NODE_FIRST_FREE_BLOCK_SIZE
+    + LEAF_NODE_FREE_BYTES_SIZE;
 
 /*
  * Leaf Node Body Layout
+ *
+ * A slotted page: an array of cell pointers in key order follows the
+ * header, the cells themselves are packed from the end of the page.
+ * Cells freed in the middle of the content area are chained into a
+ * list of free blocks, reused by later inserts or squeezed out when
+ * the page is defragmented.
+ *
+ * A cell is the size of its key, the size of its value, the key padded
+ * to 4 bytes and the value. A value too large for the page keeps only
+ * a prefix in the cell, followed by the number of the first overflow
+ * page holding the rest. Keys never overflow.
  */
-const LEAF_NODE_KEY_SIZE: usize = mem::size_of::<u32>();
-const LEAF_NODE_KEY_OFFSET: usize = 0;
-const LEAF_NODE_VALUE_SIZE: usize = ROW_SIZE;
-const LEAF_NODE_VALUE_OFFSET: usize = LEAF_NODE_KEY_OFFSET + LEAF_NODE_KEY_SIZE;
-pub const LEAF_NODE_CELL_SIZE: usize = LEAF_NODE_KEY_SIZE + LEAF_NODE_VALUE_SIZE;
-const LEAF_NODE_SPACE_FOR_CELLS: usize = PAGE_SIZE - LEAF_NODE_HEADER_SIZE;
-pub const LEAF_NODE_MAX_CELLS: usize = LEAF_NODE_SPACE_FOR_CELLS / LEAF_NODE_CELL_SIZE;
-pub const LEAF_NODE_RIGHT_SPLIT_COUNT: usize = (LEAF_NODE_MAX_CELLS + 1) / 2;
-pub const LEAF_NODE_LEFT_SPLIT_COUNT: usize = (LEAF_NODE_MAX_CELLS + 1) - LEAF_NODE_RIGHT_SPLIT_COUNT;
+const LEAF_NODE_CELL_POINTER_SIZE: usize = mem::size_of::<u16>();
+const LEAF_NODE_KEY_SIZE_SIZE: usize = mem::size_of::<u32>();
+const LEAF_NODE_KEY_SIZE_OFFSET: usize = 0;
+const LEAF_NODE_VALUE_SIZE_SIZE: usize = mem::size_of::<u32>();
+const LEAF_NODE_VALUE_SIZE_OFFSET: usize = LEAF_NODE_KEY_SIZE_OFFSET + LEAF_NODE_KEY_SIZE_SIZE;
+const LEAF_NODE_KEY_OFFSET: usize = LEAF_NODE_VALUE_SIZE_OFFSET + LEAF_NODE_VALUE_SIZE_SIZE;
+const LEAF_NODE_CELL_HEADER_SIZE: usize = LEAF_NODE_KEY_SIZE_SIZE + LEAF_NODE_VALUE_SIZE_SIZE;
+const FREE_BLOCK_NEXT_OFFSET: usize = 0;
+const FREE_BLOCK_SIZE_OFFSET: usize = mem::size_of::<u32>();
+/* Cells and the keys in them are rounded up to 4 bytes so every size stays aligned */
+const LEAF_NODE_CELL_ALIGNMENT: usize = 4;
+const LEAF_NODE_MIN_FREE_BLOCK_SIZE: usize = 2 * mem::size_of::<u32>();
+pub const LEAF_NODE_SPACE_FOR_CELLS: usize = PAGE_SIZE - LEAF_NODE_HEADER_SIZE;
+const LEAF_NODE_OVERFLOW_PAGE_SIZE: usize = mem::size_of::<u32>();
+/* Key and value bytes of a cell without overflow, splitting and merging need room for three cells */
+pub const LEAF_NODE_MAX_PAYLOAD_SIZE: usize = ((LEAF_NODE_SPACE_FOR_CELLS / 3 - LEAF_NODE_CELL_POINTER_SIZE)
+    & !(LEAF_NODE_CELL_ALIGNMENT - 1))
+    - LEAF_NODE_CELL_HEADER_SIZE;
+/* Prefix of a larger value kept in its cell */
+pub const LEAF_NODE_MIN_LOCAL_SIZE: usize =
+    (LEAF_NODE_SPACE_FOR_CELLS / 8) & !(LEAF_NODE_CELL_ALIGNMENT - 1);
+/* Non-root leaves using less must borrow or merge */
+pub const LEAF_NODE_MIN_USED_SPACE: usize = LEAF_NODE_SPACE_FOR_CELLS / 4;
+
+/*
+ * Overflow Page Layout
+ *
+ * The rest of a large value, spread over a chain of pages.
+ */
+const OVERFLOW_PAGE_NEXT_SIZE: usize = mem::size_of::<u32>();
+const OVERFLOW_PAGE_NEXT_OFFSET: usize = 0;
+const OVERFLOW_PAGE_HEADER_SIZE: usize = OVERFLOW_PAGE_NEXT_SIZE;
+pub const OVERFLOW_PAGE_SPACE_FOR_DATA: usize = PAGE_SIZE - OVERFLOW_PAGE_HEADER_SIZE;
+
+/*
+Orders the keys of a tree. The tree only ever compares keys through
+the comparator of the cursor walking it.
+*/
+pub trait KeyComparator {
+    fn compare(&self, left: &[u8], right: &[u8]) -> DbResult<Ordering>;
+}
+
+/* Table trees are keyed by row ids, 64 bit and signed as in sqlite */
+pub struct RowidComparator;
+
+impl KeyComparator for RowidComparator {
+    fn compare(&self, left: &[u8], right: &[u8]) -> DbResult<Ordering> {
+        Ok(key_rowid(left)?.cmp(&key_rowid(right)?))
+    }
+}
+
+/* Row ids are stored big endian, whatever the byte order of the host */
+pub fn rowid_key(rowid: i64) -> Vec<u8> {
+    rowid.to_be_bytes().to_vec()
+}
+
+pub fn key_rowid(key: &[u8]) -> DbResult<i64> {
+    match key.try_into() {
+        Ok(bytes) => Ok(i64::from_be_bytes(bytes)),
+        Err(_) => Err(DbError::Other(format!("Rowid key of {} bytes is corrupt.", key.len()))),
+    }
+}
+
+pub fn rowid_key_text(key: &[u8]) -> DbResult<String> {
+    Ok(key_rowid(key)?.to_string())
+}
 
 // methods for low-level b-tree implementation
 
 // ----------- print -----------------//
 pub fn print_constants() {
-    println!("ROW_SIZE: {}", ROW_SIZE);
+    println!("DB_HEADER_SIZE: {}", DB_HEADER_SIZE);
     println!("COMMON_NODE_HEADER_SIZE: {}", COMMON_NODE_HEADER_SIZE);
     println!("LEAF_NODE_HEADER_SIZE: {}", LEAF_NODE_HEADER_SIZE);
-    println!("LEAF_NODE_CELL_SIZE: {}", LEAF_NODE_CELL_SIZE);
     println!("LEAF_NODE_SPACE_FOR_CELLS: {}", LEAF_NODE_SPACE_FOR_CELLS);
-    println!("LEAF_NODE_MAX_CELLS: {}", LEAF_NODE_MAX_CELLS);
+    println!("LEAF_NODE_MAX_PAYLOAD_SIZE: {}", LEAF_NODE_MAX_PAYLOAD_SIZE);
+    println!("MAX_KEY_SIZE: {}", MAX_KEY_SIZE);
+    println!("LEAF_NODE_MIN_LOCAL_SIZE: {}", LEAF_NODE_MIN_LOCAL_SIZE);
+    println!("LEAF_NODE_MIN_USED_SPACE: {}", LEAF_NODE_MIN_USED_SPACE);
+    println!("OVERFLOW_PAGE_SPACE_FOR_DATA: {}", OVERFLOW_PAGE_SPACE_FOR_DATA);
 }
 
 fn indent(level: u32) {
@@ -103,7 +201,8 @@ fn indent(level: u32) {
     }
 }
 
-pub fn print_tree(pager: &mut Pager, page_num: usize, indentation_level: u32) -> DbResult<()> {
+pub fn print_tree(pager: &mut Pager, page_num: usize, indentation_level: u32,
+    key_text: fn(&[u8]) -> DbResult<String>) -> DbResult<()> {
     let node = pager.get_page(page_num)?;
     let mut num_keys: u32 = 0;
     let mut child: u32 = 0;
@@ -117,9 +216,7 @@ pub fn print_tree(pager: &mut Pager, page_num: usize, indentation_level: u32) ->
             println!("- leaf (size {})", num_keys);
             for i in 0..num_keys {
                 indent(indentation_level + 1);
-                unsafe{
-                    println!("- {}", *leaf_node_key(node, i as usize));
-                }
+                println!("- {}", key_text(&leaf_node_key(node, i as usize))?);
             }
             return Ok(());
         },
@@ -134,21 +231,19 @@ pub fn print_tree(pager: &mut Pager, page_num: usize, indentation_level: u32) ->
                     *internal_node_child(node, i as usize)?
                 };
                 
-                let print_res = print_tree(pager, child as usize, indentation_level + 1);
+                let print_res = print_tree(pager, child as usize, indentation_level + 1, key_text);
                 if print_res.is_err() {
                     return print_res;
                 }
 
                 indent(indentation_level + 1);
-                unsafe {
-                    println!("- key {}", *internal_node_key(node, i as usize));
-                }
+                println!("- key {}", key_text(&internal_node_key(node, i as usize))?);
             }
             child = unsafe {
                 *internal_node_right_child(node)
             };
             
-            return print_tree(pager, child as usize, indentation_level + 1);  
+            print_tree(pager, child as usize, indentation_level + 1, key_text)
         }
     }
 }
@@ -191,7 +286,7 @@ pub fn is_node_root(node: *const c_void) -> bool {
 pub fn set_node_root(node: *mut c_void, is_root: bool) {
     unsafe {
         let node_root_ptr = (node as *const u8)
-            .offset(NODE_TYPE_OFFSET as isize) 
+            .offset(IS_ROOT_OFFSET as isize) 
             as *mut u8;
         *node_root_ptr = is_root as u8;
     }
@@ -221,6 +316,22 @@ pub fn internal_node_right_child(node: *mut c_void) -> *mut u32 {
     }
 }
 
+pub fn internal_node_num_cells(node: *mut c_void) -> *mut u32 {
+    unsafe {
+        (node as *const u8)
+            .add(INTERNAL_NODE_NUM_CELLS_OFFSET)
+            as *mut u32
+    }
+}
+
+/* Cells in the leaves of the subtree under a node */
+pub fn node_num_cells(node: *mut c_void) -> u32 {
+    match get_node_type(node) {
+        NodeType::NodeLeaf => unsafe { *leaf_node_num_cells(node) },
+        NodeType::NodeInternal => unsafe { *internal_node_num_cells(node) },
+    }
+}
+
 pub fn internal_node_cell(node: *mut c_void, cell_num: usize) -> *mut u32 {
     unsafe {
         return (node as *const u8)
@@ -240,19 +351,49 @@ pub fn internal_node_child(node: *mut c_void, child_num: usize) -> DbResult<*mut
                 child_num, num_keys
             )));
         } else if child_num == num_keys {
-            return Ok(internal_node_right_child(node));
+            let right_child = internal_node_right_child(node);
+            if *right_child == INVALID_PAGE_NUM {
+                return Err(DbError::Other(
+                    "Tried to access right child of node, but was invalid page".to_string(),
+                ));
+            }
+            Ok(right_child)
         } else {
-            return Ok(internal_node_cell(node, child_num));
+            let child = internal_node_cell(node, child_num);
+            if *child == INVALID_PAGE_NUM {
+                return Err(DbError::Other(format!(
+                    "Tried to access child {} of node, but was invalid page",
+                    child_num
+                )));
+            }
+            Ok(child)
         }
     }
 }
 
-pub fn internal_node_key(node: *mut c_void, key_num: usize) -> *mut u32 {
+pub fn internal_node_key_size(node: *mut c_void, key_num: usize) -> *mut u32 {
     unsafe {
-        let internal_node_key_ptr = (internal_node_cell(node, key_num) as *const u8)
-            .offset(INTERNAL_NODE_CHILD_SIZE as isize)
-            as *mut u32;
-        internal_node_key_ptr
+        (internal_node_cell(node, key_num) as *const u8)
+            .add(INTERNAL_NODE_KEY_SIZE_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn internal_node_key(node: *mut c_void, key_num: usize) -> Vec<u8> {
+    unsafe {
+        let key_size = *internal_node_key_size(node, key_num) as usize;
+        let key_ptr = (internal_node_cell(node, key_num) as *const u8)
+            .add(INTERNAL_NODE_KEY_OFFSET);
+        std::slice::from_raw_parts(key_ptr, key_size).to_vec()
+    }
+}
+
+pub fn set_internal_node_key(node: *mut c_void, key_num: usize, key: &[u8]) {
+    unsafe {
+        *internal_node_key_size(node, key_num) = key.len() as u32;
+        let key_ptr = (internal_node_cell(node, key_num) as *mut u8)
+            .add(INTERNAL_NODE_KEY_OFFSET);
+        libc::memcpy(key_ptr as *mut c_void, key.as_ptr() as *const c_void, key.len());
     }
 }
 
@@ -272,34 +413,392 @@ pub fn leaf_node_next_leaf(node: *mut c_void) -> *mut u32 {
     }
 }
 
+pub fn leaf_node_content_start(node: *mut c_void) -> *mut u32 {
+    unsafe {
+        (node as *const u8)
+            .add(LEAF_NODE_CONTENT_START_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn leaf_node_first_free_block(node: *mut c_void) -> *mut u32 {
+    unsafe {
+        (node as *const u8)
+            .add(LEAF_NODE_FIRST_FREE_BLOCK_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn leaf_node_free_bytes(node: *mut c_void) -> *mut u32 {
+    unsafe {
+        (node as *const u8)
+            .add(LEAF_NODE_FREE_BYTES_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn leaf_node_cell_pointer(node: *mut c_void, cell_num: usize) -> *mut u16 {
+    unsafe {
+        (node as *const u8)
+            .add(LEAF_NODE_HEADER_SIZE + cell_num * LEAF_NODE_CELL_POINTER_SIZE)
+            as *mut u16
+    }
+}
+
 pub fn leaf_node_cell(node: *mut c_void, cell_num: usize) -> *mut c_void {
     unsafe {
         return (node as *const u8)
-            .offset(LEAF_NODE_HEADER_SIZE as isize + (cell_num * LEAF_NODE_CELL_SIZE) as isize)
+            .offset(*leaf_node_cell_pointer(node, cell_num) as isize)
             as *mut c_void;
     }
 }
 
-pub fn leaf_node_key(node: *mut c_void, cell_num: usize) -> *mut u32 {
+pub fn leaf_node_key_size(node: *mut c_void, cell_num: usize) -> *mut u32 {
     unsafe {
-        return leaf_node_cell(node, cell_num)
-            as *mut u32;
+        (leaf_node_cell(node, cell_num) as *const u8)
+            .add(LEAF_NODE_KEY_SIZE_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn leaf_node_key(node: *mut c_void, cell_num: usize) -> Vec<u8> {
+    unsafe {
+        let key_size = *leaf_node_key_size(node, cell_num) as usize;
+        let key_ptr = (leaf_node_cell(node, cell_num) as *const u8)
+            .add(LEAF_NODE_KEY_OFFSET);
+        std::slice::from_raw_parts(key_ptr, key_size).to_vec()
+    }
+}
+
+pub fn leaf_node_value_size(node: *mut c_void, cell_num: usize) -> *mut u32 {
+    unsafe {
+        (leaf_node_cell(node, cell_num) as *const u8)
+            .add(LEAF_NODE_VALUE_SIZE_OFFSET)
+            as *mut u32
     }
-  }
+}
   
 pub fn leaf_node_value(node: *mut c_void, cell_num: usize) -> *mut c_void {
     unsafe {
+        let key_space = leaf_node_key_space(*leaf_node_key_size(node, cell_num) as usize);
         return (leaf_node_cell(node, cell_num) as *const u8)
-            .offset(LEAF_NODE_KEY_SIZE as isize)
+            .offset((LEAF_NODE_KEY_OFFSET + key_space) as isize)
             as *mut c_void;
     }
 }
 
-pub fn get_node_max_key(node: *mut c_void) -> u32 {
+/* Number of the first overflow page, only for cells with has_overflow */
+pub fn leaf_node_overflow_page(node: *mut c_void, cell_num: usize) -> *mut u32 {
+    unsafe {
+        let local_size = leaf_node_local_size(
+            *leaf_node_key_size(node, cell_num) as usize,
+            *leaf_node_value_size(node, cell_num) as usize,
+        );
+        (leaf_node_value(node, cell_num) as *const u8)
+            .add(local_size)
+            as *mut u32
+    }
+}
+
+pub fn overflow_page_next(page: *mut c_void) -> *mut u32 {
+    unsafe {
+        (page as *const u8)
+            .add(OVERFLOW_PAGE_NEXT_OFFSET)
+            as *mut u32
+    }
+}
+
+pub fn overflow_page_data(page: *mut c_void) -> *mut c_void {
+    unsafe {
+        (page as *const u8)
+            .add(OVERFLOW_PAGE_HEADER_SIZE)
+            as *mut c_void
+    }
+}
+
+fn free_block_next(node: *mut c_void, offset: usize) -> *mut u32 {
+    unsafe {
+        (node as *const u8)
+            .add(offset + FREE_BLOCK_NEXT_OFFSET)
+            as *mut u32
+    }
+}
+
+fn free_block_size(node: *mut c_void, offset: usize) -> *mut u32 {
+    unsafe {
+        (node as *const u8)
+            .add(offset + FREE_BLOCK_SIZE_OFFSET)
+            as *mut u32
+    }
+}
+
+/* Bytes a key of key_size bytes takes in a cell */
+fn leaf_node_key_space(key_size: usize) -> usize {
+    (key_size + LEAF_NODE_CELL_ALIGNMENT - 1) & !(LEAF_NODE_CELL_ALIGNMENT - 1)
+}
+
+pub fn leaf_node_has_overflow(key_size: usize, value_size: usize) -> bool {
+    leaf_node_key_space(key_size) + value_size > LEAF_NODE_MAX_PAYLOAD_SIZE
+}
+
+/* Bytes of a value of value_size bytes stored in its cell */
+pub fn leaf_node_local_size(key_size: usize, value_size: usize) -> usize {
+    match leaf_node_has_overflow(key_size, value_size) {
+        true => LEAF_NODE_MIN_LOCAL_SIZE,
+        false => value_size,
+    }
+}
+
+/* Bytes taken in the content area by a cell holding key_size and value_size bytes */
+pub fn leaf_node_cell_size_for(key_size: usize, value_size: usize) -> usize {
+    let mut size = LEAF_NODE_CELL_HEADER_SIZE + leaf_node_key_space(key_size) + leaf_node_local_size(key_size, value_size);
+    if leaf_node_has_overflow(key_size, value_size) {
+        size += LEAF_NODE_OVERFLOW_PAGE_SIZE;
+    }
+    (size + LEAF_NODE_CELL_ALIGNMENT - 1) & !(LEAF_NODE_CELL_ALIGNMENT - 1)
+}
+
+pub fn leaf_node_cell_size(node: *mut c_void, cell_num: usize) -> usize {
+    unsafe {
+        leaf_node_cell_size_for(
+            *leaf_node_key_size(node, cell_num) as usize,
+            *leaf_node_value_size(node, cell_num) as usize,
+        )
+    }
+}
+
+/* Unallocated bytes between the cell pointer array and the content area */
+fn leaf_node_gap(node: *mut c_void) -> usize {
+    unsafe {
+        *leaf_node_content_start(node) as usize
+            - (LEAF_NODE_HEADER_SIZE + *leaf_node_num_cells(node) as usize * LEAF_NODE_CELL_POINTER_SIZE)
+    }
+}
+
+pub fn leaf_node_free_space(node: *mut c_void) -> usize {
+    leaf_node_gap(node) + unsafe { *leaf_node_free_bytes(node) } as usize
+}
+
+pub fn leaf_node_used_space(node: *mut c_void) -> usize {
+    LEAF_NODE_SPACE_FOR_CELLS - leaf_node_free_space(node)
+}
+
+/*
+Image of a cell holding key and local, the first bytes of a value of
+value_size bytes. overflow_page_num is the page holding the rest, if any.
+*/
+pub fn leaf_node_build_cell(key: &[u8], value_size: usize, local: &[u8], overflow_page_num: u32) -> Vec<u8> {
+    let cell_size = leaf_node_cell_size_for(key.len(), value_size);
+    let mut cell = Vec::with_capacity(cell_size);
+    cell.extend_from_slice(&(key.len() as u32).to_ne_bytes());
+    cell.extend_from_slice(&(value_size as u32).to_ne_bytes());
+    cell.extend_from_slice(key);
+    cell.resize(LEAF_NODE_KEY_OFFSET + leaf_node_key_space(key.len()), 0);
+    cell.extend_from_slice(local);
+    if leaf_node_has_overflow(key.len(), value_size) {
+        cell.extend_from_slice(&overflow_page_num.to_ne_bytes());
+    }
+    cell.resize(cell_size, 0);
+    cell
+}
+
+pub fn leaf_node_cell_key(cell: &[u8]) -> Vec<u8> {
+    let key_size = u32::from_ne_bytes(cell[LEAF_NODE_KEY_SIZE_OFFSET..LEAF_NODE_VALUE_SIZE_OFFSET].try_into().unwrap());
+    cell[LEAF_NODE_KEY_OFFSET..(LEAF_NODE_KEY_OFFSET + key_size as usize)].to_vec()
+}
+
+pub fn leaf_node_defragment(node: *mut c_void) {
+    /*
+    Move every cell to the end of the page, leaving the free space
+    in one piece between the cell pointers and the content area.
+    */
+    let num_cells = unsafe { *leaf_node_num_cells(node) } as usize;
+    let mut content = vec![0u8; PAGE_SIZE];
+    let mut content_start = PAGE_SIZE;
+    for i in 0..num_cells {
+        let cell_size = leaf_node_cell_size(node, i);
+        content_start -= cell_size;
+        unsafe {
+            libc::memcpy(
+                content.as_mut_ptr().add(content_start) as *mut c_void,
+                leaf_node_cell(node, i),
+                cell_size,
+            );
+            *leaf_node_cell_pointer(node, i) = content_start as u16;
+        }
+    }
     unsafe {
-        match get_node_type(node) {
-            NodeType::NodeInternal => *internal_node_key(node, *internal_node_num_keys(node) as usize - 1),
-            NodeType::NodeLeaf => *leaf_node_key(node, *leaf_node_num_cells(node) as usize - 1),
+        libc::memcpy(
+            (node as *mut u8).add(content_start) as *mut c_void,
+            content.as_ptr().add(content_start) as *const c_void,
+            PAGE_SIZE - content_start,
+        );
+        *leaf_node_content_start(node) = content_start as u32;
+        *leaf_node_first_free_block(node) = 0;
+        *leaf_node_free_bytes(node) = 0;
+    }
+}
+
+fn leaf_node_allocate(node: *mut c_void, size: usize) -> usize {
+    /*
+    Take size bytes for a cell, first fit from the free blocks, else
+    from the gap. The caller made sure the page has enough free space.
+    */
+    unsafe {
+        let mut prev: usize = 0;
+        let mut offset = *leaf_node_first_free_block(node) as usize;
+        while offset != 0 {
+            let block_size = *free_block_size(node, offset) as usize;
+            let next = *free_block_next(node, offset);
+            if block_size >= size {
+                *leaf_node_free_bytes(node) -= size as u32;
+                let rest = block_size - size;
+                if rest >= LEAF_NODE_MIN_FREE_BLOCK_SIZE {
+                    /* Keep the front of the block free, hand out its end */
+                    *free_block_size(node, offset) = rest as u32;
+                    return offset + rest;
+                }
+                /* A rest too small for a free block stays a fragment */
+                if prev == 0 {
+                    *leaf_node_first_free_block(node) = next;
+                } else {
+                    *free_block_next(node, prev) = next;
+                }
+                return offset;
+            }
+            prev = offset;
+            offset = next as usize;
+        }
+
+        if leaf_node_gap(node) < size + LEAF_NODE_CELL_POINTER_SIZE {
+            leaf_node_defragment(node);
+        }
+        *leaf_node_content_start(node) -= size as u32;
+        *leaf_node_content_start(node) as usize
+    }
+}
+
+pub fn leaf_node_insert_cell(node: *mut c_void, cell_num: usize, cell: &[u8]) -> bool {
+    /*
+    Insert a cell image at position cell_num of the pointer array.
+    Returns false when the page has no room for it.
+    */
+    let cell_size = cell.len();
+    if leaf_node_free_space(node) < cell_size + LEAF_NODE_CELL_POINTER_SIZE {
+        return false;
+    }
+    if leaf_node_gap(node) < LEAF_NODE_CELL_POINTER_SIZE {
+        leaf_node_defragment(node);
+    }
+    let offset = leaf_node_allocate(node, cell_size);
+
+    unsafe {
+        let num_cells = *leaf_node_num_cells(node) as usize;
+        if cell_num < num_cells {
+            /* Make room in the pointer array */
+            libc::memmove(
+                leaf_node_cell_pointer(node, cell_num + 1) as *mut c_void,
+                leaf_node_cell_pointer(node, cell_num) as *const c_void,
+                (num_cells - cell_num) * LEAF_NODE_CELL_POINTER_SIZE,
+            );
+        }
+        *leaf_node_cell_pointer(node, cell_num) = offset as u16;
+        *leaf_node_num_cells(node) += 1;
+        libc::memcpy(leaf_node_cell(node, cell_num), cell.as_ptr() as *const c_void, cell_size);
+    }
+    true
+}
+
+pub fn leaf_node_remove_cell(node: *mut c_void, cell_num: usize) {
+    /*
+    Drop a cell from the pointer array. Its bytes join the gap when it
+    sits at the start of the content area, else become a free block.
+    */
+    unsafe {
+        let offset = *leaf_node_cell_pointer(node, cell_num) as usize;
+        let cell_size = leaf_node_cell_size(node, cell_num);
+        if offset == *leaf_node_content_start(node) as usize {
+            *leaf_node_content_start(node) += cell_size as u32;
+        } else {
+            *free_block_next(node, offset) = *leaf_node_first_free_block(node);
+            *free_block_size(node, offset) = cell_size as u32;
+            *leaf_node_first_free_block(node) = offset as u32;
+            *leaf_node_free_bytes(node) += cell_size as u32;
+        }
+
+        let num_cells = *leaf_node_num_cells(node) as usize;
+        libc::memmove(
+            leaf_node_cell_pointer(node, cell_num) as *mut c_void,
+            leaf_node_cell_pointer(node, cell_num + 1) as *const c_void,
+            (num_cells - cell_num - 1) * LEAF_NODE_CELL_POINTER_SIZE,
+        );
+        *leaf_node_num_cells(node) -= 1;
+
+        if *leaf_node_num_cells(node) == 0 {
+            *leaf_node_content_start(node) = PAGE_SIZE as u32;
+            *leaf_node_first_free_block(node) = 0;
+            *leaf_node_free_bytes(node) = 0;
+        }
+    }
+}
+
+/* Images of every cell of a leaf, in key order */
+pub fn leaf_node_cells(node: *mut c_void) -> Vec<Vec<u8>> {
+    let num_cells = unsafe { *leaf_node_num_cells(node) } as usize;
+    (0..num_cells)
+        .map(|i| unsafe {
+            std::slice::from_raw_parts(leaf_node_cell(node, i) as *const u8, leaf_node_cell_size(node, i)).to_vec()
+        })
+        .collect()
+}
+
+/* Replace the cells of a leaf, keeping its place in the tree. Errors when they do not fit. */
+pub fn leaf_node_fill(node: *mut c_void, cells: &[Vec<u8>]) -> DbResult<()> {
+    unsafe {
+        *leaf_node_num_cells(node) = 0;
+        *leaf_node_content_start(node) = PAGE_SIZE as u32;
+        *leaf_node_first_free_block(node) = 0;
+        *leaf_node_free_bytes(node) = 0;
+    }
+    for (i, cell) in cells.iter().enumerate() {
+        if !leaf_node_insert_cell(node, i, cell) {
+            return Err(DbError::Other(format!("{} cells do not fit in a leaf.", cells.len())));
+        }
+    }
+    Ok(())
+}
+
+pub fn leaf_node_split_point(cells: &[Vec<u8>]) -> usize {
+    /*
+    Number of cells for the left node when cells are divided between
+    two leaves, so that both end up with about the same number of bytes.
+    */
+    let total: usize = cells.iter().map(|cell| cell.len() + LEAF_NODE_CELL_POINTER_SIZE).sum();
+    let mut left: usize = 0;
+    for (i, cell) in cells.iter().enumerate() {
+        let space = cell.len() + LEAF_NODE_CELL_POINTER_SIZE;
+        if i > 0 && left + space / 2 > total / 2 {
+            return i;
+        }
+        left += space;
+    }
+    cells.len() - 1
+}
+
+pub fn get_node_max_key(pager: &mut Pager, node: *mut c_void) -> DbResult<Vec<u8>> {
+    /*
+    The max key of an internal node lives in its right-most subtree,
+    internal keys only describe the children to their left.
+    */
+    match get_node_type(node) {
+        NodeType::NodeInternal => {
+            let right_child_page_num = unsafe { *internal_node_right_child(node) };
+            let right_child = pager.get_page(right_child_page_num as usize)?;
+            get_node_max_key(pager, right_child)
+        }
+        NodeType::NodeLeaf => {
+            let num_cells = unsafe { *leaf_node_num_cells(node) } as usize;
+            Ok(leaf_node_key(node, num_cells - 1))
         }
     }
 }
@@ -310,6 +809,9 @@ pub fn initialize_leaf_node(node: *mut c_void) {
     unsafe {
         *leaf_node_num_cells(node) = 0;
         *leaf_node_next_leaf(node) = 0; // 0 represents no sibling
+        *leaf_node_content_start(node) = PAGE_SIZE as u32;
+        *leaf_node_first_free_block(node) = 0;
+        *leaf_node_free_bytes(node) = 0;
     } 
 }
 
@@ -318,10 +820,12 @@ pub fn initialize_internal_node(node: *mut c_void) {
     set_node_root(node, false);
     unsafe {
         *internal_node_num_keys(node) = 0;
+        *internal_node_right_child(node) = INVALID_PAGE_NUM;
+        *internal_node_num_cells(node) = 0;
     }
 }
 
-pub fn internal_node_find_child(node: *mut c_void, key: u32) -> u32 {
+pub fn internal_node_find_child(node: *mut c_void, key: &[u8], comparator: &dyn KeyComparator) -> DbResult<u32> {
     /*
     Return the index of the child which should contain
     the given key.
@@ -337,20 +841,164 @@ pub fn internal_node_find_child(node: *mut c_void, key: u32) -> u32 {
   
     while min_index != max_index {
       let index: u32 = min_index + (max_index - min_index) / 2;
-      let key_to_right: u32 = unsafe{
-          *internal_node_key(node, index as usize)
-        };
-      if key_to_right >= key {
+      let key_to_right = internal_node_key(node, index as usize);
+      if comparator.compare(&key_to_right, key)? != Ordering::Less {
         max_index = index;
       } else {
         min_index = index + 1;
       }
     }
-    min_index
+    Ok(min_index)
+}
+
+pub fn update_internal_node_key(node: *mut c_void, old_key: &[u8], new_key: &[u8],
+    comparator: &dyn KeyComparator) -> DbResult<()> {
+    let old_child_index = internal_node_find_child(node, old_key, comparator)?;
+    /* The right child has no key of its own */
+    if old_child_index < unsafe { *internal_node_num_keys(node) } {
+        set_internal_node_key(node, old_child_index as usize, new_key);
+    }
+    Ok(())
+}
+
+pub fn internal_node_child_index(node: *mut c_void, child_page_num: u32) -> DbResult<u32> {
+    /*
+    Return the position of the given child page within the node.
+    The right child is reported as index num_keys.
+    */
+    let num_keys = unsafe { *internal_node_num_keys(node) };
+    for i in 0..(num_keys + 1) {
+        if unsafe { *internal_node_child(node, i as usize)? } == child_page_num {
+            return Ok(i);
+        }
+    }
+    Err(DbError::Other(format!(
+        "Page {} is not a child of this node",
+        child_page_num
+    )))
 }
 
-pub fn update_internal_node_key(node: *mut c_void, old_key: u32, new_key: u32) {
-    let old_child_index = internal_node_find_child(node, old_key);
-    unsafe {*internal_node_key(node, old_child_index as usize) = new_key;}
+pub fn internal_node_remove_child(node: *mut c_void, child_index: usize) -> DbResult<()> {
+    /*
+    Remove the child at child_index together with its key.
+    Removing the right child promotes the last keyed child in its place.
+    */
+    unsafe {
+        let num_keys = *internal_node_num_keys(node) as usize;
+        if child_index > num_keys || num_keys == 0 {
+            return Err(DbError::Other(format!(
+                "Tried to remove child_num {} of node with {} keys",
+                child_index, num_keys
+            )));
+        }
+
+        if child_index == num_keys {
+            *internal_node_right_child(node) = *internal_node_child(node, num_keys - 1)?;
+        } else {
+            for i in child_index..(num_keys - 1) {
+                libc::memcpy(
+                    internal_node_cell(node, i) as *mut c_void,
+                    internal_node_cell(node, i + 1) as *const c_void,
+                    INTERNAL_NODE_CELL_SIZE,
+                );
+            }
+        }
+        *internal_node_num_keys(node) = num_keys as u32 - 1;
+    }
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /* A leaf in memory, aligned like the pages of the cache */
+    fn new_leaf() -> Vec<u32> {
+        let mut page = vec![0u32; PAGE_SIZE / mem::size_of::<u32>()];
+        initialize_leaf_node(page.as_mut_ptr() as *mut c_void);
+        page
+    }
+
+    fn cell(rowid: i64, value_size: usize) -> Vec<u8> {
+        let value = vec![rowid as u8; value_size];
+        leaf_node_build_cell(&rowid_key(rowid), value_size, &value, 0)
+    }
+
+    /* Cells are the ones expected, in order, and every byte of the page is accounted for */
+    fn check_leaf(node: *mut c_void, expected: &BTreeMap<i64, Vec<u8>>) {
+        let cells = leaf_node_cells(node);
+        assert_eq!(cells.len(), expected.len());
+        for (i, (cell, (&rowid, expected))) in cells.iter().zip(expected).enumerate() {
+            assert_eq!(key_rowid(&leaf_node_key(node, i)).unwrap(), rowid);
+            assert_eq!(cell, expected, "cell of row {} changed", rowid);
+        }
+        let used: usize = cells.iter().map(|cell| cell.len() + LEAF_NODE_CELL_POINTER_SIZE).sum();
+        assert_eq!(leaf_node_used_space(node), used);
+    }
+
+    fn insert(node: *mut c_void, cells: &mut BTreeMap<i64, Vec<u8>>, rowid: i64, value_size: usize) -> bool {
+        let cell = cell(rowid, value_size);
+        let cell_num = cells.range(..rowid).count();
+        let free_space = leaf_node_free_space(node);
+        if !leaf_node_insert_cell(node, cell_num, &cell) {
+            assert!(free_space < cell.len() + LEAF_NODE_CELL_POINTER_SIZE, "cell refused with room left");
+            return false;
+        }
+        cells.insert(rowid, cell);
+        true
+    }
+
+    #[test]
+    fn cells_of_any_size_fill_the_leaf_in_key_order() {
+        let mut page = new_leaf();
+        let node = page.as_mut_ptr() as *mut c_void;
+        let mut cells = BTreeMap::new();
+        let mut rowid = 0;
+        while insert(node, &mut cells, (rowid * 37) % 101, (rowid as usize * 53) % 300) {
+            rowid += 1;
+        }
+        assert!(cells.len() > 10);
+        check_leaf(node, &cells);
+    }
+
+    #[test]
+    fn freed_space_is_reused() {
+        let mut page = new_leaf();
+        let node = page.as_mut_ptr() as *mut c_void;
+        let mut cells = BTreeMap::new();
+        let mut seed: u64 = 1;
+        let mut random = move |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+
+        let mut num_refused = 0;
+        for _ in 0..20_000 {
+            if !cells.is_empty() && random(2) == 0 {
+                let cell_num = random(cells.len());
+                let rowid = *cells.keys().nth(cell_num).unwrap();
+                leaf_node_remove_cell(node, cell_num);
+                cells.remove(&rowid);
+            } else {
+                let rowid = random(1_000) as i64;
+                if !cells.contains_key(&rowid) && !insert(node, &mut cells, rowid, random(400)) {
+                    num_refused += 1;
+                }
+            }
+            check_leaf(node, &cells);
+        }
+        /* The page was full often enough for free blocks and fragments to matter */
+        assert!(num_refused > 100);
+    }
+
+    #[test]
+    fn fill_fails_when_the_cells_do_not_fit() {
+        let mut page = new_leaf();
+        let node = page.as_mut_ptr() as *mut c_void;
+        let cells: Vec<Vec<u8>> = (0..4).map(|rowid| cell(rowid, 1_000)).collect();
+        leaf_node_fill(node, &cells[..3]).unwrap();
+        assert_eq!(leaf_node_cells(node), cells[..3]);
+        assert!(leaf_node_fill(node, &cells).is_err());
+    }
+}
@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use libc::{c_int, c_void};
+
+use super::error::{DbError, DbResult};
+use super::journal::page_checksum;
+use super::pager::PAGE_SIZE;
+
+/*
+ * Write-Ahead Log Layout
+ *
+ * header: magic, page size, checkpoint sequence, salt
+ * frame:  page number, database size in pages for commit frames
+ *         (0 otherwise), salt, checksum, page image
+ *
+ * Committed pages are appended as frames instead of being written into
+ * the database file. A frame only counts when its salt matches the
+ * header and its checksum is intact, and only frames up to the last
+ * commit frame are visible. A checkpoint copies the newest frame of every
+ * page back into the database file and restarts the log with a new salt,
+ * which invalidates every old frame at once.
+ *
+ * Read marks, in <db>-shm: one slot per connection, salt and last frame
+ *
+ * A connection owns its slot through a lock on its bytes, held while the
+ * log is open, and marks the snapshot it reads from while a statement or
+ * a transaction runs. A checkpoint only copies frames every marked
+ * snapshot already has, and only restarts the log when no other
+ * connection holds a snapshot. A frame overwritten by a restart anyway
+ * fails its salt check and the page is read from the database file.
+ *
+ * Writer lock, the byte after the read marks
+ *
+ * Taken before the first change of a transaction and held until its
+ * frames are synced, so only one connection appends at a time. A
+ * transaction whose snapshot misses a commit of another connection may
+ * not write at all, its changes would be based on stale pages.
+ */
+const WAL_MAGIC: &[u8; WAL_MAGIC_SIZE] = b"tinywal\0";
+const WAL_MAGIC_SIZE: usize = 8;
+const WAL_PAGE_SIZE_OFFSET: usize = WAL_MAGIC_SIZE;
+const WAL_CHECKPOINT_SEQ_OFFSET: usize = WAL_PAGE_SIZE_OFFSET + mem::size_of::<u32>();
+const WAL_SALT_OFFSET: usize = WAL_CHECKPOINT_SEQ_OFFSET + mem::size_of::<u32>();
+const WAL_HEADER_SIZE: usize = WAL_SALT_OFFSET + mem::size_of::<u32>();
+
+const WAL_FRAME_PAGE_NUM_OFFSET: usize = 0;
+const WAL_FRAME_DB_SIZE_OFFSET: usize = WAL_FRAME_PAGE_NUM_OFFSET + mem::size_of::<u32>();
+const WAL_FRAME_SALT_OFFSET: usize = WAL_FRAME_DB_SIZE_OFFSET + mem::size_of::<u32>();
+const WAL_FRAME_CHECKSUM_OFFSET: usize = WAL_FRAME_SALT_OFFSET + mem::size_of::<u32>();
+const WAL_FRAME_HEADER_SIZE: usize = WAL_FRAME_CHECKSUM_OFFSET + mem::size_of::<u32>();
+const WAL_FRAME_SIZE: usize = WAL_FRAME_HEADER_SIZE + PAGE_SIZE;
+
+const WAL_READ_MARKS: usize = 16;
+const READ_MARK_SALT_OFFSET: usize = 0;
+const READ_MARK_FRAME_OFFSET: usize = READ_MARK_SALT_OFFSET + mem::size_of::<u32>();
+const READ_MARK_SIZE: usize = READ_MARK_FRAME_OFFSET + mem::size_of::<u32>();
+/* Frame of a read mark while its connection holds no snapshot */
+const READ_MARK_IDLE: u32 = u32::MAX;
+/* Byte of the shm file locked by the one connection writing to the log */
+const WRITER_LOCK_OFFSET: usize = WAL_READ_MARKS * READ_MARK_SIZE;
+
+/* Pages with the frame holding their newest version */
+type PageFrames = Vec<(usize, u32)>;
+
+/* Checkpoint automatically once the log holds this many frames not yet copied back */
+pub const WAL_AUTOCHECKPOINT: u32 = 1000;
+
+fn frame_checksum(page_num: u32, db_size: u32, salt: u32, data: &[u8]) -> u32 {
+    page_checksum(page_num, data).wrapping_add(db_size).rotate_left(7) ^ salt
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/*
+Lock, unlock or test bytes of the shm file. The locks belong to the open
+file, not the process, so connections in one process exclude each other
+too. Returns false when another connection holds them.
+*/
+fn shm_lock(file: &File, start: usize, len: usize, l_type: c_int, cmd: c_int) -> DbResult<bool> {
+    let mut lock: libc::flock = unsafe { mem::zeroed() };
+    lock.l_type = l_type as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = start as libc::off_t;
+    lock.l_len = len as libc::off_t;
+    if unsafe { libc::fcntl(file.as_raw_fd(), cmd, &mut lock) } == -1 {
+        let error = std::io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => Ok(false),
+            _ => Err(error.into()),
+        };
+    }
+    Ok(cmd != libc::F_OFD_GETLK || lock.l_type == libc::F_UNLCK as libc::c_short)
+}
+
+fn read_mark_lock(file: &File, slot: usize, cmd: c_int) -> DbResult<bool> {
+    shm_lock(file, slot * READ_MARK_SIZE, READ_MARK_SIZE, libc::F_WRLCK, cmd)
+}
+
+/*
+The in-memory WAL index maps every page to the frames holding it.
+It only ever contains frames up to the end of the last transaction this
+connection has seen, so lookups read one consistent snapshot even while
+another connection keeps appending to the log.
+*/
+pub struct Wal {
+    path: String,
+    shm_path: String,
+    file: Option<File>,
+    shm: Option<File>,
+    slot: usize, // of the read mark of this connection
+    checkpoint_seq: u32,
+    salt: u32,
+    frames: HashMap<usize, Vec<u32>>, // page number -> frame numbers, oldest first
+    num_frames: u32,                  // frames written, including an open transaction
+    max_frame: u32,                   // last frame of the last committed transaction
+    backfilled: u32,                  // frames up to this one are in the database file
+    db_num_pages: usize,              // database size recorded by the last commit
+    synced: bool,
+    writer: bool, // holds the writer lock
+}
+
+impl Wal {
+    pub fn new(db_filename: &str) -> Self {
+        Self {
+            path: format!("{}-wal", db_filename),
+            shm_path: format!("{}-shm", db_filename),
+            file: None,
+            shm: None,
+            slot: 0,
+            checkpoint_seq: 0,
+            salt: 0,
+            frames: HashMap::new(),
+            num_frames: 0,
+            max_frame: 0,
+            backfilled: 0,
+            db_num_pages: 0,
+            synced: true,
+            writer: false,
+        }
+    }
+
+    pub fn db_num_pages(&self) -> usize {
+        self.db_num_pages
+    }
+
+    /* Frames committed since the last checkpoint copied them back */
+    pub fn frames_to_checkpoint(&self) -> u32 {
+        self.max_frame.saturating_sub(self.backfilled)
+    }
+
+    /*
+    Open the log, starting a fresh one when there is none yet, and take
+    a read mark slot. Committed frames left by earlier sessions are indexed.
+    */
+    pub fn open(&mut self) -> DbResult<()> {
+        let shm = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.shm_path)?;
+        let mut slot = None;
+        for i in 0..WAL_READ_MARKS {
+            if read_mark_lock(&shm, i, libc::F_OFD_SETLK)? {
+                slot = Some(i);
+                break;
+            }
+        }
+        self.slot = match slot {
+            Some(slot) => slot,
+            None => return Err(DbError::Other("Too many connections to the write-ahead log".to_owned())),
+        };
+        self.shm = Some(shm);
+        self.write_read_mark(0, READ_MARK_IDLE)?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        self.file = Some(file);
+
+        if self.read_header()?.is_none() {
+            return self.reset();
+        }
+        self.refresh()?;
+        Ok(())
+    }
+
+    pub fn close(&mut self, remove: bool) -> DbResult<()> {
+        /* Dropping the file releases the read mark slot and the writer lock */
+        self.shm = None;
+        self.writer = false;
+        if self.file.take().is_some() && remove {
+            for path in [&self.path, &self.shm_path] {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        self.frames.clear();
+        self.num_frames = 0;
+        self.max_frame = 0;
+        self.backfilled = 0;
+        Ok(())
+    }
+
+    fn shm(&self) -> DbResult<&File> {
+        self.shm
+            .as_ref()
+            .ok_or_else(|| DbError::Other("Write-ahead log is not open".to_owned()))
+    }
+
+    fn write_read_mark(&self, salt: u32, frame: u32) -> DbResult<()> {
+        let shm = self.shm()?;
+        let mut mark = [0u8; READ_MARK_SIZE];
+        mark[READ_MARK_SALT_OFFSET..READ_MARK_FRAME_OFFSET].copy_from_slice(&salt.to_le_bytes());
+        mark[READ_MARK_FRAME_OFFSET..].copy_from_slice(&frame.to_le_bytes());
+        shm.write_all_at(&mark, (self.slot * READ_MARK_SIZE) as u64)?;
+        Ok(())
+    }
+
+    /*
+    Take a snapshot for a statement or a transaction: pick up what other
+    connections committed, as refresh does, and mark the snapshot so no
+    checkpoint copies newer frames into the database file under it. The
+    mark blocks every checkpoint while the log is being read.
+    */
+    pub fn begin_snapshot(&mut self) -> DbResult<Option<Vec<usize>>> {
+        self.write_read_mark(0, 0)?;
+        let changed_pages = self.refresh()?;
+        self.write_read_mark(self.salt, self.max_frame)?;
+        Ok(changed_pages)
+    }
+
+    pub fn end_snapshot(&mut self) -> DbResult<()> {
+        self.write_read_mark(0, READ_MARK_IDLE)
+    }
+
+    /*
+    Last frame a checkpoint may copy back, the oldest snapshot marked by
+    another connection, and whether any other connection holds one. A
+    snapshot taken before the log was restarted has none of its frames.
+    */
+    fn checkpoint_limit(&self) -> DbResult<(u32, bool)> {
+        let shm = self.shm()?;
+        let mut limit = self.max_frame;
+        let mut readers = false;
+        for slot in (0..WAL_READ_MARKS).filter(|&slot| slot != self.slot) {
+            if read_mark_lock(shm, slot, libc::F_OFD_GETLK)? {
+                continue;
+            }
+            let mut mark = [0u8; READ_MARK_SIZE];
+            match shm.read_exact_at(&mut mark, (slot * READ_MARK_SIZE) as u64) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => continue,
+                Err(e) => return Err(e.into()),
+                Ok(()) => {}
+            }
+            let frame = read_u32(&mark, READ_MARK_FRAME_OFFSET);
+            if frame == READ_MARK_IDLE {
+                continue;
+            }
+            readers = true;
+            limit = match read_u32(&mark, READ_MARK_SALT_OFFSET) == self.salt {
+                true => limit.min(frame),
+                false => 0,
+            };
+        }
+        Ok((limit, readers))
+    }
+
+    /*
+    Take the writer lock before the first change of a transaction. Fails
+    when another connection writes, or committed since the snapshot was
+    taken. The snapshot is kept then, the log does not restart under it,
+    and the next one picks up the new commits.
+    */
+    pub fn begin_write(&mut self) -> DbResult<()> {
+        if self.writer {
+            return Ok(());
+        }
+        if !shm_lock(self.shm()?, WRITER_LOCK_OFFSET, 1, libc::F_WRLCK, libc::F_OFD_SETLK)? {
+            return Err(DbError::Other("Database is locked by another writer".to_owned()));
+        }
+        self.writer = true;
+
+        let (max_frame, db_num_pages) = (self.max_frame, self.db_num_pages);
+        self.refresh()?;
+        if self.max_frame != max_frame {
+            for frames in self.frames.values_mut() {
+                frames.retain(|&frame| frame <= max_frame);
+            }
+            self.frames.retain(|_, frames| !frames.is_empty());
+            self.num_frames = max_frame;
+            self.max_frame = max_frame;
+            self.db_num_pages = db_num_pages;
+            self.end_write()?;
+            return Err(DbError::Other("Database was changed by another connection since the snapshot".to_owned()));
+        }
+        Ok(())
+    }
+
+    /* Let other connections write, once the frames of the transaction are synced or forgotten */
+    pub fn end_write(&mut self) -> DbResult<()> {
+        if self.writer {
+            shm_lock(self.shm()?, WRITER_LOCK_OFFSET, 1, libc::F_UNLCK, libc::F_OFD_SETLK)?;
+            self.writer = false;
+        }
+        Ok(())
+    }
+
+    /* Checkpoint sequence and salt, None when the header is missing or torn */
+    fn read_header(&mut self) -> DbResult<Option<(u32, u32)>> {
+        let file = self.file_mut()?;
+        let mut header = [0u8; WAL_HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        match file.read_exact(&mut header) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+            Ok(()) => {}
+        }
+
+        if &header[..WAL_MAGIC_SIZE] != WAL_MAGIC
+            || read_u32(&header, WAL_PAGE_SIZE_OFFSET) != PAGE_SIZE as u32
+        {
+            return Ok(None);
+        }
+        Ok(Some((
+            read_u32(&header, WAL_CHECKPOINT_SEQ_OFFSET),
+            read_u32(&header, WAL_SALT_OFFSET),
+        )))
+    }
+
+    /*
+    Pick up transactions committed to the log by other connections.
+    Returns the pages they changed, or None when the log was restarted
+    and every cached page may be stale.
+    */
+    pub fn refresh(&mut self) -> DbResult<Option<Vec<usize>>> {
+        let restarted = match self.read_header()? {
+            Some((checkpoint_seq, salt)) => {
+                let restarted = salt != self.salt || checkpoint_seq != self.checkpoint_seq;
+                self.checkpoint_seq = checkpoint_seq;
+                self.salt = salt;
+                restarted
+            }
+            None => true,
+        };
+        if restarted {
+            self.frames.clear();
+            self.num_frames = 0;
+            self.max_frame = 0;
+            self.backfilled = 0;
+            self.db_num_pages = 0;
+        }
+
+        /* Only the frames past the last known commit can be new */
+        let mut contents = Vec::new();
+        let first_new_frame = self.max_frame + 1;
+        let file = self.file_mut()?;
+        let tail_start = WAL_HEADER_SIZE + (first_new_frame as usize - 1) * WAL_FRAME_SIZE;
+        file.seek(SeekFrom::Start(tail_start as u64))?;
+        file.read_to_end(&mut contents)?;
+
+        let mut changed_pages = Vec::new();
+        let mut pending: Vec<(usize, u32)> = Vec::new();
+        for (i, buf) in contents.chunks_exact(WAL_FRAME_SIZE).enumerate() {
+            let frame = first_new_frame + i as u32;
+            let page_num = read_u32(buf, WAL_FRAME_PAGE_NUM_OFFSET);
+            let db_size = read_u32(buf, WAL_FRAME_DB_SIZE_OFFSET);
+            let data = &buf[WAL_FRAME_HEADER_SIZE..];
+            if read_u32(buf, WAL_FRAME_SALT_OFFSET) != self.salt
+                || read_u32(buf, WAL_FRAME_CHECKSUM_OFFSET)
+                    != frame_checksum(page_num, db_size, self.salt, data)
+            {
+                break;
+            }
+
+            pending.push((page_num as usize, frame));
+            if db_size != 0 {
+                /* Commit frame, the whole transaction becomes visible */
+                for (page_num, frame) in pending.drain(..) {
+                    self.frames.entry(page_num).or_default().push(frame);
+                    changed_pages.push(page_num);
+                }
+                self.max_frame = frame;
+                self.db_num_pages = db_size as usize;
+            }
+        }
+        self.num_frames = self.max_frame;
+
+        if restarted {
+            return Ok(None);
+        }
+        Ok(Some(changed_pages))
+    }
+
+    /* Newest frame holding the page, if the log has one */
+    pub fn find_frame(&self, page_num: usize) -> Option<u32> {
+        self.frames.get(&page_num).and_then(|frames| frames.last().copied())
+    }
+
+    /*
+    Read a page image from the log. Returns false when the frame is gone
+    because another connection restarted the log, the database file then
+    holds the page.
+    */
+    pub fn read_frame(&mut self, frame: u32, page: *mut c_void) -> DbResult<bool> {
+        let salt = self.salt;
+        let file = self.file_mut()?;
+        let mut buf = vec![0u8; WAL_FRAME_SIZE];
+        let offset = WAL_HEADER_SIZE + (frame as usize - 1) * WAL_FRAME_SIZE;
+        file.seek(SeekFrom::Start(offset as u64))?;
+        match file.read_exact(&mut buf) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+            Ok(()) => {}
+        }
+
+        let page_num = read_u32(&buf, WAL_FRAME_PAGE_NUM_OFFSET);
+        let db_size = read_u32(&buf, WAL_FRAME_DB_SIZE_OFFSET);
+        let data = &buf[WAL_FRAME_HEADER_SIZE..];
+        if read_u32(&buf, WAL_FRAME_SALT_OFFSET) != salt
+            || read_u32(&buf, WAL_FRAME_CHECKSUM_OFFSET) != frame_checksum(page_num, db_size, salt, data)
+        {
+            return Ok(false);
+        }
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), page as *mut u8, PAGE_SIZE) };
+        Ok(true)
+    }
+
+    /*
+    Append a page image. A frame written with the database size
+    is a commit frame and ends the transaction.
+    */
+    pub fn append_frame(&mut self, page_num: usize, page: *const c_void,
+        commit_db_num_pages: Option<usize>) -> DbResult<()> {
+        let salt = self.salt;
+        let frame = self.num_frames + 1;
+        let db_size = commit_db_num_pages.unwrap_or(0) as u32;
+        let data = unsafe { std::slice::from_raw_parts(page as *const u8, PAGE_SIZE) };
+
+        let mut buf = Vec::with_capacity(WAL_FRAME_SIZE);
+        buf.extend_from_slice(&(page_num as u32).to_le_bytes());
+        buf.extend_from_slice(&db_size.to_le_bytes());
+        buf.extend_from_slice(&salt.to_le_bytes());
+        buf.extend_from_slice(&frame_checksum(page_num as u32, db_size, salt, data).to_le_bytes());
+        buf.extend_from_slice(data);
+
+        let file = self.file_mut()?;
+        let offset = WAL_HEADER_SIZE + (frame as usize - 1) * WAL_FRAME_SIZE;
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.write_all(&buf)?;
+
+        self.frames.entry(page_num).or_default().push(frame);
+        self.num_frames = frame;
+        self.synced = false;
+        if let Some(db_num_pages) = commit_db_num_pages {
+            self.max_frame = frame;
+            self.db_num_pages = db_num_pages;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> DbResult<()> {
+        if let (false, Some(file)) = (self.synced, self.file.as_ref()) {
+            file.sync_all()?;
+            self.synced = true;
+        }
+        Ok(())
+    }
+
+    /*
+    Forget the frames of a transaction that will not commit.
+    They stay in the file but are overwritten by the next transaction.
+    */
+    pub fn rollback(&mut self) {
+        let max_frame = self.max_frame;
+        for frames in self.frames.values_mut() {
+            frames.retain(|&frame| frame <= max_frame);
+        }
+        self.frames.retain(|_, frames| !frames.is_empty());
+        self.num_frames = max_frame;
+    }
+
+    /* Forget uncommitted frames of pages past the end of the database */
+    pub fn forget_pages(&mut self, db_num_pages: usize) {
+        let max_frame = self.max_frame;
+        for (&page_num, frames) in self.frames.iter_mut() {
+            if page_num >= db_num_pages {
+                frames.retain(|&frame| frame <= max_frame);
+            }
+        }
+        self.frames.retain(|_, frames| !frames.is_empty());
+    }
+
+    /*
+    What a checkpoint copies back: the newest frame up to the limit of
+    every page, unless an earlier checkpoint copied that frame already.
+    Returns the limit too, and whether other connections still read.
+    */
+    pub fn checkpoint_frames(&self) -> DbResult<(PageFrames, u32, bool)> {
+        let (limit, readers) = self.checkpoint_limit()?;
+        let mut frames: PageFrames = self
+            .frames
+            .iter()
+            .filter_map(|(&page_num, frames)| {
+                frames.iter().rev().find(|&&frame| frame <= limit).map(|&frame| (page_num, frame))
+            })
+            .filter(|&(_, frame)| frame > self.backfilled)
+            .collect();
+        frames.sort_unstable();
+        Ok((frames, limit, readers))
+    }
+
+    pub fn set_backfilled(&mut self, frame: u32) {
+        self.backfilled = self.backfilled.max(frame);
+    }
+
+    /*
+    Restart the log after a checkpoint. The new salt makes any frame
+    still in the file invalid, so no other connection may be reading it.
+    */
+    pub fn reset(&mut self) -> DbResult<()> {
+        self.checkpoint_seq = self.checkpoint_seq.wrapping_add(1);
+        self.salt = self.salt.wrapping_mul(1_103_515_245).wrapping_add(12_345) ^ self.checkpoint_seq;
+
+        let mut header = Vec::with_capacity(WAL_HEADER_SIZE);
+        header.extend_from_slice(WAL_MAGIC);
+        header.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&self.checkpoint_seq.to_le_bytes());
+        header.extend_from_slice(&self.salt.to_le_bytes());
+
+        let file = self.file_mut()?;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.sync_all()?;
+
+        self.frames.clear();
+        self.num_frames = 0;
+        self.max_frame = 0;
+        self.backfilled = 0;
+        self.synced = true;
+        Ok(())
+    }
+
+    fn file_mut(&mut self) -> DbResult<&mut File> {
+        self.file
+            .as_mut()
+            .ok_or_else(|| DbError::Other("Write-ahead log is not open".to_owned()))
+    }
+}
@@ -4,14 +4,12 @@ use std::rc::Rc;
 mod cli;
 mod db;
 mod service;
-#[macro_use]
-mod util;
 
 use cli::header::print_sqlite_logo;
 use cli::run_loop;
+use crate::db::pager::{JournalMode, DEFAULT_CACHE_SIZE};
 use crate::db::table::Table;
-use crate::service::Row;
-use crate::util::zascii;
+use crate::service::sorter::DEFAULT_SORT_MEMORY;
 
 use clap::Parser;
 
@@ -20,6 +18,18 @@ use clap::Parser;
 struct Cli {
     #[clap(short, long, parse(from_os_str), value_name = "FILE")]
     db: Option<PathBuf>,
+
+    /// Number of pages kept in the page cache
+    #[clap(short, long, value_name = "PAGES", default_value_t = DEFAULT_CACHE_SIZE)]
+    cache_size: usize,
+
+    /// Bytes of rows an ORDER BY or a GROUP BY keeps in memory before spilling to temporary files
+    #[clap(short, long, value_name = "BYTES", default_value_t = DEFAULT_SORT_MEMORY)]
+    sort_memory: usize,
+
+    /// Switch the database to the given journal mode
+    #[clap(short, long, value_name = "MODE", possible_values = ["rollback", "wal"])]
+    journal_mode: Option<String>,
 }
 
 fn main() {
@@ -31,32 +41,15 @@ fn main() {
         println!("DB file: {:?}\n", db_name);
     }
 
-    let table = Rc::new(Table::db_open(db_name)
-    .map_err(|e| println!("Unexpected error: {:?}", e)).unwrap());
-
-    let mut r = Row::default();
-    r.id = 3;
-    r.set_email("aaaaa.com");
-    r.set_username("dddddxzzzzm");
-    println!("email: {}", zascii(&r.email));
-    println!("username: {}", zascii(&r.username));
-    println!("Row: {}", r);
+    let journal_mode = cli.journal_mode.as_deref().map(|mode| match mode {
+        "wal" => JournalMode::JournalWal,
+        _ => JournalMode::JournalRollback,
+    });
 
-    // let page: *mut c_void = unsafe {
-    //     // Cache miss. Allocate memory and load from file.
-    //     libc::malloc(1000 as usize) as *mut c_void
-    // };
-
-    // r.serialize_row(page);
-
-    // let mut r2 = Row::default();
-    // r2.deserialize_row(page as *const c_void);
-
-    // println!("r2: {}", r2);
-
-    // unsafe{libc::free(page);}
+    let table = Rc::new(Table::db_open(db_name, cli.cache_size, journal_mode)
+    .map_err(|e| println!("Unexpected error: {:?}", e)).unwrap());
 
     print_sqlite_logo();
 
-    run_loop(table);
+    run_loop(table, cli.sort_memory);
 }
@@ -0,0 +1,567 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter};
+use std::mem::size_of;
+
+use crate::db::error::DbResult;
+use crate::service::ast::{BinaryOp, Expr, UnaryOp};
+use crate::service::expression::{bind_expr, compare_values, numeric_value, value_text, BoundExpr, TableScope};
+use crate::service::prepare_statement::PrepareResult;
+use crate::service::record::serialize_record;
+use crate::service::sorter::{read_entry, rewind, temp_file, write_entry};
+use crate::service::Value;
+
+const NUM_PARTITIONS: usize = 16; // files the groups that do not fit in memory spill to
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    AggregateCount,
+    AggregateSum,
+    AggregateAvg,
+    AggregateMin,
+    AggregateMax,
+    AggregateGroupConcat,
+    AggregateBare, // a column outside of any aggregate, its value in the last row of the group
+}
+
+impl AggregateFunction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "count" => Some(AggregateFunction::AggregateCount),
+            "sum" => Some(AggregateFunction::AggregateSum),
+            "avg" => Some(AggregateFunction::AggregateAvg),
+            "min" => Some(AggregateFunction::AggregateMin),
+            "max" => Some(AggregateFunction::AggregateMax),
+            "group_concat" => Some(AggregateFunction::AggregateGroupConcat),
+            _ => None,
+        }
+    }
+}
+
+/* An aggregate in a select, its arguments are expressions on the table rows */
+#[derive(Debug, Clone)]
+pub struct AggregateCall {
+    pub function: AggregateFunction,
+    pub args: Vec<BoundExpr>, // empty for count(*)
+}
+
+/*
+Aggregation of a select. Every group turns into a group row, the values
+of the group by expressions followed by the result of each call. The
+having clause, the result columns and the order by of the select are
+expressions on group rows.
+*/
+#[derive(Debug, Clone)]
+pub struct AggregatePlan {
+    pub group_by: Vec<BoundExpr>,
+    pub calls: Vec<AggregateCall>,
+    pub having: Option<BoundExpr>,
+}
+
+impl AggregatePlan {
+    /* Whether every call is count(*), which needs no row read */
+    pub fn counts_only(&self) -> bool {
+        self.group_by.is_empty()
+            && self
+                .calls
+                .iter()
+                .all(|call| call.function == AggregateFunction::AggregateCount && call.args.is_empty())
+    }
+}
+
+pub fn contains_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::ExprFunction { name, .. } if AggregateFunction::from_name(name).is_some() => true,
+        Expr::ExprFunction { args, .. } => args.iter().any(contains_aggregate),
+        Expr::ExprLiteral(_) | Expr::ExprColumn { .. } => false,
+        Expr::ExprUnary(_, expr) | Expr::ExprCollate(expr, _) => contains_aggregate(expr),
+        Expr::ExprBinary(left, _, right) => contains_aggregate(left) || contains_aggregate(right),
+        Expr::ExprBetween { expr, low, high, .. } => {
+            contains_aggregate(expr) || contains_aggregate(low) || contains_aggregate(high)
+        }
+    }
+}
+
+/*
+Binds the expressions of an aggregate select to group rows. A group by
+expression becomes its column of the group row, an aggregate or a bare
+column becomes a call. Calls written the same way share a result.
+*/
+pub struct AggregateBinder<'a> {
+    scope: &'a [TableScope<'a>],
+    group_by: Vec<Expr>,
+    calls: Vec<(Expr, AggregateCall)>,
+}
+
+impl<'a> AggregateBinder<'a> {
+    pub fn new(scope: &'a [TableScope<'a>], group_by: Vec<Expr>) -> Self {
+        Self {
+            scope,
+            group_by,
+            calls: Vec::new(),
+        }
+    }
+
+    pub fn bind(&mut self, expr: &Expr) -> Result<BoundExpr, PrepareResult> {
+        if let Some(index) = self.group_by.iter().position(|group| group == expr) {
+            return Ok(BoundExpr::BoundColumn(index));
+        }
+
+        match expr {
+            Expr::ExprLiteral(_) => bind_expr(self.scope, expr),
+            Expr::ExprColumn { .. } => {
+                let arg = bind_expr(self.scope, expr)?;
+                Ok(self.add_call(expr, AggregateFunction::AggregateBare, vec![arg]))
+            }
+            Expr::ExprFunction { name, args, star } => {
+                let function = match AggregateFunction::from_name(name) {
+                    Some(function) => function,
+                    None => return Err(PrepareResult::PrepareUnsupported("Functions")),
+                };
+                let num_args = match function {
+                    AggregateFunction::AggregateCount if *star => 0..=0,
+                    AggregateFunction::AggregateGroupConcat => 1..=2,
+                    _ => 1..=1,
+                };
+                if *star != (function == AggregateFunction::AggregateCount && args.is_empty())
+                    || !num_args.contains(&args.len())
+                {
+                    return Err(PrepareResult::PrepareInvalid(format!(
+                        "wrong number of arguments to function {}()",
+                        name
+                    )));
+                }
+
+                let mut bound_args = Vec::new();
+                for arg in args {
+                    bound_args.push(bind_expr(self.scope, arg)?);
+                }
+                Ok(self.add_call(expr, function, bound_args))
+            }
+            Expr::ExprUnary(op, operand) => Ok(BoundExpr::BoundUnary(*op, Box::new(self.bind(operand)?))),
+            Expr::ExprBinary(left, op, right) => Ok(BoundExpr::BoundBinary(
+                Box::new(self.bind(left)?),
+                *op,
+                Box::new(self.bind(right)?),
+            )),
+            Expr::ExprBetween {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                let low = Expr::ExprBinary(expr.clone(), BinaryOp::BinaryGtEq, low.clone());
+                let high = Expr::ExprBinary(expr.clone(), BinaryOp::BinaryLtEq, high.clone());
+                let between = BoundExpr::BoundBinary(
+                    Box::new(self.bind(&low)?),
+                    BinaryOp::BinaryAnd,
+                    Box::new(self.bind(&high)?),
+                );
+                match negated {
+                    true => Ok(BoundExpr::BoundUnary(UnaryOp::UnaryNot, Box::new(between))),
+                    false => Ok(between),
+                }
+            }
+            Expr::ExprCollate(..) => Err(PrepareResult::PrepareUnsupported("COLLATE")),
+        }
+    }
+
+    fn add_call(&mut self, expr: &Expr, function: AggregateFunction, args: Vec<BoundExpr>) -> BoundExpr {
+        let index = match self.calls.iter().position(|(written, _)| written == expr) {
+            Some(index) => index,
+            None => {
+                self.calls.push((expr.clone(), AggregateCall { function, args }));
+                self.calls.len() - 1
+            }
+        };
+        BoundExpr::BoundColumn(self.group_by.len() + index)
+    }
+
+    pub fn finish(self, having: Option<BoundExpr>) -> Result<AggregatePlan, PrepareResult> {
+        let mut group_by = Vec::new();
+        for expr in &self.group_by {
+            group_by.push(bind_expr(self.scope, expr)?);
+        }
+        Ok(AggregatePlan {
+            group_by,
+            calls: self.calls.into_iter().map(|(_, call)| call).collect(),
+            having,
+        })
+    }
+}
+
+/* Running result of one call over the rows of a group */
+#[derive(Debug, Clone)]
+enum AggregateState {
+    StateCount(i64),
+    StateSum {
+        count: i64,
+        integer: Option<i64>, // exact sum while every value is an integer and it does not overflow
+        real: f64,
+    },
+    StateValue(Value), // min, max and bare columns
+    StateConcat(Option<String>),
+}
+
+impl AggregateState {
+    fn new(function: AggregateFunction) -> Self {
+        match function {
+            AggregateFunction::AggregateCount => AggregateState::StateCount(0),
+            AggregateFunction::AggregateSum | AggregateFunction::AggregateAvg => AggregateState::StateSum {
+                count: 0,
+                integer: Some(0),
+                real: 0.0,
+            },
+            AggregateFunction::AggregateMin | AggregateFunction::AggregateMax | AggregateFunction::AggregateBare => {
+                AggregateState::StateValue(Value::ValueNull)
+            }
+            AggregateFunction::AggregateGroupConcat => AggregateState::StateConcat(None),
+        }
+    }
+
+    /* Add the arguments of a row, returns the bytes the state grew by */
+    fn update(&mut self, function: AggregateFunction, args: &[Value]) -> usize {
+        if function == AggregateFunction::AggregateBare {
+            if let AggregateState::StateValue(value) = self {
+                *value = args[0].clone();
+            }
+            return 0;
+        }
+        /* Every other aggregate skips NULL, count(*) has no argument */
+        if args.first() == Some(&Value::ValueNull) {
+            return 0;
+        }
+
+        match self {
+            AggregateState::StateCount(count) => *count += 1,
+            AggregateState::StateSum { count, integer, real } => {
+                *count += 1;
+                match numeric_value(&args[0]) {
+                    Value::ValueInteger(value) => {
+                        *integer = integer.and_then(|sum| sum.checked_add(value));
+                        *real += value as f64;
+                    }
+                    Value::ValueReal(value) => {
+                        *integer = None;
+                        *real += value;
+                    }
+                    _ => {}
+                }
+            }
+            AggregateState::StateValue(value) => {
+                let wanted = match function {
+                    AggregateFunction::AggregateMin => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                if *value == Value::ValueNull || compare_values(&args[0], value) == wanted {
+                    let grown = value_size(&args[0]).saturating_sub(value_size(value));
+                    *value = args[0].clone();
+                    return grown;
+                }
+            }
+            AggregateState::StateConcat(text) => {
+                let value = value_text(&args[0]);
+                let grown = match text {
+                    Some(text) => {
+                        let separator = match args.get(1) {
+                            Some(Value::ValueNull) => String::new(),
+                            Some(separator) => value_text(separator),
+                            None => ",".to_owned(),
+                        };
+                        text.push_str(&separator);
+                        text.push_str(&value);
+                        separator.len() + value.len()
+                    }
+                    None => {
+                        *text = Some(value);
+                        text.as_ref().map_or(0, |text| text.len())
+                    }
+                };
+                return grown;
+            }
+        }
+        0
+    }
+
+    fn result(&self, function: AggregateFunction) -> Value {
+        match self {
+            AggregateState::StateCount(count) => Value::ValueInteger(*count),
+            AggregateState::StateSum { count: 0, .. } => Value::ValueNull,
+            AggregateState::StateSum { count, integer, real } => match function {
+                AggregateFunction::AggregateAvg => Value::ValueReal(real / *count as f64),
+                _ => match integer {
+                    Some(sum) => Value::ValueInteger(*sum),
+                    None => Value::ValueReal(*real),
+                },
+            },
+            AggregateState::StateValue(value) => value.clone(),
+            AggregateState::StateConcat(Some(text)) => Value::ValueText(text.clone()),
+            AggregateState::StateConcat(None) => Value::ValueNull,
+        }
+    }
+}
+
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::ValueText(text) => text.len(),
+        Value::ValueBlob(blob) => blob.len(),
+        _ => 0,
+    }
+}
+
+struct Group {
+    keys: Vec<Value>, // values of the group by expressions in the first row of the group
+    states: Vec<AggregateState>,
+}
+
+/*
+Hash aggregation. Groups are kept in a hash table on their key values
+up to memory_limit bytes. Once it is full, rows of groups that are not
+in it are written to one of NUM_PARTITIONS temporary files by the hash
+of their key, and each file is aggregated on its own after the table
+is done. A file that is still too big partitions again one level down,
+with a different hash.
+
+A row is fed as its group by values followed by the arguments of every
+call, which is also how spilled rows are written.
+*/
+pub struct Aggregator {
+    functions: Vec<AggregateFunction>,
+    num_args: Vec<usize>, // per call
+    num_keys: usize,
+    memory_limit: usize,
+    memory_used: usize,
+    level: u64, // of partitioning, salts the hash
+    groups: HashMap<Vec<u8>, Group>,
+    partitions: Vec<BufWriter<File>>,
+}
+
+impl Aggregator {
+    pub fn new(plan: &AggregatePlan, memory_limit: usize) -> Self {
+        Self {
+            functions: plan.calls.iter().map(|call| call.function).collect(),
+            num_args: plan.calls.iter().map(|call| call.args.len()).collect(),
+            num_keys: plan.group_by.len(),
+            memory_limit,
+            memory_used: 0,
+            level: 0,
+            groups: HashMap::new(),
+            partitions: Vec::new(),
+        }
+    }
+
+    /* An empty aggregator for the same plan */
+    fn with_level(&self, level: u64) -> Self {
+        Self {
+            functions: self.functions.clone(),
+            num_args: self.num_args.clone(),
+            num_keys: self.num_keys,
+            memory_limit: self.memory_limit,
+            memory_used: 0,
+            level,
+            groups: HashMap::new(),
+            partitions: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, row: Vec<Value>) -> DbResult<()> {
+        let key = group_key(&row[..self.num_keys]);
+        if let Some(group) = self.groups.get_mut(&key) {
+            self.memory_used += update_states(&mut group.states, &self.functions, &self.num_args, &row[self.num_keys..]);
+            return Ok(());
+        }
+
+        /* A query without group by has one group, it never spills */
+        if self.memory_used < self.memory_limit || self.num_keys == 0 {
+            let mut states: Vec<AggregateState> = self.functions.iter().map(|f| AggregateState::new(*f)).collect();
+            let grown = update_states(&mut states, &self.functions, &self.num_args, &row[self.num_keys..]);
+            self.memory_used += size_of::<Group>()
+                + 2 * key.len()
+                + states.len() * size_of::<AggregateState>()
+                + self.num_keys * size_of::<Value>()
+                + grown;
+            let keys = row[..self.num_keys].to_vec();
+            self.groups.insert(key, Group { keys, states });
+            return Ok(());
+        }
+
+        if self.partitions.is_empty() {
+            for _ in 0..NUM_PARTITIONS {
+                self.partitions.push(BufWriter::new(temp_file()?));
+            }
+        }
+        let mut hasher = DefaultHasher::new();
+        self.level.hash(&mut hasher);
+        key.hash(&mut hasher);
+        let partition = (hasher.finish() % NUM_PARTITIONS as u64) as usize;
+        write_entry(&mut self.partitions[partition], &row)
+    }
+
+    pub fn finish(mut self) -> DbResult<AggregateRows> {
+        if self.num_keys == 0 && self.groups.is_empty() {
+            /* Aggregates over no rows still give one row, count(*) is 0 */
+            let states = self.functions.iter().map(|f| AggregateState::new(*f)).collect();
+            self.groups.insert(Vec::new(), Group { keys: Vec::new(), states });
+        }
+
+        let mut partitions = Vec::new();
+        for writer in self.partitions.drain(..) {
+            partitions.push(rewind(writer)?);
+        }
+        let aggregator = self.with_level(self.level + 1);
+        Ok(AggregateRows {
+            functions: self.functions,
+            groups: self.groups.into_values().collect::<Vec<Group>>().into_iter(),
+            partitions,
+            aggregator,
+            nested: None,
+        })
+    }
+}
+
+fn update_states(states: &mut [AggregateState], functions: &[AggregateFunction], num_args: &[usize], args: &[Value]) -> usize {
+    let mut grown = 0;
+    let mut offset = 0;
+    for (i, state) in states.iter_mut().enumerate() {
+        grown += state.update(functions[i], &args[offset..offset + num_args[i]]);
+        offset += num_args[i];
+    }
+    grown
+}
+
+/*
+Key of a group in the hash table. Values that compare equal must get
+the same key, so reals with an integral value are keyed as integers.
+*/
+pub fn group_key(keys: &[Value]) -> Vec<u8> {
+    let keys: Vec<Value> = keys
+        .iter()
+        .map(|value| match value {
+            Value::ValueReal(real) if real.fract() == 0.0 && *real >= i64::MIN as f64 && *real < i64::MAX as f64 => {
+                Value::ValueInteger(*real as i64)
+            }
+            value => value.clone(),
+        })
+        .collect();
+    serialize_record(&keys)
+}
+
+/* Group rows of an aggregator, read with next_group */
+pub struct AggregateRows {
+    functions: Vec<AggregateFunction>,
+    groups: std::vec::IntoIter<Group>,
+    partitions: Vec<File>,
+    aggregator: Aggregator, // empty, one level down, for aggregating the partitions
+    nested: Option<Box<AggregateRows>>, // rows of the partition being read
+}
+
+impl AggregateRows {
+    pub fn next_group(&mut self) -> DbResult<Option<Vec<Value>>> {
+        loop {
+            if let Some(nested) = &mut self.nested {
+                if let Some(row) = nested.next_group()? {
+                    return Ok(Some(row));
+                }
+                self.nested = None;
+            }
+
+            if let Some(group) = self.groups.next() {
+                let mut row = group.keys;
+                for (i, state) in group.states.iter().enumerate() {
+                    row.push(state.result(self.functions[i]));
+                }
+                return Ok(Some(row));
+            }
+
+            let partition = match self.partitions.pop() {
+                Some(partition) => partition,
+                None => return Ok(None),
+            };
+            let mut aggregator = self.aggregator.with_level(self.aggregator.level);
+            let mut reader = BufReader::new(partition);
+            while let Some(row) = read_entry(&mut reader)? {
+                aggregator.add(row)?;
+            }
+            self.nested = Some(Box::new(aggregator.finish()?));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /* count(*), sum, min and max of the second column, grouped by the first */
+    fn plan() -> AggregatePlan {
+        let call = |function, num_args| AggregateCall {
+            function,
+            args: vec![BoundExpr::BoundColumn(1); num_args],
+        };
+        AggregatePlan {
+            group_by: vec![BoundExpr::BoundColumn(0)],
+            calls: vec![
+                call(AggregateFunction::AggregateCount, 0),
+                call(AggregateFunction::AggregateSum, 1),
+                call(AggregateFunction::AggregateMin, 1),
+                call(AggregateFunction::AggregateMax, 1),
+            ],
+            having: None,
+        }
+    }
+
+    fn integer(value: &Value) -> i64 {
+        match value {
+            Value::ValueInteger(value) => *value,
+            Value::ValueReal(value) => *value as i64,
+            value => panic!("{:?} is not a number", value),
+        }
+    }
+
+    /* Groups of 3000 keys, some given as reals, which must land in the group of the integer */
+    fn aggregate(memory_limit: usize) {
+        let mut aggregator = Aggregator::new(&plan(), memory_limit);
+        let mut expected: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+        for i in 0..20_000_i64 {
+            let key = (i * 7919) % 3_000;
+            let value = (i * 31) % 1_000 - 500;
+            let key_value = if i % 3 == 0 { Value::ValueReal(key as f64) } else { Value::ValueInteger(key) };
+            let value = Value::ValueInteger(value);
+            aggregator.add(vec![key_value, value.clone(), value.clone(), value.clone()]).unwrap();
+
+            let value = integer(&value);
+            let group = expected.entry(key).or_insert_with(|| vec![0, 0, value, value]);
+            group[0] += 1;
+            group[1] += value;
+            group[2] = group[2].min(value);
+            group[3] = group[3].max(value);
+        }
+
+        let mut groups = BTreeMap::new();
+        let mut rows = aggregator.finish().unwrap();
+        while let Some(row) = rows.next_group().unwrap() {
+            let row: Vec<i64> = row.iter().map(integer).collect();
+            assert!(groups.insert(row[0], row[1..].to_vec()).is_none(), "group {} comes twice", row[0]);
+        }
+        assert_eq!(groups, expected);
+    }
+
+    #[test]
+    fn groups_fit_in_memory() {
+        aggregate(usize::MAX);
+    }
+
+    #[test]
+    fn groups_spill_to_partitions() {
+        aggregate(16 * 1024);
+    }
+
+    /* Every partition overflows again, down several levels */
+    #[test]
+    fn partitions_spill_again() {
+        aggregate(1);
+    }
+}
@@ -0,0 +1,284 @@
+/*
+Syntax tree built by the parser. It only describes what was written,
+names are resolved against the schema when a statement is prepared.
+*/
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    StmtSelect(Box<SelectStmt>), // boxed, a select is far larger than the other statements
+    StmtInsert(InsertStmt),
+    StmtUpdate(UpdateStmt),
+    StmtDelete(DeleteStmt),
+    StmtCreateTable(CreateTableStmt),
+    StmtCreateIndex(CreateIndexStmt),
+    StmtBegin,
+    StmtCommit,
+    StmtRollback,
+    StmtSavepoint(String),
+    StmtRelease(String),
+    StmtRollbackTo(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectStmt {
+    pub core: SelectCore,
+    pub order_by: Vec<OrderingTerm>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectCore {
+    pub columns: Vec<ResultColumn>,
+    pub from: Option<FromClause>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultColumn {
+    ResultStar,
+    ResultTableStar(String),
+    ResultExpr(Expr, Option<String>), // expression and its alias
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FromClause {
+    pub table: TableRef,
+    pub joins: Vec<Join>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    pub name: String,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: TableRef,
+    pub constraint: JoinConstraint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKind {
+    JoinInner,
+    JoinLeft,
+    JoinCross,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinConstraint {
+    JoinNone,
+    JoinOn(Expr),
+    JoinUsing(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    pub desc: bool,
+}
+
+/*
+`insert into t (a, b) values (1, 2), (3, 4)`. The shorthand
+`insert 1 name email` of the users table leaves table and columns out.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct InsertStmt {
+    pub table: Option<String>,
+    pub columns: Option<Vec<String>>,
+    pub rows: Vec<Vec<Expr>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateStmt {
+    pub table: String,
+    pub assignments: Vec<(String, Expr)>,
+    pub where_clause: Option<Expr>,
+}
+
+// the table may be left out, as in `delete where id = 1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteStmt {
+    pub table: Option<String>,
+    pub where_clause: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTableStmt {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    pub sql: String, // statement text, kept in the schema
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub type_name: Option<String>, // as written, e.g. "varchar(255)"
+    pub primary_key: bool,
+    pub not_null: bool,
+    pub unique: bool,
+    pub collation: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndexStmt {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<IndexedColumn>,
+    pub unique: bool,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedColumn {
+    pub name: String,
+    pub collation: Option<String>,
+    pub desc: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    LiteralNull,
+    LiteralInteger(i64),
+    LiteralFloat(f64),
+    LiteralString(String),
+    LiteralBlob(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    UnaryNeg,
+    UnaryPlus,
+    UnaryNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    BinaryOr,
+    BinaryAnd,
+    BinaryEq,
+    BinaryNotEq,
+    BinaryIs,
+    BinaryIsNot,
+    BinaryLt,
+    BinaryLtEq,
+    BinaryGt,
+    BinaryGtEq,
+    BinaryAdd,
+    BinarySub,
+    BinaryMul,
+    BinaryDiv,
+    BinaryMod,
+    BinaryConcat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    ExprLiteral(Literal),
+    ExprColumn {
+        table: Option<String>,
+        name: String,
+    },
+    ExprUnary(UnaryOp, Box<Expr>),
+    ExprBinary(Box<Expr>, BinaryOp, Box<Expr>),
+    ExprBetween {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    ExprFunction {
+        name: String,
+        args: Vec<Expr>,
+        star: bool, // count(*)
+    },
+    ExprCollate(Box<Expr>, String),
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::LiteralNull => write!(f, "NULL"),
+            Literal::LiteralInteger(value) => write!(f, "{}", value),
+            Literal::LiteralFloat(value) => write!(f, "{:?}", value),
+            Literal::LiteralString(text) => write!(f, "'{}'", text.replace('\'', "''")),
+            Literal::LiteralBlob(blob) => {
+                write!(f, "x'")?;
+                for byte in blob {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            }
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            BinaryOp::BinaryOr => "OR",
+            BinaryOp::BinaryAnd => "AND",
+            BinaryOp::BinaryEq => "=",
+            BinaryOp::BinaryNotEq => "<>",
+            BinaryOp::BinaryIs => "IS",
+            BinaryOp::BinaryIsNot => "IS NOT",
+            BinaryOp::BinaryLt => "<",
+            BinaryOp::BinaryLtEq => "<=",
+            BinaryOp::BinaryGt => ">",
+            BinaryOp::BinaryGtEq => ">=",
+            BinaryOp::BinaryAdd => "+",
+            BinaryOp::BinarySub => "-",
+            BinaryOp::BinaryMul => "*",
+            BinaryOp::BinaryDiv => "/",
+            BinaryOp::BinaryMod => "%",
+            BinaryOp::BinaryConcat => "||",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+/*
+SQL text of an expression, used to name result columns that have no
+alias. Operands that are themselves operations get parentheses.
+*/
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn operand(expr: &Expr) -> String {
+            match expr {
+                Expr::ExprBinary(..) | Expr::ExprBetween { .. } => format!("({})", expr),
+                _ => expr.to_string(),
+            }
+        }
+
+        match self {
+            Expr::ExprLiteral(literal) => write!(f, "{}", literal),
+            Expr::ExprColumn { table: Some(table), name } => write!(f, "{}.{}", table, name),
+            Expr::ExprColumn { table: None, name } => write!(f, "{}", name),
+            Expr::ExprUnary(UnaryOp::UnaryNeg, expr) => write!(f, "-{}", operand(expr)),
+            Expr::ExprUnary(UnaryOp::UnaryPlus, expr) => write!(f, "+{}", operand(expr)),
+            Expr::ExprUnary(UnaryOp::UnaryNot, expr) => write!(f, "NOT {}", operand(expr)),
+            Expr::ExprBinary(left, op, right) => write!(f, "{} {} {}", operand(left), op, operand(right)),
+            Expr::ExprBetween {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                let not = if *negated { "NOT " } else { "" };
+                write!(f, "{} {}BETWEEN {} AND {}", operand(expr), not, operand(low), operand(high))
+            }
+            Expr::ExprFunction { name, star: true, .. } => write!(f, "{}(*)", name),
+            Expr::ExprFunction { name, args, .. } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Expr::ExprCollate(expr, collation) => write!(f, "{} COLLATE {}", operand(expr), collation),
+        }
+    }
+}
@@ -1,66 +1,744 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::rc::Rc;
-use std::cell::RefCell;
 
-use libc::c_void;
 
 use super::{Statement, StatementType};
+use crate::db::catalog::{CatalogEntry, CatalogEntryType};
 use crate::db::table::Table;
 use crate::db::error::{DbError, DbResult};
-use crate::service::Row;
+use crate::service::{KeyRange, Row, Value};
+use crate::service::expression::{evaluate, is_true, BoundExpr};
+use crate::service::sorter::Sorter;
+use crate::service::aggregate::{group_key, AggregatePlan, Aggregator};
+use crate::service::ast::JoinKind;
+use crate::service::join::{JoinPlan, JoinStrategy};
+use crate::service::index::{delete_index_entry, insert_index_entry, IndexScan};
+use crate::service::schema::{float_to_integer, Schema};
 use crate::db::cursor::Cursor;
 use crate::db::tree::*;
 
 pub enum ExecuteResult {
     ExecuteSuccess,
     ExecuteDuplicateKey,
+    ExecuteTableFull,
+    ExecuteRowsAffected(usize),
+    ExecuteNestedTransaction,
+    ExecuteNoTransaction,
+    ExecuteNoSuchSavepoint(String),
+    ExecuteUniqueFailed(String), // the columns of the unique index, the statement is rolled back
+    ExecuteInvalid(String), // a computed value does not fit its column, the statement is rolled back
 }
 
-pub struct Executor {}
+/* Receives the result of a select, the column names come before any row */
+pub trait RowOutput {
+    fn columns(&mut self, names: &[String]);
+    fn row(&mut self, row: &Row);
+}
+
+/* Rows of a joined table by the key of their join columns */
+type HashTable = HashMap<Vec<u8>, Vec<Vec<Value>>>;
+
+fn passes_filter(stmt: &Statement, values: &[Value]) -> bool {
+    match &stmt.filter {
+        Some(filter) => is_true(&evaluate(filter, values)) == Some(true),
+        None => true,
+    }
+}
+
+fn passes_condition(join: &JoinPlan, values: &[Value]) -> bool {
+    match &join.condition {
+        Some(condition) => is_true(&evaluate(condition, values)) == Some(true),
+        None => true,
+    }
+}
+
+/* Key of join columns in a hash table, None when one is NULL as it matches nothing */
+fn hash_key(keys: &[BoundExpr], values: &[Value]) -> Option<Vec<u8>> {
+    let keys: Vec<Value> = keys.iter().map(|key| evaluate(key, values)).collect();
+    match keys.contains(&Value::ValueNull) {
+        true => None,
+        false => Some(group_key(&keys)),
+    }
+}
+
+/* Rowid a value names, None when it cannot be one */
+fn rowid_value(value: &Value) -> Option<i64> {
+    match value {
+        Value::ValueInteger(value) => Some(*value),
+        Value::ValueReal(value) => float_to_integer(*value),
+        _ => None,
+    }
+}
+
+pub struct Executor {
+    sort_memory: usize, // bytes an ORDER BY or a GROUP BY keeps in memory before spilling
+}
+
+/* Rows of a select on their way to the output, through the sort and the limit */
+struct SelectResult<'a> {
+    stmt: &'a Statement,
+    output: &'a mut dyn RowOutput,
+    sorter: Option<Sorter>,
+    rows_to_skip: u64,
+    rows_to_return: u64,
+}
+
+impl<'a> SelectResult<'a> {
+    /* Add a row to evaluate the result columns on, false once the limit is reached */
+    fn push(&mut self, values: &[Value]) -> DbResult<bool> {
+        let stmt = self.stmt;
+        match &mut self.sorter {
+            Some(sorter) => {
+                let keys = stmt.order_by.iter().map(|(expr, _)| evaluate(expr, values)).collect();
+                let row = stmt.projection.iter().map(|(_, expr)| evaluate(expr, values)).collect();
+                sorter.add(keys, row)?;
+            }
+            None if self.rows_to_skip > 0 => self.rows_to_skip -= 1,
+            None => {
+                let row = stmt.projection.iter().map(|(_, expr)| evaluate(expr, values)).collect();
+                self.output.row(&Row { values: row });
+                self.rows_to_return -= 1;
+            }
+        }
+        Ok(self.rows_to_return > 0)
+    }
+
+    fn push_group(&mut self, plan: &AggregatePlan, group: &[Value]) -> DbResult<bool> {
+        if let Some(having) = &plan.having {
+            if is_true(&evaluate(having, group)) != Some(true) {
+                return Ok(true);
+            }
+        }
+        self.push(group)
+    }
+
+    fn finish(mut self) -> DbResult<()> {
+        if let Some(sorter) = self.sorter.take() {
+            let mut sorted = sorter.finish()?;
+            while self.rows_to_return > 0 {
+                match sorted.next_row()? {
+                    Some(_) if self.rows_to_skip > 0 => self.rows_to_skip -= 1,
+                    Some(values) => {
+                        self.output.row(&Row { values });
+                        self.rows_to_return -= 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+        Ok(())
+    }
+}
 
 impl Executor {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(sort_memory: usize) -> Self {
+        Self { sort_memory }
     }
 
-    pub fn execute_insert(&self, stmt: &Statement,  table: Rc<Table>) -> DbResult<ExecuteResult>  {
-        let row_to_insert = &stmt.row_to_insert;
-        let key_to_insert: u32 = row_to_insert.id;
-        let cursor = Cursor::table_find(table.clone(), key_to_insert)?;
+    pub fn execute_create_table(&self, stmt: &Statement, table: Rc<Table>) -> DbResult<ExecuteResult> {
+        let schema = &stmt.schema;
+        let root_page_num = table.create_tree()?;
+        table.add_catalog_entry(CatalogEntry {
+            entry_type: CatalogEntryType::CatalogTable,
+            name: schema.name.clone(),
+            table_name: schema.name.clone(),
+            root_page_num,
+            sql: schema.sql.clone(),
+        })?;
+        Ok(ExecuteResult::ExecuteSuccess)
+    }
 
-        let node = table.pager.borrow_mut().get_page(cursor.page_num)?;
-        let num_cells = unsafe{*leaf_node_key(node, cursor.cell_num) as usize };
+    /* Create the index tree and add an entry for every row already in the table */
+    pub fn execute_create_index(&self, stmt: &Statement, table: Rc<Table>) -> DbResult<ExecuteResult> {
+        let schema = &stmt.schema;
+        let mut index = match &stmt.index {
+            Some(index) => index.clone(),
+            None => return Err(DbError::Other("Index plan is corrupt.".to_string())),
+        };
+        index.root_page_num = table.create_tree()?;
+        table.add_catalog_entry(CatalogEntry {
+            entry_type: CatalogEntryType::CatalogIndex,
+            name: index.name.clone(),
+            table_name: schema.name.clone(),
+            root_page_num: index.root_page_num,
+            sql: index.sql.clone(),
+        })?;
 
-        if cursor.cell_num < num_cells {
-            let key_at_index = unsafe {
-                *leaf_node_key(node, cursor.cell_num)
+        let mut unique = true;
+        self.scan_table(table.clone(), schema, KeyRange::default(), 0, |values| {
+            let rowid = rowid_value(&values[schema.columns.len()]).unwrap_or_default();
+            unique = insert_index_entry(&table, schema, &index, &values, rowid)?;
+            Ok(unique)
+        })?;
+        match unique {
+            true => Ok(ExecuteResult::ExecuteSuccess),
+            false => Ok(ExecuteResult::ExecuteUniqueFailed(index.column_names(schema))),
+        }
+    }
+
+    /* Largest key in the table, None when it is empty */
+    fn max_key(&self, table: Rc<Table>, root_page_num: usize) -> DbResult<Option<i64>> {
+        let mut pager = table.pager.borrow_mut();
+        let root = pager.get_page(root_page_num)?;
+        if let NodeType::NodeLeaf = get_node_type(root) {
+            if unsafe { *leaf_node_num_cells(root) } == 0 {
+                return Ok(None);
+            }
+        }
+        Ok(Some(key_rowid(&get_node_max_key(&mut pager, root)?)?))
+    }
+
+    /*
+    Check every key before inserting anything, so a statement with a
+    duplicate among its rows leaves the table untouched. Rows without
+    a key get one past the largest key so far, like the sqlite rowid.
+    Unique indexes are checked as their entries go in, a row breaking
+    one fails the statement and it is rolled back.
+    */
+    pub fn execute_insert(&self, stmt: &Statement,  table: Rc<Table>) -> DbResult<ExecuteResult>  {
+        let schema = &stmt.schema;
+        let mut max_key = self.max_key(table.clone(), schema.root_page_num)?;
+        let mut rows_to_insert: Vec<(i64, Row)> = Vec::new();
+        for row_to_insert in &stmt.rows_to_insert {
+            let mut row = row_to_insert.clone();
+            let key_to_insert: i64 = match schema.row_key(&row) {
+                Some(key) => key,
+                None => {
+                    let key = match max_key {
+                        Some(i64::MAX) => return Ok(ExecuteResult::ExecuteTableFull),
+                        Some(key) => key + 1,
+                        None => 1,
+                    };
+                    if let Some(column) = schema.key_column {
+                        row.values[column] = Value::ValueInteger(key);
+                    }
+                    key
+                }
             };
-            if key_at_index == key_to_insert {
+            if rows_to_insert.iter().any(|(key, _)| *key == key_to_insert) {
+                return Ok(ExecuteResult::ExecuteDuplicateKey);
+            }
+
+            let cursor = Cursor::table_find(table.clone(), stmt.schema.root_page_num, key_to_insert)?;
+            if cursor.cursor_matches(&rowid_key(key_to_insert))? {
                 return Ok(ExecuteResult::ExecuteDuplicateKey);
             }
+            max_key = max_key.max(Some(key_to_insert));
+            rows_to_insert.push((key_to_insert, row));
         }
 
-        cursor.leaf_node_insert( key_to_insert, Rc::new(RefCell::new(stmt.row_to_insert)))?;
+        for (key, row) in &rows_to_insert {
+            let cursor = Cursor::table_find(table.clone(), stmt.schema.root_page_num, *key)?;
+            cursor.leaf_node_insert(&rowid_key(*key), &row.serialize_row())?;
+            drop(cursor);
+            for index in &schema.indexes {
+                if !insert_index_entry(&table, schema, index, &row.values, *key)? {
+                    return Ok(ExecuteResult::ExecuteUniqueFailed(index.column_names(schema)));
+                }
+            }
+            table.pager.borrow_mut().shrink_cache()?;
+        }
 
         Ok(ExecuteResult::ExecuteSuccess)
     }
 
-    pub fn execute_select(&self, table: Rc<Table>) -> DbResult<ExecuteResult>  {
-        let mut cursor = Cursor::table_start(table.clone())?;
+    /*
+    Visit the rows of a table with a key in key_range, as their values
+    followed by the rowid, until visit returns false. The first skip
+    cells are passed without being read, the number actually passed is
+    returned.
+    */
+    fn scan_table(&self, table: Rc<Table>, schema: &Schema, key_range: KeyRange, skip: u64,
+        mut visit: impl FnMut(Vec<Value>) -> DbResult<bool>) -> DbResult<u64> {
+        if key_range.start > key_range.end {
+            return Ok(0);
+        }
+
+        let mut cursor = Cursor::table_seek(table.clone(), schema.root_page_num, key_range.start)?;
+        let skipped = cursor.cursor_skip(skip)?;
+        while !cursor.end_of_table {
+            let key = cursor.cursor_rowid()?;
+            if key > key_range.end {
+                break;
+            }
+
+            let mut values = Row::deserialize_row(schema, &cursor.cursor_value()?)?.values;
+            values.push(Value::ValueInteger(key));
+            if !visit(values)? {
+                break;
+            }
+            cursor.cursor_advance()?;
+        }
+        Ok(skipped)
+    }
 
-        let mut row = Row::default();
+    /*
+    Visit the rows an index scan finds, as scan_table does, in the order
+    of the index. Each entry leads to its row in the table tree.
+    */
+    fn scan_index(&self, table: Rc<Table>, schema: &Schema, scan: &IndexScan,
+        mut visit: impl FnMut(Vec<Value>) -> DbResult<bool>) -> DbResult<()> {
+        let index = &scan.index;
+        let mut cursor = Cursor::tree_seek(table.clone(), index.comparator(), index.root_page_num, &scan.start_key())?;
         while !cursor.end_of_table {
-            row.deserialize_row(cursor.cursor_value()? as *const c_void);
-            println!("{}", row);
+            let (entry, rowid) = index.entry_values(&cursor.cursor_key()?)?;
+            match scan.entry_position(&entry) {
+                Ordering::Less => {}
+                Ordering::Greater => break,
+                Ordering::Equal => {
+                    let row_cursor = Cursor::table_find(table.clone(), schema.root_page_num, rowid)?;
+                    if !row_cursor.cursor_matches(&rowid_key(rowid))? {
+                        return Err(DbError::Other(format!("Index {} is corrupt.", index.name)));
+                    }
+                    let mut values = Row::deserialize_row(schema, &row_cursor.cursor_value()?)?.values;
+                    values.push(Value::ValueInteger(rowid));
+                    drop(row_cursor);
+                    if !visit(values)? {
+                        break;
+                    }
+                }
+            }
             cursor.cursor_advance()?;
         }
+        Ok(())
+    }
+
+    /* Rows in a table, from the cell counts of its leaves */
+    fn count_rows(&self, table: Rc<Table>, schema: &Schema) -> DbResult<u64> {
+        self.scan_table(table, schema, KeyRange::default(), u64::MAX, |_| Ok(false))
+    }
+
+    /*
+    Visit the rows of a select that pass the filter, those of its table
+    or those joined from its tables. Without joins the first skip cells
+    are passed without being read, as in scan_table, unless the rows are
+    read through an index.
+    */
+    fn select_rows(&self, stmt: &Statement, table: Rc<Table>, skip: u64,
+        visit: &mut dyn FnMut(&[Value]) -> DbResult<bool>) -> DbResult<u64> {
+        if stmt.joins.is_empty() {
+            let visit_filtered = |values: Vec<Value>| match passes_filter(stmt, &values) {
+                true => visit(&values),
+                false => Ok(true),
+            };
+            if let Some(scan) = &stmt.index_scan {
+                self.scan_index(table, &stmt.schema, scan, visit_filtered)?;
+                return Ok(0);
+            }
+            return self.scan_table(table, &stmt.schema, stmt.key_range, skip, visit_filtered);
+        }
+
+        /*
+        Hash tables are built up front from the joined tables. When the
+        first table is the smaller input of the first join its rows are
+        hashed instead, and the joined table streams past them.
+        */
+        let mut hash_tables: Vec<Option<HashTable>> = Vec::new();
+        let mut build_first = false;
+        for (i, join) in stmt.joins.iter().enumerate() {
+            let build_keys = match &join.strategy {
+                JoinStrategy::StrategyHash { build_keys, .. } => build_keys,
+                _ => {
+                    hash_tables.push(None);
+                    continue;
+                }
+            };
+            if i == 0 && self.count_rows(table.clone(), &stmt.schema)? < self.count_rows(table.clone(), &join.schema)? {
+                build_first = true;
+                hash_tables.push(None);
+                continue;
+            }
+
+            let mut hash_table = HashTable::new();
+            self.scan_table(table.clone(), &join.schema, KeyRange::default(), 0, |values| {
+                if let Some(key) = hash_key(build_keys, &values) {
+                    hash_table.entry(key).or_default().push(values);
+                }
+                Ok(true)
+            })?;
+            hash_tables.push(Some(hash_table));
+        }
+
+        if !build_first {
+            self.scan_table(table.clone(), &stmt.schema, stmt.key_range, 0, |values| {
+                self.join_row(stmt, &table, &hash_tables, 0, values, visit)
+            })?;
+            return Ok(0);
+        }
+
+        let join = &stmt.joins[0];
+        let (probe_keys, build_keys) = match &join.strategy {
+            JoinStrategy::StrategyHash { probe_keys, build_keys } => (probe_keys, build_keys),
+            _ => return Err(DbError::Other("Join plan is corrupt.".to_string())),
+        };
+        let mut first_rows: Vec<Vec<Value>> = Vec::new();
+        let mut first_hash_table: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+        self.scan_table(table.clone(), &stmt.schema, stmt.key_range, 0, |values| {
+            if let Some(key) = hash_key(probe_keys, &values) {
+                first_hash_table.entry(key).or_default().push(first_rows.len());
+            }
+            first_rows.push(values);
+            Ok(true)
+        })?;
+
+        let mut matched = vec![false; first_rows.len()];
+        let mut more = true;
+        self.scan_table(table.clone(), &join.schema, KeyRange::default(), 0, |right| {
+            let indexes = match hash_key(build_keys, &right).and_then(|key| first_hash_table.get(&key)) {
+                Some(indexes) => indexes,
+                None => return Ok(true),
+            };
+            for &index in indexes {
+                let mut joined = first_rows[index].clone();
+                joined.extend_from_slice(&right);
+                if !passes_condition(join, &joined) {
+                    continue;
+                }
+                matched[index] = true;
+                more = self.join_row(stmt, &table, &hash_tables, 1, joined, visit)?;
+                if !more {
+                    break;
+                }
+            }
+            Ok(more)
+        })?;
+
+        if join.kind == JoinKind::JoinLeft {
+            for (index, mut row) in first_rows.into_iter().enumerate() {
+                if !more {
+                    break;
+                }
+                if !matched[index] {
+                    row.resize(row.len() + join.schema.columns.len() + 1, Value::ValueNull);
+                    more = self.join_row(stmt, &table, &hash_tables, 1, row, visit)?;
+                }
+            }
+        }
+        Ok(0)
+    }
+
+    /*
+    Join a row of the tables before the join at level to the rows of its
+    table, and on to the joins after it. A left join with no row for it
+    goes on with NULLs. Returns false once visit wants no more rows.
+    */
+    fn join_row(&self, stmt: &Statement, table: &Rc<Table>, hash_tables: &[Option<HashTable>], level: usize,
+        row: Vec<Value>, visit: &mut dyn FnMut(&[Value]) -> DbResult<bool>) -> DbResult<bool> {
+        let join = match stmt.joins.get(level) {
+            Some(join) => join,
+            None if passes_filter(stmt, &row) => return visit(&row),
+            None => return Ok(true),
+        };
+
+        let mut matched = false;
+        let mut more = true;
+        let mut join_right = |right: &[Value]| -> DbResult<bool> {
+            let mut joined = row.clone();
+            joined.extend_from_slice(right);
+            if !passes_condition(join, &joined) {
+                return Ok(true);
+            }
+            matched = true;
+            self.join_row(stmt, table, hash_tables, level + 1, joined, visit)
+        };
+        match &join.strategy {
+            JoinStrategy::StrategyLookup(key) => {
+                if let Some(key) = rowid_value(&evaluate(key, &row)) {
+                    let cursor = Cursor::table_seek(table.clone(), join.schema.root_page_num, key)?;
+                    if cursor.cursor_matches(&rowid_key(key))? {
+                        let mut values = Row::deserialize_row(&join.schema, &cursor.cursor_value()?)?.values;
+                        values.push(Value::ValueInteger(key));
+                        drop(cursor);
+                        more = join_right(&values)?;
+                    }
+                }
+            }
+            JoinStrategy::StrategyHash { probe_keys, .. } => {
+                let rows = match (hash_key(probe_keys, &row), &hash_tables[level]) {
+                    (Some(key), Some(hash_table)) => hash_table.get(&key),
+                    _ => None,
+                };
+                for right in rows.into_iter().flatten() {
+                    more = join_right(right)?;
+                    if !more {
+                        break;
+                    }
+                }
+            }
+            JoinStrategy::StrategyLoop => {
+                self.scan_table(table.clone(), &join.schema, KeyRange::default(), 0, |values| {
+                    more = join_right(&values)?;
+                    Ok(more)
+                })?;
+            }
+        }
+
+        if more && !matched && join.kind == JoinKind::JoinLeft {
+            let mut row = row;
+            row.resize(row.len() + join.schema.columns.len() + 1, Value::ValueNull);
+            more = self.join_row(stmt, table, hash_tables, level + 1, row, visit)?;
+        }
+        Ok(more)
+    }
+
+    /*
+    A lookup for a single id visits one leaf, a range stops at its end.
+    Without either a filter on indexed columns reads through the index.
+    With an ORDER BY the rows go through a sorter before the output.
+    The scan stops once the limit is reached, and without a filter, a
+    join or a sort the offset is skipped a leaf at a time.
 
+    An aggregate select feeds the rows to an aggregator and outputs its
+    groups instead. count(*) alone without a filter only adds up the
+    cell counts of the leaves.
+    */
+    pub fn execute_select(&self, stmt: &Statement, table: Rc<Table>,
+        output: &mut dyn RowOutput) -> DbResult<ExecuteResult> {
+        let names: Vec<String> = stmt.projection.iter().map(|(name, _)| name.clone()).collect();
+        output.columns(&names);
+
+        let mut result = SelectResult {
+            stmt,
+            output,
+            sorter: match stmt.order_by.is_empty() {
+                true => None,
+                false => Some(Sorter::new(stmt.order_by.iter().map(|(_, desc)| *desc).collect(), self.sort_memory)),
+            },
+            rows_to_skip: stmt.offset,
+            rows_to_return: stmt.limit.unwrap_or(u64::MAX),
+        };
+        if result.rows_to_return == 0 {
+            return Ok(ExecuteResult::ExecuteSuccess);
+        }
+
+        match &stmt.aggregate {
+            None => {
+                let skip = match result.sorter.is_none() && stmt.filter.is_none() && stmt.joins.is_empty() {
+                    true => result.rows_to_skip,
+                    false => 0,
+                };
+                /* Rows the cursor passes are never visited, the rest of the offset is skipped on output */
+                result.rows_to_skip -= skip;
+                self.select_rows(stmt, table, skip, &mut |values| result.push(values))?;
+            }
+            Some(plan) if plan.counts_only() && stmt.filter.is_none() && stmt.joins.is_empty() => {
+                let count = self.count_rows(table, &stmt.schema)?;
+                let group = vec![Value::ValueInteger(count as i64); plan.calls.len()];
+                result.push_group(plan, &group)?;
+            }
+            Some(plan) => {
+                let mut aggregator = Aggregator::new(plan, self.sort_memory);
+                self.select_rows(stmt, table, 0, &mut |values| {
+                    let mut row: Vec<Value> = plan.group_by.iter().map(|expr| evaluate(expr, values)).collect();
+                    for call in &plan.calls {
+                        row.extend(call.args.iter().map(|expr| evaluate(expr, values)));
+                    }
+                    aggregator.add(row)?;
+                    Ok(true)
+                })?;
+
+                let mut groups = aggregator.finish()?;
+                while let Some(group) = groups.next_group()? {
+                    if !result.push_group(plan, &group)? {
+                        break;
+                    }
+                }
+            }
+        }
+
+        result.finish()?;
         Ok(ExecuteResult::ExecuteSuccess)
     }
 
-    pub fn execute_statement(&self, stmt: &Statement,  table: Rc<Table>) -> DbResult<ExecuteResult> {
-        match stmt.stmt_type {
-            StatementType::StatementInsert => self.execute_insert(stmt, table),
-            StatementType::StatementSelect => self.execute_select(table),
+    /*
+    Rowids of the rows a delete or update changes, those passing its
+    filter, found through its key range or index scan. They are all
+    collected before the first change, as changing the tree moves the
+    cells under a cursor and a row moved to a new id could otherwise
+    be visited twice.
+    */
+    fn matching_rowids(&self, stmt: &Statement, table: Rc<Table>) -> DbResult<Vec<i64>> {
+        let mut keys: Vec<i64> = Vec::new();
+        let visit = |values: Vec<Value>| {
+            if passes_filter(stmt, &values) {
+                if let Some(Value::ValueInteger(key)) = values.last() {
+                    keys.push(*key);
+                }
+            }
+            Ok(true)
+        };
+        match &stmt.index_scan {
+            Some(scan) => self.scan_index(table, &stmt.schema, scan, visit)?,
+            None => {
+                self.scan_table(table, &stmt.schema, stmt.key_range, 0, visit)?;
+            }
+        }
+        Ok(keys)
+    }
+
+    pub fn execute_delete(&self, stmt: &Statement, table: Rc<Table>) -> DbResult<ExecuteResult> {
+        let keys = self.matching_rowids(stmt, table.clone())?;
+
+        for &key in &keys {
+            let cursor = Cursor::table_find(table.clone(), stmt.schema.root_page_num, key)?;
+            let values = match stmt.schema.indexes.is_empty() {
+                true => Vec::new(),
+                false => Row::deserialize_row(&stmt.schema, &cursor.cursor_value()?)?.values,
+            };
+            cursor.leaf_node_delete()?;
+            drop(cursor);
+            for index in &stmt.schema.indexes {
+                delete_index_entry(&table, index, &values, key)?;
+            }
+            table.pager.borrow_mut().shrink_cache()?;
+        }
+
+        Ok(ExecuteResult::ExecuteRowsAffected(keys.len()))
+    }
+
+    pub fn execute_update(&self, stmt: &Statement, table: Rc<Table>) -> DbResult<ExecuteResult> {
+        let row_update = &stmt.row_update;
+        let keys = self.matching_rowids(stmt, table.clone())?;
+
+        let schema = &stmt.schema;
+        for &key in &keys {
+            let cursor = Cursor::table_find(table.clone(), stmt.schema.root_page_num, key)?;
+            let old_row = Row::deserialize_row(schema, &cursor.cursor_value()?)?;
+            let mut row = old_row.clone();
+            if let Err(reason) = row_update.apply(schema, &mut row, key) {
+                return Ok(ExecuteResult::ExecuteInvalid(reason));
+            }
+            let value = row.serialize_row();
+
+            let new_key = schema.row_key(&row).unwrap_or(key);
+            if new_key == key {
+                cursor.leaf_node_update(&value)?;
+            } else {
+                /* The key changed, move the cell to its new position unless a row has it */
+                if Cursor::table_find(table.clone(), stmt.schema.root_page_num, new_key)?
+                    .cursor_matches(&rowid_key(new_key))?
+                {
+                    return Ok(ExecuteResult::ExecuteDuplicateKey);
+                }
+                cursor.leaf_node_delete()?;
+                let cursor = Cursor::table_find(table.clone(), stmt.schema.root_page_num, new_key)?;
+                cursor.leaf_node_insert(&rowid_key(new_key), &value)?;
+            }
+
+            /*
+            Only the entries of indexes on a changed column or of a moved row
+            change. A change past the prefix of a long value leaves the same
+            key but is still checked against the unique indexes.
+            */
+            for index in &schema.indexes {
+                if key == new_key && index.indexed_values(&old_row.values) == index.indexed_values(&row.values) {
+                    continue;
+                }
+                delete_index_entry(&table, index, &old_row.values, key)?;
+                if !insert_index_entry(&table, schema, index, &row.values, new_key)? {
+                    return Ok(ExecuteResult::ExecuteUniqueFailed(index.column_names(schema)));
+                }
+            }
+            table.pager.borrow_mut().shrink_cache()?;
+        }
+
+        Ok(ExecuteResult::ExecuteRowsAffected(keys.len()))
+    }
+
+    pub fn execute_begin(&self, table: Rc<Table>) -> DbResult<ExecuteResult> {
+        let mut pager = table.pager.borrow_mut();
+        if pager.in_transaction() {
+            return Ok(ExecuteResult::ExecuteNestedTransaction);
+        }
+        pager.begin_transaction();
+        Ok(ExecuteResult::ExecuteSuccess)
+    }
+
+    /* Commit or roll back the transaction opened by begin */
+    pub fn execute_end(&self, table: Rc<Table>, commit: bool) -> DbResult<ExecuteResult> {
+        let mut pager = table.pager.borrow_mut();
+        if !pager.in_transaction() {
+            return Ok(ExecuteResult::ExecuteNoTransaction);
+        }
+        pager.end_transaction(commit)?;
+        Ok(ExecuteResult::ExecuteSuccess)
+    }
+
+    pub fn execute_savepoint(&self, stmt: &Statement, table: Rc<Table>) -> DbResult<ExecuteResult> {
+        table.pager.borrow_mut().savepoint(&stmt.savepoint_name);
+        Ok(ExecuteResult::ExecuteSuccess)
+    }
+
+    pub fn execute_release(&self, stmt: &Statement, table: Rc<Table>) -> DbResult<ExecuteResult> {
+        if !table.pager.borrow_mut().release_savepoint(&stmt.savepoint_name)? {
+            return Ok(ExecuteResult::ExecuteNoSuchSavepoint(stmt.savepoint_name.clone()));
+        }
+        Ok(ExecuteResult::ExecuteSuccess)
+    }
+
+    pub fn execute_rollback_to(&self, stmt: &Statement, table: Rc<Table>) -> DbResult<ExecuteResult> {
+        if !table.pager.borrow_mut().rollback_to_savepoint(&stmt.savepoint_name)? {
+            return Ok(ExecuteResult::ExecuteNoSuchSavepoint(stmt.savepoint_name.clone()));
+        }
+        Ok(ExecuteResult::ExecuteSuccess)
+    }
+
+    pub fn execute_statement(&self, stmt: &Statement, table: Rc<Table>,
+        output: &mut dyn RowOutput) -> DbResult<ExecuteResult> {
+        let changes_data = matches!(
+            stmt.stmt_type,
+            StatementType::StatementInsert
+                | StatementType::StatementDelete
+                | StatementType::StatementUpdate
+                | StatementType::StatementCreateTable
+                | StatementType::StatementCreateIndex
+        );
+        {
+            let mut pager = table.pager.borrow_mut();
+            pager.begin_read()?;
+            if changes_data {
+                pager.begin_statement();
+            }
+        }
+
+        let result = match stmt.stmt_type {
+            StatementType::StatementInsert => self.execute_insert(stmt, table.clone()),
+            StatementType::StatementSelect => self.execute_select(stmt, table.clone(), output),
+            StatementType::StatementDelete => self.execute_delete(stmt, table.clone()),
+            StatementType::StatementUpdate => self.execute_update(stmt, table.clone()),
+            StatementType::StatementCreateTable => self.execute_create_table(stmt, table.clone()),
+            StatementType::StatementCreateIndex => self.execute_create_index(stmt, table.clone()),
+            StatementType::StatementBegin => self.execute_begin(table.clone()),
+            StatementType::StatementCommit => self.execute_end(table.clone(), true),
+            StatementType::StatementRollback => self.execute_end(table.clone(), false),
+            StatementType::StatementSavepoint => self.execute_savepoint(stmt, table.clone()),
+            StatementType::StatementRelease => self.execute_release(stmt, table.clone()),
+            StatementType::StatementRollbackTo => self.execute_rollback_to(stmt, table.clone()),
+        };
+
+        /*
+        Outside an explicit transaction every statement is its own,
+        all or nothing on disk. Inside one a failed statement, or one
+        breaking a constraint halfway, is undone through its own
+        savepoint and the transaction stays open with the savepoints of
+        the user.
+        */
+        let failed = matches!(
+            result,
+            Err(_)
+                | Ok(ExecuteResult::ExecuteDuplicateKey)
+                | Ok(ExecuteResult::ExecuteUniqueFailed(_))
+                | Ok(ExecuteResult::ExecuteInvalid(_))
+        );
+        let mut pager = table.pager.borrow_mut();
+        match pager.in_transaction() {
+            false if failed => pager.rollback()?,
+            false => pager.commit()?,
+            true => pager.end_statement(!failed)?,
         }
+        pager.end_read()?;
+        pager.shrink_cache()?;
+        result
     }
 }
\ No newline at end of file
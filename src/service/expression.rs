@@ -0,0 +1,340 @@
+use std::cmp::Ordering;
+
+use crate::service::aggregate::AggregateFunction;
+use crate::service::ast::{BinaryOp, Expr, Literal, UnaryOp};
+use crate::service::prepare_statement::PrepareResult;
+use crate::service::schema::{ColumnType, Schema};
+use crate::service::Value;
+
+/*
+An expression with its column names resolved against the tables of a
+statement. It is evaluated against the values of a row of each table
+followed by the rowid of that row, one table after the other.
+*/
+#[derive(Debug, Clone)]
+pub enum BoundExpr {
+    BoundLiteral(Value),
+    BoundColumn(usize), // index into the row values
+    BoundUnary(UnaryOp, Box<BoundExpr>),
+    BoundBinary(Box<BoundExpr>, BinaryOp, Box<BoundExpr>),
+}
+
+/* A table a statement reads, offset is where its values start */
+#[derive(Debug, Clone, Copy)]
+pub struct TableScope<'a> {
+    pub name: &'a str, // the statement refers to the table by, its alias if it has one
+    pub schema: &'a Schema,
+    pub offset: usize,
+    pub using: &'a [String], // columns joined with USING, plain names of them refer to the table before
+}
+
+impl<'a> TableScope<'a> {
+    /* Number of values a row of the table takes, its columns and the rowid */
+    pub fn width(&self) -> usize {
+        self.schema.columns.len() + 1
+    }
+
+    /* Index of a column or of the rowid, which comes after the columns unless a column is its alias */
+    pub fn column(&self, name: &str) -> Option<usize> {
+        let index = match self.schema.column_index(name) {
+            Some(index) => index,
+            None if self.schema.is_key_column(name) => self.schema.key_column.unwrap_or(self.schema.columns.len()),
+            None => return None,
+        };
+        Some(self.offset + index)
+    }
+}
+
+pub fn bind_expr(scope: &[TableScope], expr: &Expr) -> Result<BoundExpr, PrepareResult> {
+    match expr {
+        Expr::ExprLiteral(literal) => Ok(BoundExpr::BoundLiteral(literal_value(literal))),
+        Expr::ExprColumn { table: Some(table), name } => {
+            match scope.iter().find(|tab| tab.name.eq_ignore_ascii_case(table)) {
+                Some(tab) => match tab.column(name) {
+                    Some(index) => Ok(BoundExpr::BoundColumn(index)),
+                    None => Err(PrepareResult::PrepareNoSuchColumn(format!("{}.{}", table, name))),
+                },
+                None => Err(PrepareResult::PrepareNoSuchColumn(format!("{}.{}", table, name))),
+            }
+        }
+        Expr::ExprColumn { table: None, name } => {
+            let mut indexes = scope
+                .iter()
+                .filter(|tab| !tab.using.iter().any(|column| column.eq_ignore_ascii_case(name)))
+                .filter_map(|tab| tab.column(name));
+            match (indexes.next(), indexes.next()) {
+                (Some(index), None) => Ok(BoundExpr::BoundColumn(index)),
+                (Some(_), Some(_)) => Err(PrepareResult::PrepareInvalid(format!("ambiguous column name: {}", name))),
+                (None, _) => Err(PrepareResult::PrepareNoSuchColumn(name.clone())),
+            }
+        }
+        Expr::ExprUnary(op, operand) => Ok(BoundExpr::BoundUnary(*op, Box::new(bind_expr(scope, operand)?))),
+        Expr::ExprBinary(left, op, right) => {
+            let mut left = bind_expr(scope, left)?;
+            let mut right = bind_expr(scope, right)?;
+            if is_comparison(*op) {
+                /* A constant compared to a column takes the type of the column */
+                right = apply_affinity(scope, &left, right);
+                left = apply_affinity(scope, &right, left);
+            }
+            Ok(BoundExpr::BoundBinary(Box::new(left), *op, Box::new(right)))
+        }
+        Expr::ExprBetween {
+            expr,
+            low,
+            high,
+            negated,
+        } => {
+            /* x BETWEEN a AND b is x >= a AND x <= b */
+            let low = Expr::ExprBinary(expr.clone(), BinaryOp::BinaryGtEq, low.clone());
+            let high = Expr::ExprBinary(expr.clone(), BinaryOp::BinaryLtEq, high.clone());
+            let between = BoundExpr::BoundBinary(
+                Box::new(bind_expr(scope, &low)?),
+                BinaryOp::BinaryAnd,
+                Box::new(bind_expr(scope, &high)?),
+            );
+            match negated {
+                true => Ok(BoundExpr::BoundUnary(UnaryOp::UnaryNot, Box::new(between))),
+                false => Ok(between),
+            }
+        }
+        Expr::ExprFunction { name, .. } if AggregateFunction::from_name(name).is_some() => {
+            Err(PrepareResult::PrepareInvalid(format!("misuse of aggregate: {}()", name)))
+        }
+        Expr::ExprFunction { .. } => Err(PrepareResult::PrepareUnsupported("Functions")),
+        Expr::ExprCollate(..) => Err(PrepareResult::PrepareUnsupported("COLLATE")),
+    }
+}
+
+pub fn literal_value(literal: &Literal) -> Value {
+    match literal {
+        Literal::LiteralNull => Value::ValueNull,
+        Literal::LiteralInteger(value) => Value::ValueInteger(*value),
+        Literal::LiteralFloat(value) => Value::ValueReal(*value),
+        Literal::LiteralString(text) => Value::ValueText(text.clone()),
+        Literal::LiteralBlob(blob) => Value::ValueBlob(blob.clone()),
+    }
+}
+
+fn is_comparison(op: BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::BinaryEq
+            | BinaryOp::BinaryNotEq
+            | BinaryOp::BinaryIs
+            | BinaryOp::BinaryIsNot
+            | BinaryOp::BinaryLt
+            | BinaryOp::BinaryLtEq
+            | BinaryOp::BinaryGt
+            | BinaryOp::BinaryGtEq
+    )
+}
+
+fn apply_affinity(scope: &[TableScope], column: &BoundExpr, operand: BoundExpr) -> BoundExpr {
+    let index = match column {
+        BoundExpr::BoundColumn(index) => *index,
+        _ => return operand,
+    };
+    let tab = match scope.iter().find(|tab| tab.offset <= index && index < tab.offset + tab.width()) {
+        Some(tab) => tab,
+        None => return operand,
+    };
+    let col_type = match tab.schema.columns.get(index - tab.offset) {
+        Some(column) => column.col_type,
+        None => ColumnType::ColumnInteger, // the rowid
+    };
+    match (col_type, operand) {
+        (ColumnType::ColumnInteger | ColumnType::ColumnReal, BoundExpr::BoundLiteral(Value::ValueText(text))) => {
+            match text_to_number(&text) {
+                Some(number) => BoundExpr::BoundLiteral(number),
+                None => BoundExpr::BoundLiteral(Value::ValueText(text)),
+            }
+        }
+        (ColumnType::ColumnText, BoundExpr::BoundLiteral(value @ (Value::ValueInteger(_) | Value::ValueReal(_)))) => {
+            BoundExpr::BoundLiteral(Value::ValueText(value_text(&value)))
+        }
+        (_, operand) => operand,
+    }
+}
+
+/* Number written in text, None when the text is not one */
+fn text_to_number(text: &str) -> Option<Value> {
+    let text = text.trim();
+    if let Ok(value) = text.parse::<i64>() {
+        return Some(Value::ValueInteger(value));
+    }
+    text.parse::<f64>().ok().map(Value::ValueReal)
+}
+
+pub fn evaluate(expr: &BoundExpr, values: &[Value]) -> Value {
+    match expr {
+        BoundExpr::BoundLiteral(value) => value.clone(),
+        BoundExpr::BoundColumn(index) => values[*index].clone(),
+        BoundExpr::BoundUnary(op, operand) => {
+            let operand = evaluate(operand, values);
+            match op {
+                UnaryOp::UnaryPlus => operand,
+                UnaryOp::UnaryNeg => match numeric_value(&operand) {
+                    Value::ValueInteger(value) => match value.checked_neg() {
+                        Some(value) => Value::ValueInteger(value),
+                        None => Value::ValueReal(-(value as f64)),
+                    },
+                    Value::ValueReal(value) => Value::ValueReal(-value),
+                    _ => Value::ValueNull,
+                },
+                UnaryOp::UnaryNot => match is_true(&operand) {
+                    Some(truth) => boolean(!truth),
+                    None => Value::ValueNull,
+                },
+            }
+        }
+        BoundExpr::BoundBinary(left, BinaryOp::BinaryAnd, right) => {
+            /* Three-valued logic, false wins over NULL */
+            let left = is_true(&evaluate(left, values));
+            if left == Some(false) {
+                return boolean(false);
+            }
+            match (left, is_true(&evaluate(right, values))) {
+                (_, Some(false)) => boolean(false),
+                (Some(true), Some(true)) => boolean(true),
+                _ => Value::ValueNull,
+            }
+        }
+        BoundExpr::BoundBinary(left, BinaryOp::BinaryOr, right) => {
+            /* Three-valued logic, true wins over NULL */
+            let left = is_true(&evaluate(left, values));
+            if left == Some(true) {
+                return boolean(true);
+            }
+            match (left, is_true(&evaluate(right, values))) {
+                (_, Some(true)) => boolean(true),
+                (Some(false), Some(false)) => boolean(false),
+                _ => Value::ValueNull,
+            }
+        }
+        BoundExpr::BoundBinary(left, op, right) => {
+            let left = evaluate(left, values);
+            let right = evaluate(right, values);
+            binary_operation(*op, &left, &right)
+        }
+    }
+}
+
+fn binary_operation(op: BinaryOp, left: &Value, right: &Value) -> Value {
+    match op {
+        BinaryOp::BinaryIs => boolean(compare_values(left, right) == Ordering::Equal),
+        BinaryOp::BinaryIsNot => boolean(compare_values(left, right) != Ordering::Equal),
+        _ if *left == Value::ValueNull || *right == Value::ValueNull => Value::ValueNull,
+        BinaryOp::BinaryEq => boolean(compare_values(left, right) == Ordering::Equal),
+        BinaryOp::BinaryNotEq => boolean(compare_values(left, right) != Ordering::Equal),
+        BinaryOp::BinaryLt => boolean(compare_values(left, right) == Ordering::Less),
+        BinaryOp::BinaryLtEq => boolean(compare_values(left, right) != Ordering::Greater),
+        BinaryOp::BinaryGt => boolean(compare_values(left, right) == Ordering::Greater),
+        BinaryOp::BinaryGtEq => boolean(compare_values(left, right) != Ordering::Less),
+        BinaryOp::BinaryConcat => Value::ValueText(value_text(left) + &value_text(right)),
+        _ => arithmetic(op, &numeric_value(left), &numeric_value(right)),
+    }
+}
+
+/*
+Integer arithmetic stays integer unless it overflows, as in sqlite.
+Division or modulo by zero is NULL.
+*/
+fn arithmetic(op: BinaryOp, left: &Value, right: &Value) -> Value {
+    if let (Value::ValueInteger(left), Value::ValueInteger(right)) = (left, right) {
+        let result = match op {
+            BinaryOp::BinaryAdd => left.checked_add(*right),
+            BinaryOp::BinarySub => left.checked_sub(*right),
+            BinaryOp::BinaryMul => left.checked_mul(*right),
+            BinaryOp::BinaryDiv | BinaryOp::BinaryMod if *right == 0 => return Value::ValueNull,
+            BinaryOp::BinaryDiv => left.checked_div(*right),
+            BinaryOp::BinaryMod => left.checked_rem(*right),
+            _ => return Value::ValueNull,
+        };
+        if let Some(result) = result {
+            return Value::ValueInteger(result);
+        }
+    }
+
+    let (left, right) = match (real_value(left), real_value(right)) {
+        (Some(left), Some(right)) => (left, right),
+        _ => return Value::ValueNull,
+    };
+    match op {
+        BinaryOp::BinaryAdd => Value::ValueReal(left + right),
+        BinaryOp::BinarySub => Value::ValueReal(left - right),
+        BinaryOp::BinaryMul => Value::ValueReal(left * right),
+        BinaryOp::BinaryDiv if right == 0.0 => Value::ValueNull,
+        BinaryOp::BinaryDiv => Value::ValueReal(left / right),
+        /* Modulo works on the integer parts */
+        BinaryOp::BinaryMod => match (left as i64, right as i64) {
+            (_, 0) => Value::ValueNull,
+            (left, right) => Value::ValueReal(left.wrapping_rem(right) as f64),
+        },
+        _ => Value::ValueNull,
+    }
+}
+
+fn boolean(truth: bool) -> Value {
+    Value::ValueInteger(truth as i64)
+}
+
+/* Value used where a number is needed, text that is not a number is 0 */
+pub fn numeric_value(value: &Value) -> Value {
+    match value {
+        Value::ValueNull | Value::ValueInteger(_) | Value::ValueReal(_) => value.clone(),
+        Value::ValueText(text) => text_to_number(text).unwrap_or(Value::ValueInteger(0)),
+        Value::ValueBlob(_) => Value::ValueInteger(0),
+    }
+}
+
+fn real_value(value: &Value) -> Option<f64> {
+    match value {
+        Value::ValueInteger(value) => Some(*value as f64),
+        Value::ValueReal(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/* Text of a value, as it prints */
+pub fn value_text(value: &Value) -> String {
+    match value {
+        Value::ValueText(text) => text.clone(),
+        Value::ValueBlob(blob) => String::from_utf8_lossy(blob).into_owned(),
+        value => value.to_string(),
+    }
+}
+
+/* Truth of a condition, None when it is NULL */
+pub fn is_true(value: &Value) -> Option<bool> {
+    match numeric_value(value) {
+        Value::ValueInteger(value) => Some(value != 0),
+        Value::ValueReal(value) => Some(value != 0.0),
+        _ => None,
+    }
+}
+
+/*
+Order of two values as in sqlite: NULL first, then numbers, then text,
+then blobs. Text and blobs compare byte by byte.
+*/
+pub fn compare_values(left: &Value, right: &Value) -> Ordering {
+    fn class(value: &Value) -> u8 {
+        match value {
+            Value::ValueNull => 0,
+            Value::ValueInteger(_) | Value::ValueReal(_) => 1,
+            Value::ValueText(_) => 2,
+            Value::ValueBlob(_) => 3,
+        }
+    }
+
+    match (left, right) {
+        (Value::ValueInteger(left), Value::ValueInteger(right)) => left.cmp(right),
+        (Value::ValueText(left), Value::ValueText(right)) => left.as_bytes().cmp(right.as_bytes()),
+        (Value::ValueBlob(left), Value::ValueBlob(right)) => left.cmp(right),
+        _ => match (real_value(left), real_value(right)) {
+            (Some(left), Some(right)) => left.partial_cmp(&right).unwrap_or(Ordering::Equal),
+            _ => class(left).cmp(&class(right)),
+        },
+    }
+}
@@ -0,0 +1,593 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use crate::db::catalog::CatalogEntry;
+use crate::db::cursor::Cursor;
+use crate::db::error::{DbError, DbResult};
+use crate::db::table::Table;
+use crate::db::tree::{KeyComparator, MAX_KEY_SIZE};
+use crate::service::ast::{BinaryOp, CreateIndexStmt, Stmt};
+use crate::service::expression::{compare_values, BoundExpr};
+use crate::service::join::split_conjuncts;
+use crate::service::parser::parse;
+use crate::service::prepare_statement::PrepareResult;
+use crate::service::record::{deserialize_record, serialize_record, VARINT_MAX_SIZE};
+use crate::service::schema::Schema;
+use crate::service::{Row, Value};
+
+/* How an index column orders text, other values always compare as in compare_values */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collation {
+    CollationBinary,
+    CollationNoCase, // ascii letters compare without their case
+    CollationRTrim,  // trailing spaces are ignored
+}
+
+impl Collation {
+    pub fn from_name(name: &str) -> Option<Collation> {
+        match name.to_ascii_lowercase().as_str() {
+            "binary" => Some(Collation::CollationBinary),
+            "nocase" => Some(Collation::CollationNoCase),
+            "rtrim" => Some(Collation::CollationRTrim),
+            _ => None,
+        }
+    }
+
+    pub fn compare(&self, left: &Value, right: &Value) -> Ordering {
+        match (self, left, right) {
+            (Collation::CollationNoCase, Value::ValueText(left), Value::ValueText(right)) => {
+                left.to_ascii_lowercase().cmp(&right.to_ascii_lowercase())
+            }
+            (Collation::CollationRTrim, Value::ValueText(left), Value::ValueText(right)) => {
+                left.trim_end_matches(' ').as_bytes().cmp(right.trim_end_matches(' ').as_bytes())
+            }
+            _ => compare_values(left, right),
+        }
+    }
+}
+
+/*
+A secondary index of a table, built from its create index statement.
+Its tree holds an entry for every row of the table, keyed by the record
+of the indexed columns followed by the rowid. Entries have no value.
+Text and blobs longer than the prefix size of the index are cut so that
+every key fits in MAX_KEY_SIZE; entries then tie on their prefix and
+the full values are read from the rows.
+*/
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub name: String,
+    pub columns: Vec<usize>, // of the table, in key order
+    pub collations: Vec<Collation>, // of each indexed column
+    pub unique: bool,
+    pub root_page_num: usize, // of the index tree, set once the index exists
+    pub sql: String,
+}
+
+/*
+Orders index keys column by column with the collations of the index,
+the rowid after them compares as binary. A key that is a prefix of
+another comes first, so seeking to the values of the first columns
+finds the first entry holding them.
+*/
+pub struct IndexComparator {
+    collations: Vec<Collation>,
+}
+
+impl KeyComparator for IndexComparator {
+    fn compare(&self, left: &[u8], right: &[u8]) -> DbResult<Ordering> {
+        Ok(compare_keys(&key_values(left)?, &key_values(right)?, &self.collations))
+    }
+}
+
+/* Values of an index key, which must be a record */
+fn key_values(key: &[u8]) -> DbResult<Vec<Value>> {
+    deserialize_record(key).ok_or_else(|| DbError::Other("Record is corrupt.".to_string()))
+}
+
+fn compare_keys(left: &[Value], right: &[Value], collations: &[Collation]) -> Ordering {
+    for (i, (left, right)) in left.iter().zip(right).enumerate() {
+        let collation = collations.get(i).unwrap_or(&Collation::CollationBinary);
+        let ordering = collation.compare(left, right);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    left.len().cmp(&right.len())
+}
+
+/* Index keys as .btree prints them */
+pub fn index_key_text(key: &[u8]) -> DbResult<String> {
+    Ok(Row { values: key_values(key)? }.to_string())
+}
+
+impl Index {
+    pub fn from_create_index(create: &CreateIndexStmt, schema: &Schema) -> Result<Index, PrepareResult> {
+        let mut columns = Vec::new();
+        let mut collations = Vec::new();
+        for column in &create.columns {
+            let collation = match &column.collation {
+                Some(name) => match Collation::from_name(name) {
+                    Some(collation) => collation,
+                    None => return Err(PrepareResult::PrepareInvalid(format!("no such collation sequence: {}", name))),
+                },
+                None => Collation::CollationBinary,
+            };
+            collations.push(collation);
+            if column.desc {
+                return Err(PrepareResult::PrepareUnsupported("DESC in an index"));
+            }
+            match schema.column_index(&column.name) {
+                Some(index) => columns.push(index),
+                None => return Err(PrepareResult::PrepareNoSuchColumn(column.name.clone())),
+            }
+        }
+
+        Ok(Index {
+            name: create.name.clone(),
+            columns,
+            collations,
+            unique: create.unique,
+            root_page_num: 0,
+            sql: create.sql.clone(),
+        })
+    }
+
+    /* Rebuild an index of the table of schema from its catalog entry */
+    pub fn load(entry: &CatalogEntry, schema: &Schema) -> Option<Index> {
+        let mut index = match parse(&entry.sql).ok()?.as_slice() {
+            [Stmt::StmtCreateIndex(create)] => Index::from_create_index(create, schema).ok()?,
+            _ => return None,
+        };
+        index.root_page_num = entry.root_page_num;
+        Some(index)
+    }
+
+    pub fn comparator(&self) -> Rc<dyn KeyComparator> {
+        Rc::new(IndexComparator {
+            collations: self.collations.clone(),
+        })
+    }
+
+    /* Values of the indexed columns of a row */
+    pub fn indexed_values(&self, values: &[Value]) -> Vec<Value> {
+        self.columns.iter().map(|&column| values[column].clone()).collect()
+    }
+
+    /*
+    Bytes of a text or blob an entry keeps. Each column may take its
+    serial type and a text cut up to 3 bytes past this size to end on a
+    char boundary, next to the record header and the rowid.
+    */
+    fn prefix_size(&self) -> usize {
+        let columns = self.columns.len().max(1);
+        ((MAX_KEY_SIZE - 2 - 1 - VARINT_MAX_SIZE) / columns).saturating_sub(2 + 3)
+    }
+
+    /*
+    A value as an entry holds it. Text is cut on the first char boundary
+    from the prefix size on, so that cut values keep the order of the
+    full ones, only ties appear.
+    */
+    fn key_value(&self, value: &Value) -> Value {
+        let size = self.prefix_size();
+        match value {
+            Value::ValueText(text) if text.len() > size => {
+                let end = (size..text.len()).find(|&end| text.is_char_boundary(end)).unwrap_or(text.len());
+                Value::ValueText(text[..end].to_string())
+            }
+            Value::ValueBlob(blob) if blob.len() > size => Value::ValueBlob(blob[..size].to_vec()),
+            value => value.clone(),
+        }
+    }
+
+    /* Whether an entry may hold a value cut from a longer one */
+    fn maybe_cut(&self, value: &Value) -> bool {
+        match value {
+            Value::ValueText(text) => text.len() >= self.prefix_size(),
+            Value::ValueBlob(blob) => blob.len() >= self.prefix_size(),
+            _ => false,
+        }
+    }
+
+    /* Indexed values of a row as its entry holds them, without the rowid */
+    fn key_values(&self, values: &[Value]) -> Vec<Value> {
+        self.columns.iter().map(|&column| self.key_value(&values[column])).collect()
+    }
+
+    /* Key of the entry for a row with these column values */
+    pub fn entry_key(&self, values: &[Value], rowid: i64) -> Vec<u8> {
+        let mut key = self.key_values(values);
+        key.push(Value::ValueInteger(rowid));
+        serialize_record(&key)
+    }
+
+    /* Indexed values and rowid of an entry, long values are cut */
+    pub fn entry_values(&self, key: &[u8]) -> DbResult<(Vec<Value>, i64)> {
+        let mut values = match deserialize_record(key) {
+            Some(values) if values.len() == self.columns.len() + 1 => values,
+            _ => return Err(DbError::Other(format!("Index {} is corrupt.", self.name))),
+        };
+        match values.pop() {
+            Some(Value::ValueInteger(rowid)) => Ok((values, rowid)),
+            _ => Err(DbError::Other(format!("Index {} is corrupt.", self.name))),
+        }
+    }
+
+    /* Indexed columns as a failed UNIQUE constraint names them, like t.a, t.b */
+    pub fn column_names(&self, schema: &Schema) -> String {
+        let names: Vec<String> = self
+            .columns
+            .iter()
+            .map(|&column| format!("{}.{}", schema.name, schema.columns[column].name))
+            .collect();
+        names.join(", ")
+    }
+}
+
+/*
+Add the entry of a row of the table of schema to an index. Returns
+false without adding it when the index is unique and holds the same
+values for another row, equal under the collations of the index. Rows
+with a NULL among the indexed columns never conflict, as in sqlite.
+Entries tying on the prefix of a long value are checked on their rows.
+*/
+pub fn insert_index_entry(table: &Rc<Table>, schema: &Schema, index: &Index, values: &[Value], rowid: i64) -> DbResult<bool> {
+    let comparator = index.comparator();
+    let indexed = index.indexed_values(values);
+    if index.unique && !indexed.contains(&Value::ValueNull) {
+        let prefix = index.key_values(values);
+        let mut cursor = Cursor::tree_seek(table.clone(), comparator.clone(), index.root_page_num, &serialize_record(&prefix))?;
+        while !cursor.end_of_table {
+            let (entry, entry_rowid) = index.entry_values(&cursor.cursor_key()?)?;
+            if compare_keys(&entry, &prefix, &index.collations) != Ordering::Equal {
+                break;
+            }
+            let row_cursor = Cursor::table_find(table.clone(), schema.root_page_num, entry_rowid)?;
+            let row = Row::deserialize_row(schema, &row_cursor.cursor_value()?)?;
+            drop(row_cursor);
+            if compare_keys(&index.indexed_values(&row.values), &indexed, &index.collations) == Ordering::Equal {
+                return Ok(false);
+            }
+            cursor.cursor_advance()?;
+        }
+    }
+
+    let key = index.entry_key(values, rowid);
+    let cursor = Cursor::tree_find(table.clone(), comparator, index.root_page_num, &key)?;
+    cursor.leaf_node_insert(&key, &[])?;
+    Ok(true)
+}
+
+/* Remove the entry of a row from an index */
+pub fn delete_index_entry(table: &Rc<Table>, index: &Index, values: &[Value], rowid: i64) -> DbResult<()> {
+    let key = index.entry_key(values, rowid);
+    let cursor = Cursor::tree_find(table.clone(), index.comparator(), index.root_page_num, &key)?;
+    if !cursor.cursor_matches(&key)? {
+        return Err(DbError::Other(format!("Index {} is corrupt.", index.name)));
+    }
+    cursor.leaf_node_delete()
+}
+
+/*
+Entries of an index a select reads instead of its whole table: those
+equal to equal on the first columns of the index, then within low and
+high on the next column when there are bounds.
+*/
+#[derive(Debug, Clone)]
+pub struct IndexScan {
+    pub index: Index,
+    pub equal: Vec<Value>,
+    pub low: Option<(Value, bool)>, // bound on the next column and whether it is included
+    pub high: Option<(Value, bool)>,
+}
+
+impl IndexScan {
+    /* Key the scan seeks to before reading entries */
+    pub fn start_key(&self) -> Vec<u8> {
+        let mut start: Vec<Value> = self.equal.iter().map(|value| self.index.key_value(value)).collect();
+        if let Some((low, _)) = &self.low {
+            start.push(self.index.key_value(low));
+        }
+        serialize_record(&start)
+    }
+
+    /*
+    Where the indexed values of an entry fall from the start key on:
+    Less when still before the scan, Equal within it and Greater past
+    its end. A NULL in the bounded column is before, it matches no bound.
+    Values are compared as entries hold them, an entry tying with a
+    bound on a cut value is within the scan and left to the filter.
+    */
+    pub fn entry_position(&self, entry: &[Value]) -> Ordering {
+        let index = &self.index;
+        let num_equal = self.equal.len();
+        let equal: Vec<Value> = self.equal.iter().map(|value| index.key_value(value)).collect();
+        if compare_keys(&entry[..num_equal], &equal, &index.collations) != Ordering::Equal {
+            return Ordering::Greater;
+        }
+        if self.low.is_none() && self.high.is_none() {
+            return Ordering::Equal;
+        }
+
+        let value = &entry[num_equal];
+        if *value == Value::ValueNull {
+            return Ordering::Less;
+        }
+        if let Some((low, inclusive)) = &self.low {
+            match compare_values(value, &index.key_value(low)) {
+                Ordering::Less => return Ordering::Less,
+                Ordering::Equal if !inclusive && !index.maybe_cut(value) => return Ordering::Less,
+                _ => {}
+            }
+        }
+        if let Some((high, inclusive)) = &self.high {
+            match compare_values(value, &index.key_value(high)) {
+                Ordering::Greater => return Ordering::Greater,
+                Ordering::Equal if !inclusive && !index.maybe_cut(value) => return Ordering::Greater,
+                _ => {}
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+/* Keep the tighter of two bounds, tighter is Greater for a low bound and Less for a high one */
+fn tighten(bound: &mut Option<(Value, bool)>, value: &Value, inclusive: bool, tighter: Ordering) {
+    let replace = match bound {
+        None => true,
+        Some((current, current_inclusive)) => match compare_values(value, current) {
+            Ordering::Equal => *current_inclusive && !inclusive,
+            ordering => ordering == tighter,
+        },
+    };
+    if replace {
+        *bound = Some((value.clone(), inclusive));
+    }
+}
+
+/*
+Pick the index of the table in schema that the filter of a select is
+best read through, None when none helps. Only terms of a top level AND
+comparing a column with a constant are used. The index with the most
+of its first columns compared by equality wins, a range on the column
+after them counts for less. Comparisons are binary, so only the columns
+before one with another collation are used. The filter still checks
+every row found.
+*/
+pub fn plan_index_scan(schema: &Schema, filter: &BoundExpr) -> Option<IndexScan> {
+    /* Comparisons as column, operator and constant, 5 < a is a > 5 */
+    let mut terms: Vec<(usize, BinaryOp, &Value)> = Vec::new();
+    for term in split_conjuncts(filter) {
+        let (left, op, right) = match term {
+            BoundExpr::BoundBinary(left, op, right) => (&**left, *op, &**right),
+            _ => continue,
+        };
+        let (column, op, value) = match (left, right) {
+            (BoundExpr::BoundColumn(column), BoundExpr::BoundLiteral(value)) => (*column, op, value),
+            (BoundExpr::BoundLiteral(value), BoundExpr::BoundColumn(column)) => {
+                let flipped = match op {
+                    BinaryOp::BinaryLt => BinaryOp::BinaryGt,
+                    BinaryOp::BinaryLtEq => BinaryOp::BinaryGtEq,
+                    BinaryOp::BinaryGt => BinaryOp::BinaryLt,
+                    BinaryOp::BinaryGtEq => BinaryOp::BinaryLtEq,
+                    op => op,
+                };
+                (*column, flipped, value)
+            }
+            _ => continue,
+        };
+        /* A comparison with NULL is never true, the filter rejects every row alone */
+        if *value != Value::ValueNull {
+            terms.push((column, op, value));
+        }
+    }
+
+    let mut best: Option<(usize, IndexScan)> = None;
+    for index in &schema.indexes {
+        let mut scan = IndexScan {
+            index: index.clone(),
+            equal: Vec::new(),
+            low: None,
+            high: None,
+        };
+        for (&column, &collation) in index.columns.iter().zip(&index.collations) {
+            if collation != Collation::CollationBinary {
+                break;
+            }
+            if let Some((_, _, value)) = terms.iter().find(|(c, op, _)| *c == column && *op == BinaryOp::BinaryEq) {
+                scan.equal.push((*value).clone());
+                continue;
+            }
+            for (_, op, value) in terms.iter().filter(|(c, _, _)| *c == column) {
+                match op {
+                    BinaryOp::BinaryGt => tighten(&mut scan.low, value, false, Ordering::Greater),
+                    BinaryOp::BinaryGtEq => tighten(&mut scan.low, value, true, Ordering::Greater),
+                    BinaryOp::BinaryLt => tighten(&mut scan.high, value, false, Ordering::Less),
+                    BinaryOp::BinaryLtEq => tighten(&mut scan.high, value, true, Ordering::Less),
+                    _ => {}
+                }
+            }
+            break;
+        }
+
+        let score = 2 * scan.equal.len() + (scan.low.is_some() || scan.high.is_some()) as usize;
+        if score > 0 && best.as_ref().is_none_or(|(best_score, _)| score > *best_score) {
+            best = Some((score, scan));
+        }
+    }
+    best.map(|(_, scan)| scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::TempDb;
+    use crate::service::executor::{ExecuteResult, Executor, RowOutput};
+    use crate::service::prepare_statement::PrepareService;
+    use crate::service::sorter::DEFAULT_SORT_MEMORY;
+    use crate::service::Statement;
+
+    struct Rows(Vec<Vec<Value>>);
+
+    impl RowOutput for Rows {
+        fn columns(&mut self, _names: &[String]) {}
+        fn row(&mut self, row: &Row) {
+            self.0.push(row.values.clone());
+        }
+    }
+
+    fn prepare(table: &Rc<Table>, sql: &str) -> Statement {
+        let prepare_service = PrepareService::new();
+        let node = prepare_service.parse_statements(sql).unwrap().remove(0);
+        let mut stmt = Statement::new();
+        match prepare_service.prepare_statement(&node, table.clone(), &mut stmt).unwrap() {
+            PrepareResult::PrepareSuccess => stmt,
+            result => panic!("{}: {}", sql, result),
+        }
+    }
+
+    fn execute(table: &Rc<Table>, sql: &str) -> (ExecuteResult, Vec<Vec<Value>>) {
+        let stmt = prepare(table, sql);
+        let mut rows = Rows(Vec::new());
+        let result = Executor::new(DEFAULT_SORT_MEMORY).execute_statement(&stmt, table.clone(), &mut rows).unwrap();
+        (result, rows.0)
+    }
+
+    fn run(table: &Rc<Table>, sql: &str) {
+        let (result, _) = execute(table, sql);
+        assert!(!matches!(result, ExecuteResult::ExecuteUniqueFailed(_)), "{} failed", sql);
+    }
+
+    fn select(table: &Rc<Table>, sql: &str) -> Vec<Vec<Value>> {
+        execute(table, sql).1
+    }
+
+    /*
+    Every index of t holds exactly one entry per row, with the key built
+    from the row as it is now, and its entries are in order.
+    */
+    fn check_indexes(table: &Rc<Table>) {
+        let schema = prepare(table, "select * from t;").schema;
+        let mut rows = Vec::new();
+        let mut cursor = Cursor::table_seek(table.clone(), schema.root_page_num, i64::MIN).unwrap();
+        while !cursor.end_of_table {
+            let row = Row::deserialize_row(&schema, &cursor.cursor_value().unwrap()).unwrap();
+            rows.push((row.values, cursor.cursor_rowid().unwrap()));
+            cursor.cursor_advance().unwrap();
+        }
+        drop(cursor);
+
+        assert!(!schema.indexes.is_empty());
+        for index in &schema.indexes {
+            let comparator = index.comparator();
+            let mut expected: Vec<Vec<u8>> = rows.iter().map(|(values, rowid)| index.entry_key(values, *rowid)).collect();
+            expected.sort_by(|left, right| comparator.compare(left, right).unwrap());
+
+            let mut entries = Vec::new();
+            let mut cursor = Cursor::tree_seek(table.clone(), comparator.clone(), index.root_page_num, &serialize_record(&[])).unwrap();
+            while !cursor.end_of_table {
+                entries.push(cursor.cursor_key().unwrap());
+                cursor.cursor_advance().unwrap();
+            }
+            assert_eq!(entries.len(), expected.len(), "index {} has {} entries for {} rows", index.name, entries.len(), rows.len());
+            assert!(entries == expected, "entries of index {} do not match the rows", index.name);
+        }
+        table.pager.borrow_mut().shrink_cache().unwrap();
+    }
+
+    fn text(i: i64) -> String {
+        match i % 4 {
+            0 => format!("Text {}", i),
+            /* Long values share a prefix longer than any key holds */
+            1 => format!("{}{}", "x".repeat(700), i),
+            _ => format!("{:05}", (i * 7919) % 100_000),
+        }
+    }
+
+    fn populated_table(db: &TempDb) -> Rc<Table> {
+        let table = db.open();
+        run(&table, "create table t (id integer primary key, a integer, b text, c text);");
+        run(&table, "create index ia on t (a);");
+        run(&table, "create index iab on t (a, b collate nocase);");
+        run(&table, "create unique index uc on t (c);");
+        run(&table, "begin;");
+        for i in 0..1_000 {
+            run(&table, &format!("insert into t values ({}, {}, '{}', '{}');", i, (i * 31) % 17, text(i), text(i * 2 + 1)));
+        }
+        run(&table, "commit;");
+        check_indexes(&table);
+        table
+    }
+
+    #[test]
+    fn indexes_follow_updates_and_deletes() {
+        let db = TempDb::new("index-updates");
+        let table = populated_table(&db);
+        for sql in [
+            "update t set a = a + 1 where id >= 100 and id < 400;",
+            "update t set b = b || 'y' where a = 5;",
+            "update t set c = c || 'z' where id % 3 = 0;",
+            "update t set id = id + 10000 where id < 50;",
+            "update t set a = null where a = 9;",
+            "delete from t where a = 7;",
+            "delete from t where b > 'x';",
+            "delete from t where id > 800 and id < 10000;",
+        ] {
+            run(&table, sql);
+            check_indexes(&table);
+        }
+        table.db_close().unwrap();
+
+        let table = db.open();
+        check_indexes(&table);
+        table.db_close().unwrap();
+    }
+
+    #[test]
+    fn failed_statements_leave_the_indexes_alone() {
+        let db = TempDb::new("index-failed");
+        let table = populated_table(&db);
+        /* The conflict is on the last row, after every other row was changed */
+        let (result, _) = execute(&table, "update t set c = 'same' where id >= 500;");
+        assert!(matches!(result, ExecuteResult::ExecuteUniqueFailed(_)));
+        check_indexes(&table);
+
+        run(&table, "begin;");
+        run(&table, "delete from t where a = 3;");
+        let (result, _) = execute(&table, &format!("insert into t values (5000, 1, 'b', '{}');", text(7)));
+        assert!(matches!(result, ExecuteResult::ExecuteUniqueFailed(_)));
+        check_indexes(&table);
+        run(&table, "rollback;");
+        check_indexes(&table);
+        assert_eq!(select(&table, "select count(*) from t;"), vec![vec![Value::ValueInteger(1_000)]]);
+        table.db_close().unwrap();
+    }
+
+    #[test]
+    fn long_values_are_indexed_by_their_prefix() {
+        let db = TempDb::new("index-long");
+        let table = populated_table(&db);
+        let long = |suffix: &str| format!("{}{}", "é".repeat(400), suffix);
+
+        run(&table, &format!("insert into t values (3000, 1, 'b', '{}');", long("a")));
+        run(&table, &format!("insert into t values (3001, 1, 'b', '{}');", long("b")));
+        let (result, _) = execute(&table, &format!("insert into t values (3002, 1, 'b', '{}');", long("a")));
+        assert!(matches!(result, ExecuteResult::ExecuteUniqueFailed(_)));
+        let (result, _) = execute(&table, &format!("update t set c = '{}' where id = 3000;", long("b")));
+        assert!(matches!(result, ExecuteResult::ExecuteUniqueFailed(_)));
+        run(&table, &format!("update t set c = '{}' where id = 3000;", long("c")));
+        check_indexes(&table);
+
+        let ids = |sql: &str| -> Vec<Value> { select(&table, sql).into_iter().map(|row| row[0].clone()).collect() };
+        assert_eq!(ids(&format!("select id from t where c = '{}';", long("b"))), vec![Value::ValueInteger(3001)]);
+        assert_eq!(ids(&format!("select id from t where c > '{}';", long("b"))), vec![Value::ValueInteger(3000)]);
+        assert_eq!(
+            ids(&format!("select id from t where c >= '{}' and c < '{}';", long("a"), long("c"))),
+            vec![Value::ValueInteger(3001)]
+        );
+        let with_x = ids(&format!("select id from t where b = '{}';", text(1)));
+        assert_eq!(with_x, vec![Value::ValueInteger(1)]);
+        table.db_close().unwrap();
+    }
+}
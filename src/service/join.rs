@@ -0,0 +1,111 @@
+use crate::service::ast::{BinaryOp, JoinKind};
+use crate::service::expression::{BoundExpr, TableScope};
+use crate::service::schema::Schema;
+
+/* How the rows of a joined table are found for a row coming into the join */
+#[derive(Debug, Clone)]
+pub enum JoinStrategy {
+    StrategyLookup(BoundExpr), // rowid of the joined table, on the incoming row
+    StrategyHash {
+        probe_keys: Vec<BoundExpr>, // on the incoming row
+        build_keys: Vec<BoundExpr>, // on a row of the joined table alone
+    },
+    StrategyLoop, // no equality to use, every row of the joined table
+}
+
+/*
+A table joined to the rows of the tables before it. The condition is
+checked on the incoming row followed by a row of the joined table,
+whatever the strategy found that row with.
+*/
+#[derive(Debug, Clone)]
+pub struct JoinPlan {
+    pub schema: Schema,
+    pub kind: JoinKind,
+    pub condition: Option<BoundExpr>, // the ON or USING clause
+    pub strategy: JoinStrategy,
+}
+
+/* Terms of a top level AND */
+pub fn split_conjuncts(expr: &BoundExpr) -> Vec<&BoundExpr> {
+    match expr {
+        BoundExpr::BoundBinary(left, BinaryOp::BinaryAnd, right) => {
+            let mut terms = split_conjuncts(left);
+            terms.extend(split_conjuncts(right));
+            terms
+        }
+        expr => vec![expr],
+    }
+}
+
+/* Smallest and largest column an expression reads, None when it reads none */
+fn column_bounds(expr: &BoundExpr) -> Option<(usize, usize)> {
+    match expr {
+        BoundExpr::BoundLiteral(_) => None,
+        BoundExpr::BoundColumn(index) => Some((*index, *index)),
+        BoundExpr::BoundUnary(_, operand) => column_bounds(operand),
+        BoundExpr::BoundBinary(left, _, right) => match (column_bounds(left), column_bounds(right)) {
+            (Some((low1, high1)), Some((low2, high2))) => Some((low1.min(low2), high1.max(high2))),
+            (bounds, None) | (None, bounds) => bounds,
+        },
+    }
+}
+
+/* The expression with its columns moved down by offset */
+fn shift_columns(expr: &BoundExpr, offset: usize) -> BoundExpr {
+    match expr {
+        BoundExpr::BoundLiteral(value) => BoundExpr::BoundLiteral(value.clone()),
+        BoundExpr::BoundColumn(index) => BoundExpr::BoundColumn(index - offset),
+        BoundExpr::BoundUnary(op, operand) => BoundExpr::BoundUnary(*op, Box::new(shift_columns(operand, offset))),
+        BoundExpr::BoundBinary(left, op, right) => BoundExpr::BoundBinary(
+            Box::new(shift_columns(left, offset)),
+            *op,
+            Box::new(shift_columns(right, offset)),
+        ),
+    }
+}
+
+/*
+Pick how to find the rows of the table in tab from the equalities among
+terms between an expression on it and one on the tables before it. An
+equality on its rowid is looked up in its tree, others are hashed. The
+terms only choose the rows to try, the condition of the join decides.
+*/
+pub fn plan_join(tab: &TableScope, terms: &[&BoundExpr]) -> JoinStrategy {
+    let start = tab.offset;
+    let end = tab.offset + tab.width();
+    let rowids = [
+        start + tab.schema.key_column.unwrap_or(tab.schema.columns.len()),
+        start + tab.schema.columns.len(),
+    ];
+
+    let mut probe_keys = Vec::new();
+    let mut build_keys = Vec::new();
+    for term in terms {
+        let (left, right) = match term {
+            BoundExpr::BoundBinary(left, BinaryOp::BinaryEq, right) => (left, right),
+            _ => continue,
+        };
+        for (inner, outer) in [(left, right), (right, left)] {
+            let on_table = matches!(column_bounds(inner), Some((low, high)) if low >= start && high < end);
+            let before_table = column_bounds(outer).is_none_or(|(_, high)| high < start);
+            if !on_table || !before_table {
+                continue;
+            }
+
+            if let BoundExpr::BoundColumn(index) = **inner {
+                if rowids.contains(&index) {
+                    return JoinStrategy::StrategyLookup((**outer).clone());
+                }
+            }
+            probe_keys.push((**outer).clone());
+            build_keys.push(shift_columns(inner, start));
+            break;
+        }
+    }
+
+    match probe_keys.is_empty() {
+        true => JoinStrategy::StrategyLoop,
+        false => JoinStrategy::StrategyHash { probe_keys, build_keys },
+    }
+}
@@ -1,6 +1,8 @@
 use std::rc::Rc;
 
+use crate::db::catalog::CatalogEntryType;
 use crate::db::tree::*;
+use crate::service::index::index_key_text;
 use crate::db::table::Table;
 use crate::db::error::{DbError, DbResult};
 
@@ -25,7 +27,26 @@ impl MetaCommandService {
             },
             ".btree;" => {
                 println!("print btree\n");
-                print_tree(&mut table.pager.borrow_mut(), 0, 0)?;
+                table.load_catalog()?;
+                for entry in table.catalog() {
+                    println!("{}:", entry.name);
+                    let key_text = match entry.entry_type {
+                        CatalogEntryType::CatalogTable => rowid_key_text,
+                        CatalogEntryType::CatalogIndex => index_key_text,
+                    };
+                    print_tree(&mut table.pager.borrow_mut(), entry.root_page_num, 0, key_text)?;
+                }
+                Ok(MetaCommandResult::MetaCmdSuccess)
+            }
+            ".checkpoint;" => {
+                table.pager.borrow_mut().checkpoint()?;
+                Ok(MetaCommandResult::MetaCmdSuccess)
+            }
+            ".schema;" => {
+                table.load_catalog()?;
+                for entry in table.catalog() {
+                    println!("{};", entry.sql);
+                }
                 Ok(MetaCommandResult::MetaCmdSuccess)
             }
             ".constants;" => {
@@ -1,18 +1,42 @@
 pub mod meta_command;
 pub mod prepare_statement;
 pub mod executor;
+pub mod tokenizer;
+pub mod ast;
+pub mod parser;
+pub mod record;
+pub mod schema;
+pub mod expression;
+pub mod sorter;
+pub mod aggregate;
+pub mod join;
+pub mod index;
 
 use std::fmt;
 
-use super::util::{zascii, str2dst};
-use crate::db::tree::{ID_OFFSET, EMAIL_OFFSET, USERNAME_OFFSET, ID_SIZE, USERNAME_SIZE, EMAIL_SIZE};
 
-use libc::{self, c_void, c_char, size_t};
+use crate::db::error::{DbError, DbResult};
+use record::{deserialize_record, serialize_record};
+use schema::Schema;
+use aggregate::AggregatePlan;
+use expression::{evaluate, BoundExpr};
+use join::JoinPlan;
+use index::{Index, IndexScan};
 
 #[derive(Debug)]
 pub enum StatementType {
     StatementInsert,
     StatementSelect,
+    StatementDelete,
+    StatementUpdate,
+    StatementCreateTable,
+    StatementCreateIndex,
+    StatementBegin,
+    StatementCommit,
+    StatementRollback,
+    StatementSavepoint,
+    StatementRelease,
+    StatementRollbackTo,
 }
 
 impl Default for StatementType {
@@ -21,137 +45,134 @@ impl Default for StatementType {
     }
 }
 
-const COLUMN_USERNAME_SIZE: usize = 32;
-const COLUMN_EMAIL_SIZE: usize = 255;
-
-// hard code table row currently.
-#[derive(Debug, Clone, Copy)]
-pub struct Row {
-    pub id: u32,
-    pub email: [c_char; COLUMN_EMAIL_SIZE + 1],
-    pub username: [c_char; COLUMN_USERNAME_SIZE + 1],
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    ValueNull,
+    ValueInteger(i64),
+    ValueReal(f64),
+    ValueText(String),
+    ValueBlob(Vec<u8>),
 }
 
-impl Default for Row {
-    fn default() -> Self {
-        Self {
-            id: 0,
-            email: [0 as c_char; COLUMN_EMAIL_SIZE + 1],
-            username: [0 as c_char; COLUMN_USERNAME_SIZE + 1],
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::ValueNull => write!(f, "NULL"),
+            Value::ValueInteger(value) => write!(f, "{}", value),
+            Value::ValueReal(value) => write!(f, "{:?}", value),
+            Value::ValueText(value) => write!(f, "{}", value),
+            Value::ValueBlob(value) => {
+                write!(f, "x'")?;
+                for byte in value {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            }
         }
     }
 }
 
+// one row of a table, a value for every column of its schema
+#[derive(Debug, Clone, Default)]
+pub struct Row {
+    pub values: Vec<Value>,
+}
+
 impl fmt::Display for Row {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        return write!(
-            f,
-            "({},{},{})",
-            self.id,
-            zascii(&self.username),
-            zascii(&self.email),
-            )
-        ;
+        let values: Vec<String> = self.values.iter().map(|value| value.to_string()).collect();
+        write!(f, "({})", values.join(","))
     }
 }
 
 impl Row {
-    fn get_id_mut_ptr(&self) -> *mut u32 {
-        unsafe {
-            ((self as *const _) as *const u8)
-                .offset(memoffset::offset_of!(Row, id) as isize)
-                as *mut u32
-        }
+    pub fn serialize_row(&self) -> Vec<u8> {
+        serialize_record(&self.values)
     }
 
-    fn get_username_mut_ptr(&self) -> *mut c_char {
-        unsafe {
-            ((self as *const _) as *const u8)
-                .offset(memoffset::offset_of!(Row, username) as isize)
-                as *mut c_char
-        }
+    /*
+    Read the record held by a cell value.
+    Columns missing from the record are NULL.
+    */
+    pub fn deserialize_row(schema: &Schema, src: &[u8]) -> DbResult<Row> {
+        let mut values = match deserialize_record(src) {
+            Some(values) if values.len() <= schema.columns.len() => values,
+            _ => return Err(DbError::Other("Record is corrupt.".to_string())),
+        };
+        values.resize(schema.columns.len(), Value::ValueNull);
+        Ok(Row { values })
     }
+}
 
-    fn get_email_mut_ptr(&self) -> *mut c_char {
-        unsafe {
-            ((self as *const _) as *const u8)
-                .offset(memoffset::offset_of!(Row, email) as isize)
-                as *mut c_char
-        }
-    }
+// inclusive range of ids matched by a where clause, empty when start > end
+#[derive(Debug, Clone, Copy)]
+pub struct KeyRange {
+    pub start: i64,
+    pub end: i64,
+}
 
-    pub fn serialize_row(&self, dst: *mut c_void) {
-        unsafe {
-            libc::memcpy(
-                (dst as *const u8)
-                    .offset(ID_OFFSET as isize)
-                    as *mut c_void,
-                self.get_id_mut_ptr()
-                    as *mut c_void,
-                ID_SIZE as size_t
-            );
-            libc::memcpy(
-                (dst as *const u8)
-                    .offset(USERNAME_OFFSET as isize)
-                    as *mut c_void,
-                self.get_username_mut_ptr()
-                    as *mut c_void,
-                USERNAME_SIZE as size_t
-            );
-            libc::memcpy(
-                (dst as *const u8)
-                    .offset(EMAIL_OFFSET as isize)
-                    as *mut c_void,
-                self.get_email_mut_ptr()
-                    as *mut c_void,
-                EMAIL_SIZE as size_t
-            );
-        }
+impl KeyRange {
+    /* True when the range holds every key */
+    pub fn is_full(&self) -> bool {
+        self.start == i64::MIN && self.end == i64::MAX
     }
+}
 
-    pub fn deserialize_row(&mut self, src: *const c_void) {
-        unsafe {
-            libc::memcpy(
-                self.get_id_mut_ptr()
-                    as *mut c_void,
-                (src as *const u8)
-                    .offset(ID_OFFSET as isize)
-                    as *const c_void,
-                ID_SIZE as size_t
-            );
-            libc::memcpy(
-                self.get_username_mut_ptr()
-                    as *mut c_void,
-                (src as *const u8)
-                    .offset(USERNAME_OFFSET as isize)
-                    as *const c_void,
-                USERNAME_SIZE as size_t
-            );
-            libc::memcpy(
-                self.get_email_mut_ptr()
-                    as *mut c_void,
-                (src as *const u8)
-                    .offset(EMAIL_OFFSET as isize)
-                    as *const c_void,
-                EMAIL_SIZE as size_t
-            );
+impl Default for KeyRange {
+    fn default() -> Self {
+        Self {
+            start: i64::MIN,
+            end: i64::MAX,
         }
     }
+}
 
-    pub fn set_username(&mut self, username: &str) {
-            let r_username_ptr = self.get_username_mut_ptr();
-            let _ = str2dst(r_username_ptr, username);
-    }
+// new column values assigned by the set clause of an update statement
+#[derive(Default, Debug)]
+pub struct RowUpdate {
+    pub values: Vec<(usize, BoundExpr)>, // column index and its new value, bound to the columns and the rowid
+}
 
-    pub fn set_email(&mut self, email: &str) {
-            let r_email_ptr = self.get_email_mut_ptr();
-            let _ = str2dst(r_email_ptr, email);
+impl RowUpdate {
+    /*
+    Set the new values of a row, computed from its old values and
+    converted to the types of their columns. The reason is returned
+    when one does not fit its column.
+    */
+    pub fn apply(&self, schema: &Schema, row: &mut Row, rowid: i64) -> Result<(), String> {
+        let mut old_values = row.values.clone();
+        old_values.push(Value::ValueInteger(rowid));
+        for (index, expr) in &self.values {
+            let column = &schema.columns[*index];
+            let value = column.convert_value(evaluate(expr, &old_values))?;
+            if value == Value::ValueNull && schema.key_column == Some(*index) {
+                return Err(format!("{} must be an integer", column.name));
+            } else if value == Value::ValueNull && column.not_null {
+                return Err(format!("NOT NULL constraint failed: {}.{}", schema.name, column.name));
+            }
+            row.values[*index] = value;
+        }
+        Ok(())
     }
 }
+
 #[derive(Default, Debug)]
 pub struct Statement {
     stmt_type: StatementType,
-    row_to_insert: Row, // only insert by insert statement
+    schema: Schema, // of the table the statement works on, or creates, the first one of a join
+    rows_to_insert: Vec<Row>, // only insert by insert statement
+    key_range: KeyRange, // only used by statements with a where clause
+    index_scan: Option<IndexScan>, // only used by select statements read through an index
+    filter: Option<BoundExpr>, // only used by select statements with a where clause
+    joins: Vec<JoinPlan>, // tables a select joins to the one in schema, in order
+    projection: Vec<(String, BoundExpr)>, // name and value of each result column of a select, on group rows when it aggregates
+    aggregate: Option<AggregatePlan>, // only used by select statements with aggregates or a group by
+    order_by: Vec<(BoundExpr, bool)>, // sort keys of a select and whether each is descending
+    limit: Option<u64>, // rows a select returns at most, None for all
+    offset: u64, // rows a select skips before returning any
+    row_update: RowUpdate, // only update by update statement
+    index: Option<Index>, // only used by create index statements
+    savepoint_name: String, // only used by savepoint statements
 }
 
 impl Statement {
@@ -0,0 +1,789 @@
+use std::fmt;
+
+use crate::service::ast::*;
+use crate::service::tokenizer::{tokenize, Keyword, Position, Token, TokenKind, TokenizeError};
+use crate::service::tokenizer::Keyword::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub pos: Position,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.pos.line, self.pos.column, self.message)
+    }
+}
+
+impl From<TokenizeError> for ParseError {
+    fn from(err: TokenizeError) -> Self {
+        Self {
+            pos: err.pos,
+            message: err.message,
+        }
+    }
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+/*
+Parse every statement of the input. Statements are separated by
+semicolons, the last one may go without.
+*/
+pub fn parse(sql: &str) -> ParseResult<Vec<Stmt>> {
+    let tokens = tokenize(sql)?;
+    let mut parser = Parser::new(sql, &tokens);
+
+    let mut stmts = Vec::new();
+    loop {
+        while parser.eat(&TokenKind::TokenSemicolon) {}
+        if parser.peek() == &TokenKind::TokenEof {
+            return Ok(stmts);
+        }
+        stmts.push(parser.parse_stmt()?);
+        if parser.peek() != &TokenKind::TokenEof {
+            parser.expect(&TokenKind::TokenSemicolon)?;
+        }
+    }
+}
+
+/*
+Recursive-descent parser over the token list, one method per grammar
+rule. The token list always ends with TokenEof, which is never consumed.
+*/
+struct Parser<'a> {
+    sql: &'a str,
+    tokens: &'a [Token],
+    index: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(sql: &'a str, tokens: &'a [Token]) -> Self {
+        Self { sql, tokens, index: 0 }
+    }
+
+    /* Input text from the token at start up to the current token */
+    fn source(&self, start: usize) -> String {
+        let begin = self.tokens[start].offset;
+        let end = self.tokens[self.index].offset;
+        let text: String = self.sql.chars().skip(begin).take(end - begin).collect();
+        text.trim_end().to_owned()
+    }
+
+    fn peek(&self) -> &'a TokenKind {
+        &self.tokens[self.index].kind
+    }
+
+    fn peek_at(&self, ahead: usize) -> &'a TokenKind {
+        let index = (self.index + ahead).min(self.tokens.len() - 1);
+        &self.tokens[index].kind
+    }
+
+    fn advance(&mut self) -> &'a TokenKind {
+        let kind = &self.tokens[self.index].kind;
+        if self.index + 1 < self.tokens.len() {
+            self.index += 1;
+        }
+        kind
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.peek() == kind {
+            self.advance();
+            return true;
+        }
+        false
+    }
+
+    fn eat_keyword(&mut self, keyword: Keyword) -> bool {
+        self.eat(&TokenKind::TokenKeyword(keyword))
+    }
+
+    /* Error at the current token naming what the grammar wanted there */
+    fn expected<T>(&self, what: &str) -> ParseResult<T> {
+        let found = match self.peek() {
+            TokenKind::TokenEof => "end of input".to_owned(),
+            TokenKind::TokenString(_) => format!("{}", self.peek()),
+            kind => format!("'{}'", kind),
+        };
+        Err(ParseError {
+            pos: self.tokens[self.index].pos,
+            message: format!("expected {}, found {}", what, found),
+        })
+    }
+
+    fn expect(&mut self, kind: &TokenKind) -> ParseResult<()> {
+        if self.eat(kind) {
+            return Ok(());
+        }
+        self.expected(&format!("'{}'", kind))
+    }
+
+    fn expect_keyword(&mut self, keyword: Keyword) -> ParseResult<()> {
+        if self.eat_keyword(keyword) {
+            return Ok(());
+        }
+        self.expected(&keyword.as_str().to_uppercase())
+    }
+
+    fn expect_identifier(&mut self) -> ParseResult<String> {
+        match self.peek() {
+            TokenKind::TokenIdentifier(name) => {
+                self.advance();
+                Ok(name.clone())
+            }
+            _ => self.expected("identifier"),
+        }
+    }
+
+    /* A comma separated list of at least one item */
+    fn parse_list<T>(&mut self, mut parse_item: impl FnMut(&mut Self) -> ParseResult<T>) -> ParseResult<Vec<T>> {
+        let mut items = vec![parse_item(self)?];
+        while self.eat(&TokenKind::TokenComma) {
+            items.push(parse_item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn parse_stmt(&mut self) -> ParseResult<Stmt> {
+        match self.peek() {
+            TokenKind::TokenKeyword(KeywordSelect) => Ok(Stmt::StmtSelect(Box::new(self.parse_select()?))),
+            TokenKind::TokenKeyword(KeywordInsert) => Ok(Stmt::StmtInsert(self.parse_insert()?)),
+            TokenKind::TokenKeyword(KeywordUpdate) => Ok(Stmt::StmtUpdate(self.parse_update()?)),
+            TokenKind::TokenKeyword(KeywordDelete) => Ok(Stmt::StmtDelete(self.parse_delete()?)),
+            TokenKind::TokenKeyword(KeywordCreate) => self.parse_create(),
+            TokenKind::TokenKeyword(
+                KeywordBegin | KeywordCommit | KeywordRollback | KeywordSavepoint | KeywordRelease,
+            ) => self.parse_transaction(),
+            _ => self.expected("statement"),
+        }
+    }
+
+    /*
+    begin [transaction], commit [transaction], rollback [transaction],
+    savepoint <name>, release [savepoint] <name> and
+    rollback [transaction] to [savepoint] <name>.
+    */
+    fn parse_transaction(&mut self) -> ParseResult<Stmt> {
+        match self.advance() {
+            TokenKind::TokenKeyword(KeywordBegin) => {
+                self.eat_keyword(KeywordTransaction);
+                Ok(Stmt::StmtBegin)
+            }
+            TokenKind::TokenKeyword(KeywordCommit) => {
+                self.eat_keyword(KeywordTransaction);
+                Ok(Stmt::StmtCommit)
+            }
+            TokenKind::TokenKeyword(KeywordRollback) => {
+                self.eat_keyword(KeywordTransaction);
+                if !self.eat_keyword(KeywordTo) {
+                    return Ok(Stmt::StmtRollback);
+                }
+                self.eat_keyword(KeywordSavepoint);
+                Ok(Stmt::StmtRollbackTo(self.expect_identifier()?))
+            }
+            TokenKind::TokenKeyword(KeywordSavepoint) => Ok(Stmt::StmtSavepoint(self.expect_identifier()?)),
+            TokenKind::TokenKeyword(KeywordRelease) => {
+                self.eat_keyword(KeywordSavepoint);
+                Ok(Stmt::StmtRelease(self.expect_identifier()?))
+            }
+            _ => self.expected("statement"),
+        }
+    }
+
+    /*
+    select <columns> [from ...] [where ...] [group by ... [having ...]]
+    [order by ...] [limit n [offset m]]. A bare `select` lists every
+    row of the users table.
+    */
+    fn parse_select(&mut self) -> ParseResult<SelectStmt> {
+        self.expect_keyword(KeywordSelect)?;
+
+        if matches!(self.peek(), TokenKind::TokenSemicolon | TokenKind::TokenEof) {
+            return Ok(SelectStmt {
+                core: SelectCore {
+                    columns: vec![ResultColumn::ResultStar],
+                    from: None,
+                    where_clause: None,
+                    group_by: Vec::new(),
+                    having: None,
+                },
+                order_by: Vec::new(),
+                limit: None,
+                offset: None,
+            });
+        }
+
+        let columns = self.parse_list(Self::parse_result_column)?;
+        let from = match self.eat_keyword(KeywordFrom) {
+            true => Some(self.parse_from()?),
+            false => None,
+        };
+        let where_clause = match self.eat_keyword(KeywordWhere) {
+            true => Some(self.parse_expr()?),
+            false => None,
+        };
+        let mut group_by = Vec::new();
+        let mut having = None;
+        if self.eat_keyword(KeywordGroup) {
+            self.expect_keyword(KeywordBy)?;
+            group_by = self.parse_list(Self::parse_expr)?;
+            if self.eat_keyword(KeywordHaving) {
+                having = Some(self.parse_expr()?);
+            }
+        }
+
+        let mut order_by = Vec::new();
+        if self.eat_keyword(KeywordOrder) {
+            self.expect_keyword(KeywordBy)?;
+            order_by = self.parse_list(Self::parse_ordering_term)?;
+        }
+
+        let mut limit = None;
+        let mut offset = None;
+        if self.eat_keyword(KeywordLimit) {
+            let first = self.parse_expr()?;
+            if self.eat_keyword(KeywordOffset) {
+                limit = Some(first);
+                offset = Some(self.parse_expr()?);
+            } else if self.eat(&TokenKind::TokenComma) {
+                // limit <offset>, <count>
+                offset = Some(first);
+                limit = Some(self.parse_expr()?);
+            } else {
+                limit = Some(first);
+            }
+        }
+
+        Ok(SelectStmt {
+            core: SelectCore {
+                columns,
+                from,
+                where_clause,
+                group_by,
+                having,
+            },
+            order_by,
+            limit,
+            offset,
+        })
+    }
+
+    fn parse_result_column(&mut self) -> ParseResult<ResultColumn> {
+        if self.eat(&TokenKind::TokenStar) {
+            return Ok(ResultColumn::ResultStar);
+        }
+        if let (TokenKind::TokenIdentifier(table), TokenKind::TokenDot, TokenKind::TokenStar) =
+            (self.peek(), self.peek_at(1), self.peek_at(2))
+        {
+            self.advance();
+            self.advance();
+            self.advance();
+            return Ok(ResultColumn::ResultTableStar(table.clone()));
+        }
+
+        let expr = self.parse_expr()?;
+        Ok(ResultColumn::ResultExpr(expr, self.parse_alias()?))
+    }
+
+    /* [as] <name> after a result column or table */
+    fn parse_alias(&mut self) -> ParseResult<Option<String>> {
+        if self.eat_keyword(KeywordAs) {
+            return Ok(Some(self.expect_identifier()?));
+        }
+        if let TokenKind::TokenIdentifier(alias) = self.peek() {
+            self.advance();
+            return Ok(Some(alias.clone()));
+        }
+        Ok(None)
+    }
+
+    fn parse_table_ref(&mut self) -> ParseResult<TableRef> {
+        let name = self.expect_identifier()?;
+        let alias = self.parse_alias()?;
+        Ok(TableRef { name, alias })
+    }
+
+    /* <table> followed by any number of joins, a comma is a cross join */
+    fn parse_from(&mut self) -> ParseResult<FromClause> {
+        let table = self.parse_table_ref()?;
+        let mut joins = Vec::new();
+        loop {
+            let kind = if self.eat(&TokenKind::TokenComma) {
+                JoinKind::JoinCross
+            } else if self.eat_keyword(KeywordJoin) {
+                JoinKind::JoinInner
+            } else if self.eat_keyword(KeywordInner) {
+                self.expect_keyword(KeywordJoin)?;
+                JoinKind::JoinInner
+            } else if self.eat_keyword(KeywordLeft) {
+                self.eat_keyword(KeywordOuter);
+                self.expect_keyword(KeywordJoin)?;
+                JoinKind::JoinLeft
+            } else if self.eat_keyword(KeywordCross) {
+                self.expect_keyword(KeywordJoin)?;
+                JoinKind::JoinCross
+            } else {
+                return Ok(FromClause { table, joins });
+            };
+
+            let table = self.parse_table_ref()?;
+            let constraint = if self.eat_keyword(KeywordOn) {
+                JoinConstraint::JoinOn(self.parse_expr()?)
+            } else if self.eat_keyword(KeywordUsing) {
+                self.expect(&TokenKind::TokenLeftParen)?;
+                let columns = self.parse_list(Self::expect_identifier)?;
+                self.expect(&TokenKind::TokenRightParen)?;
+                JoinConstraint::JoinUsing(columns)
+            } else {
+                JoinConstraint::JoinNone
+            };
+            joins.push(Join {
+                kind,
+                table,
+                constraint,
+            });
+        }
+    }
+
+    fn parse_ordering_term(&mut self) -> ParseResult<OrderingTerm> {
+        let expr = self.parse_expr()?;
+        let desc = if self.eat_keyword(KeywordDesc) {
+            true
+        } else {
+            self.eat_keyword(KeywordAsc);
+            false
+        };
+        Ok(OrderingTerm { expr, desc })
+    }
+
+    /*
+    insert into <table> [(<columns>)] values (<exprs>)[, ...],
+    or the users table shorthand insert <id> <username> <email>.
+    */
+    fn parse_insert(&mut self) -> ParseResult<InsertStmt> {
+        self.expect_keyword(KeywordInsert)?;
+
+        if !self.eat_keyword(KeywordInto) {
+            let row = vec![
+                self.parse_shorthand_value()?,
+                self.parse_shorthand_value()?,
+                self.parse_shorthand_value()?,
+            ];
+            return Ok(InsertStmt {
+                table: None,
+                columns: None,
+                rows: vec![row],
+            });
+        }
+
+        let table = self.expect_identifier()?;
+        let mut columns = None;
+        if self.eat(&TokenKind::TokenLeftParen) {
+            columns = Some(self.parse_list(Self::expect_identifier)?);
+            self.expect(&TokenKind::TokenRightParen)?;
+        }
+
+        self.expect_keyword(KeywordValues)?;
+        let rows = self.parse_list(|parser| {
+            parser.expect(&TokenKind::TokenLeftParen)?;
+            let values = parser.parse_list(Self::parse_expr)?;
+            parser.expect(&TokenKind::TokenRightParen)?;
+            Ok(values)
+        })?;
+
+        Ok(InsertStmt {
+            table: Some(table),
+            columns,
+            rows,
+        })
+    }
+
+    /* Values of the insert shorthand, bare words are taken as text */
+    fn parse_shorthand_value(&mut self) -> ParseResult<Expr> {
+        if let TokenKind::TokenIdentifier(word) = self.peek() {
+            self.advance();
+            return Ok(Expr::ExprLiteral(Literal::LiteralString(word.clone())));
+        }
+        if self.eat(&TokenKind::TokenMinus) {
+            let value = self.parse_primary()?;
+            return Ok(Expr::ExprUnary(UnaryOp::UnaryNeg, Box::new(value)));
+        }
+        match self.peek() {
+            TokenKind::TokenInteger(_) | TokenKind::TokenFloat(_) | TokenKind::TokenString(_) => {
+                self.parse_primary()
+            }
+            _ => self.expected("value"),
+        }
+    }
+
+    // update <table> set <column> = <expr>[, ...] [where <expr>]
+    fn parse_update(&mut self) -> ParseResult<UpdateStmt> {
+        self.expect_keyword(KeywordUpdate)?;
+        let table = self.expect_identifier()?;
+        self.expect_keyword(KeywordSet)?;
+
+        let assignments = self.parse_list(|parser| {
+            let column = parser.expect_identifier()?;
+            parser.expect(&TokenKind::TokenEq)?;
+            Ok((column, parser.parse_expr()?))
+        })?;
+        let where_clause = match self.eat_keyword(KeywordWhere) {
+            true => Some(self.parse_expr()?),
+            false => None,
+        };
+
+        Ok(UpdateStmt {
+            table,
+            assignments,
+            where_clause,
+        })
+    }
+
+    // delete [from <table>] [where <expr>]
+    fn parse_delete(&mut self) -> ParseResult<DeleteStmt> {
+        self.expect_keyword(KeywordDelete)?;
+        let table = match self.eat_keyword(KeywordFrom) {
+            true => Some(self.expect_identifier()?),
+            false => None,
+        };
+        let where_clause = match self.eat_keyword(KeywordWhere) {
+            true => Some(self.parse_expr()?),
+            false => None,
+        };
+        Ok(DeleteStmt {
+            table,
+            where_clause,
+        })
+    }
+
+    fn parse_create(&mut self) -> ParseResult<Stmt> {
+        let start = self.index;
+        self.expect_keyword(KeywordCreate)?;
+        if self.eat_keyword(KeywordTable) {
+            return Ok(Stmt::StmtCreateTable(self.parse_create_table(start)?));
+        }
+        let unique = self.eat_keyword(KeywordUnique);
+        if self.eat_keyword(KeywordIndex) {
+            return Ok(Stmt::StmtCreateIndex(self.parse_create_index(start, unique)?));
+        }
+        match unique {
+            true => self.expected("INDEX"),
+            false => self.expected("TABLE or INDEX"),
+        }
+    }
+
+    // create table <name> (<column> [<type>] [<constraint> ...], ...)
+    fn parse_create_table(&mut self, start: usize) -> ParseResult<CreateTableStmt> {
+        let name = self.expect_identifier()?;
+        self.expect(&TokenKind::TokenLeftParen)?;
+        let columns = self.parse_list(Self::parse_column_def)?;
+        self.expect(&TokenKind::TokenRightParen)?;
+        Ok(CreateTableStmt {
+            name,
+            columns,
+            sql: self.source(start),
+        })
+    }
+
+    fn parse_column_def(&mut self) -> ParseResult<ColumnDef> {
+        let name = self.expect_identifier()?;
+
+        /* Type names are plain words, optionally sized: varchar(255) */
+        let mut words = Vec::new();
+        while let TokenKind::TokenIdentifier(word) = self.peek() {
+            self.advance();
+            words.push(word.clone());
+        }
+        let mut type_name = match words.is_empty() {
+            true => None,
+            false => Some(words.join(" ")),
+        };
+        if type_name.is_some() && self.eat(&TokenKind::TokenLeftParen) {
+            let sizes = self.parse_list(|parser| {
+                let negative = parser.eat(&TokenKind::TokenMinus);
+                match parser.peek() {
+                    TokenKind::TokenInteger(size) => {
+                        parser.advance();
+                        Ok(if negative { -size } else { *size })
+                    }
+                    _ => parser.expected("type size"),
+                }
+            })?;
+            self.expect(&TokenKind::TokenRightParen)?;
+            let sizes: Vec<String> = sizes.iter().map(|size| size.to_string()).collect();
+            type_name = type_name.map(|name| format!("{}({})", name, sizes.join(",")));
+        }
+
+        let mut column = ColumnDef {
+            name,
+            type_name,
+            primary_key: false,
+            not_null: false,
+            unique: false,
+            collation: None,
+        };
+        loop {
+            if self.eat_keyword(KeywordPrimary) {
+                self.expect_keyword(KeywordKey)?;
+                if !self.eat_keyword(KeywordAsc) {
+                    self.eat_keyword(KeywordDesc);
+                }
+                column.primary_key = true;
+            } else if self.eat_keyword(KeywordNot) {
+                self.expect_keyword(KeywordNull)?;
+                column.not_null = true;
+            } else if self.eat_keyword(KeywordNull) {
+                column.not_null = false;
+            } else if self.eat_keyword(KeywordUnique) {
+                column.unique = true;
+            } else if self.eat_keyword(KeywordCollate) {
+                column.collation = Some(self.expect_identifier()?);
+            } else {
+                return Ok(column);
+            }
+        }
+    }
+
+    // create [unique] index <name> on <table> (<column> [collate <name>] [asc|desc], ...)
+    fn parse_create_index(&mut self, start: usize, unique: bool) -> ParseResult<CreateIndexStmt> {
+        let name = self.expect_identifier()?;
+        self.expect_keyword(KeywordOn)?;
+        let table = self.expect_identifier()?;
+        self.expect(&TokenKind::TokenLeftParen)?;
+        let columns = self.parse_list(|parser| {
+            let name = parser.expect_identifier()?;
+            let collation = match parser.eat_keyword(KeywordCollate) {
+                true => Some(parser.expect_identifier()?),
+                false => None,
+            };
+            let desc = parser.eat_keyword(KeywordDesc);
+            if !desc {
+                parser.eat_keyword(KeywordAsc);
+            }
+            Ok(IndexedColumn {
+                name,
+                collation,
+                desc,
+            })
+        })?;
+        self.expect(&TokenKind::TokenRightParen)?;
+
+        Ok(CreateIndexStmt {
+            name,
+            table,
+            columns,
+            unique,
+            sql: self.source(start),
+        })
+    }
+
+    /*
+    Expressions, loosest binding first:
+    OR, AND, NOT, = != IS BETWEEN, < <= > >=, + -, * / %, ||,
+    unary - +, COLLATE.
+    */
+    pub fn parse_expr(&mut self) -> ParseResult<Expr> {
+        self.parse_or()
+    }
+
+    fn parse_or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.eat_keyword(KeywordOr) {
+            let right = self.parse_and()?;
+            expr = Expr::ExprBinary(Box::new(expr), BinaryOp::BinaryOr, Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_not()?;
+        while self.eat_keyword(KeywordAnd) {
+            let right = self.parse_not()?;
+            expr = Expr::ExprBinary(Box::new(expr), BinaryOp::BinaryAnd, Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> ParseResult<Expr> {
+        if self.eat_keyword(KeywordNot) {
+            let expr = self.parse_not()?;
+            return Ok(Expr::ExprUnary(UnaryOp::UnaryNot, Box::new(expr)));
+        }
+        self.parse_equality()
+    }
+
+    fn parse_equality(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_comparison()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::TokenEq => BinaryOp::BinaryEq,
+                TokenKind::TokenNotEq => BinaryOp::BinaryNotEq,
+                TokenKind::TokenKeyword(KeywordIs) if self.peek_at(1) == &TokenKind::TokenKeyword(KeywordNot) => {
+                    self.advance();
+                    BinaryOp::BinaryIsNot
+                }
+                TokenKind::TokenKeyword(KeywordIs) => BinaryOp::BinaryIs,
+                TokenKind::TokenKeyword(KeywordBetween) => {
+                    expr = self.parse_between(expr, false)?;
+                    continue;
+                }
+                TokenKind::TokenKeyword(KeywordNot)
+                    if self.peek_at(1) == &TokenKind::TokenKeyword(KeywordBetween) =>
+                {
+                    self.advance();
+                    expr = self.parse_between(expr, true)?;
+                    continue;
+                }
+                _ => return Ok(expr),
+            };
+            self.advance();
+            let right = self.parse_comparison()?;
+            expr = Expr::ExprBinary(Box::new(expr), op, Box::new(right));
+        }
+    }
+
+    fn parse_between(&mut self, expr: Expr, negated: bool) -> ParseResult<Expr> {
+        self.expect_keyword(KeywordBetween)?;
+        let low = self.parse_comparison()?;
+        self.expect_keyword(KeywordAnd)?;
+        let high = self.parse_comparison()?;
+        Ok(Expr::ExprBetween {
+            expr: Box::new(expr),
+            low: Box::new(low),
+            high: Box::new(high),
+            negated,
+        })
+    }
+
+    fn parse_comparison(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_additive()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::TokenLt => BinaryOp::BinaryLt,
+                TokenKind::TokenLtEq => BinaryOp::BinaryLtEq,
+                TokenKind::TokenGt => BinaryOp::BinaryGt,
+                TokenKind::TokenGtEq => BinaryOp::BinaryGtEq,
+                _ => return Ok(expr),
+            };
+            self.advance();
+            let right = self.parse_additive()?;
+            expr = Expr::ExprBinary(Box::new(expr), op, Box::new(right));
+        }
+    }
+
+    fn parse_additive(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::TokenPlus => BinaryOp::BinaryAdd,
+                TokenKind::TokenMinus => BinaryOp::BinarySub,
+                _ => return Ok(expr),
+            };
+            self.advance();
+            let right = self.parse_multiplicative()?;
+            expr = Expr::ExprBinary(Box::new(expr), op, Box::new(right));
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_concat()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::TokenStar => BinaryOp::BinaryMul,
+                TokenKind::TokenSlash => BinaryOp::BinaryDiv,
+                TokenKind::TokenPercent => BinaryOp::BinaryMod,
+                _ => return Ok(expr),
+            };
+            self.advance();
+            let right = self.parse_concat()?;
+            expr = Expr::ExprBinary(Box::new(expr), op, Box::new(right));
+        }
+    }
+
+    fn parse_concat(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_unary()?;
+        while self.eat(&TokenKind::TokenConcat) {
+            let right = self.parse_unary()?;
+            expr = Expr::ExprBinary(Box::new(expr), BinaryOp::BinaryConcat, Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> ParseResult<Expr> {
+        let op = match self.peek() {
+            TokenKind::TokenMinus => UnaryOp::UnaryNeg,
+            TokenKind::TokenPlus => UnaryOp::UnaryPlus,
+            _ => return self.parse_collate(),
+        };
+        self.advance();
+        let expr = self.parse_unary()?;
+        Ok(Expr::ExprUnary(op, Box::new(expr)))
+    }
+
+    fn parse_collate(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_primary()?;
+        while self.eat_keyword(KeywordCollate) {
+            expr = Expr::ExprCollate(Box::new(expr), self.expect_identifier()?);
+        }
+        Ok(expr)
+    }
+
+    /* Literal, column reference, function call or parenthesized expression */
+    fn parse_primary(&mut self) -> ParseResult<Expr> {
+        let literal = match self.peek() {
+            TokenKind::TokenInteger(value) => Literal::LiteralInteger(*value),
+            TokenKind::TokenFloat(value) => Literal::LiteralFloat(*value),
+            TokenKind::TokenString(value) => Literal::LiteralString(value.clone()),
+            TokenKind::TokenBlob(value) => Literal::LiteralBlob(value.clone()),
+            TokenKind::TokenKeyword(KeywordNull) => Literal::LiteralNull,
+            TokenKind::TokenLeftParen => {
+                self.advance();
+                let expr = self.parse_expr()?;
+                self.expect(&TokenKind::TokenRightParen)?;
+                return Ok(expr);
+            }
+            TokenKind::TokenIdentifier(name) => {
+                self.advance();
+                return self.parse_name(name.clone());
+            }
+            _ => return self.expected("expression"),
+        };
+        self.advance();
+        Ok(Expr::ExprLiteral(literal))
+    }
+
+    /* What follows an identifier: a call, a qualified column or nothing */
+    fn parse_name(&mut self, name: String) -> ParseResult<Expr> {
+        if self.eat(&TokenKind::TokenLeftParen) {
+            if self.eat(&TokenKind::TokenStar) {
+                self.expect(&TokenKind::TokenRightParen)?;
+                return Ok(Expr::ExprFunction {
+                    name,
+                    args: Vec::new(),
+                    star: true,
+                });
+            }
+            let mut args = Vec::new();
+            if !self.eat(&TokenKind::TokenRightParen) {
+                args = self.parse_list(Self::parse_expr)?;
+                self.expect(&TokenKind::TokenRightParen)?;
+            }
+            return Ok(Expr::ExprFunction {
+                name,
+                args,
+                star: false,
+            });
+        }
+
+        if self.eat(&TokenKind::TokenDot) {
+            let column = self.expect_identifier()?;
+            return Ok(Expr::ExprColumn {
+                table: Some(name),
+                name: column,
+            });
+        }
+        Ok(Expr::ExprColumn { table: None, name })
+    }
+}
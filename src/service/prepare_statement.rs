@@ -1,28 +1,38 @@
 use std::fmt;
+use std::rc::Rc;
 
-use crate::service::{Statement, StatementType};
-use crate::service::{COLUMN_EMAIL_SIZE, COLUMN_USERNAME_SIZE};
+use crate::db::error::{DbError, DbResult};
+use crate::db::catalog::{CatalogEntry, CatalogEntryType};
+use crate::db::table::Table;
+use crate::service::{KeyRange, Row, RowUpdate, Statement, StatementType, Value};
+use crate::service::ast::*;
+use crate::service::parser::{parse, ParseError};
+use crate::service::schema::Schema;
+use crate::service::aggregate::{contains_aggregate, AggregateBinder};
+use crate::service::expression::{bind_expr, literal_value, BoundExpr, TableScope};
+use crate::service::join::{plan_join, split_conjuncts, JoinPlan};
+use crate::service::index::{plan_index_scan, Index};
 
 #[derive(Debug)]
 pub enum PrepareResult {
     PrepareSuccess,
-    PrepareNegativeId,
-    PrepareStringTooLong,
-    PrepareSyntaxError,
-    PrepareUnrecognizeStmt(String),
+    PrepareSyntaxError(ParseError),
+    PrepareNoSuchTable(String),
+    PrepareNoSuchColumn(String),
+    PrepareInvalid(String),
+    PrepareUnsupported(&'static str),
 }
 
 impl fmt::Display for PrepareResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             PrepareResult::PrepareSuccess => write!(f, "Successfully prepared!"),
-            PrepareResult::PrepareNegativeId => write!(f, "ID must be positive."),
-            PrepareResult::PrepareStringTooLong => write!(f, "String is too long."),
-            PrepareResult::PrepareSyntaxError => {
-                write!(f, "Syntax error. Could not parse statement.")
-            }
-            PrepareResult::PrepareUnrecognizeStmt(stmt) => {
-                write!(f, "Unrecognized keyword at start of \'{}\'.", stmt)
+            PrepareResult::PrepareSyntaxError(err) => write!(f, "Syntax error at {}.", err),
+            PrepareResult::PrepareNoSuchTable(name) => write!(f, "Error: No such table: {}.", name),
+            PrepareResult::PrepareNoSuchColumn(name) => write!(f, "Error: No such column: {}.", name),
+            PrepareResult::PrepareInvalid(reason) => write!(f, "Error: {}.", reason),
+            PrepareResult::PrepareUnsupported(feature) => {
+                write!(f, "Error: {} is not supported yet.", feature)
             }
         }
     }
@@ -35,57 +45,622 @@ impl PrepareService {
         Self {}
     }
 
-    pub fn prepare_statement(&self, line: &str, stmt: &mut Statement) -> PrepareResult {
-        if line.starts_with("insert") {
-            return self.prepare_insert(line, stmt);
+    pub fn parse_statements(&self, line: &str) -> Result<Vec<Stmt>, PrepareResult> {
+        parse(line).map_err(PrepareResult::PrepareSyntaxError)
+    }
+
+    /*
+    Check a parsed statement against the catalog and lower it to a
+    Statement. The catalog is read again for every statement, the one
+    before may have created a table.
+    */
+    pub fn prepare_statement(&self, node: &Stmt, table: Rc<Table>,
+        stmt: &mut Statement) -> DbResult<PrepareResult> {
+        table.load_catalog()?;
+        let catalog = table.catalog();
+        for entry in &catalog {
+            let loaded = match entry.entry_type {
+                CatalogEntryType::CatalogTable => Schema::load(entry).is_some(),
+                CatalogEntryType::CatalogIndex => catalog
+                    .iter()
+                    .find(|table| table.entry_type == CatalogEntryType::CatalogTable && table.name == entry.table_name)
+                    .and_then(Schema::load)
+                    .and_then(|schema| Index::load(entry, &schema))
+                    .is_some(),
+            };
+            if !loaded {
+                return Err(DbError::Other(format!("Schema of {} is corrupt.", entry.name)));
+            }
         }
 
-        if line.starts_with("select") {
-            stmt.stmt_type = StatementType::StatementSelect;
-            return PrepareResult::PrepareSuccess;
+        match self.lower_statement(node, &catalog, stmt) {
+            Ok(()) => Ok(PrepareResult::PrepareSuccess),
+            Err(res) => Ok(res),
         }
+    }
 
-        return PrepareResult::PrepareUnrecognizeStmt(line.to_string());
+    fn lower_statement(&self, node: &Stmt, catalog: &[CatalogEntry],
+        stmt: &mut Statement) -> Result<(), PrepareResult> {
+        match node {
+            Stmt::StmtSelect(select) => self.prepare_select(select, catalog, stmt),
+            Stmt::StmtInsert(insert) => self.prepare_insert(insert, catalog, stmt),
+            Stmt::StmtUpdate(update) => self.prepare_update(update, catalog, stmt),
+            Stmt::StmtDelete(delete) => self.prepare_delete(delete, catalog, stmt),
+            Stmt::StmtCreateTable(create) => self.prepare_create_table(create, catalog, stmt),
+            Stmt::StmtCreateIndex(create) => self.prepare_create_index(create, catalog, stmt),
+            Stmt::StmtBegin => {
+                stmt.stmt_type = StatementType::StatementBegin;
+                Ok(())
+            }
+            Stmt::StmtCommit => {
+                stmt.stmt_type = StatementType::StatementCommit;
+                Ok(())
+            }
+            Stmt::StmtRollback => {
+                stmt.stmt_type = StatementType::StatementRollback;
+                Ok(())
+            }
+            Stmt::StmtSavepoint(name) => {
+                stmt.stmt_type = StatementType::StatementSavepoint;
+                stmt.savepoint_name = name.clone();
+                Ok(())
+            }
+            Stmt::StmtRelease(name) => {
+                stmt.stmt_type = StatementType::StatementRelease;
+                stmt.savepoint_name = name.clone();
+                Ok(())
+            }
+            Stmt::StmtRollbackTo(name) => {
+                stmt.stmt_type = StatementType::StatementRollbackTo;
+                stmt.savepoint_name = name.clone();
+                Ok(())
+            }
+        }
     }
 
-    fn prepare_insert(&self, line: &str, stmt: &mut Statement) -> PrepareResult {
-        stmt.stmt_type = StatementType::StatementInsert;
+    fn prepare_create_table(&self, create: &CreateTableStmt, catalog: &[CatalogEntry],
+        stmt: &mut Statement) -> Result<(), PrepareResult> {
+        stmt.stmt_type = StatementType::StatementCreateTable;
+
+        /* Tables and indexes share one namespace */
+        if let Some(entry) = catalog.iter().find(|entry| entry.name.eq_ignore_ascii_case(&create.name)) {
+            return Err(PrepareResult::PrepareInvalid(match entry.entry_type {
+                CatalogEntryType::CatalogTable => format!("table {} already exists", create.name),
+                CatalogEntryType::CatalogIndex => format!("there is already an index named {}", create.name),
+            }));
+        }
 
-        let line_partition: Vec<&str> = line.split(' ').collect();
+        stmt.schema = Schema::from_create_table(create)?;
+        Ok(())
+    }
+
+    fn prepare_create_index(&self, create: &CreateIndexStmt, catalog: &[CatalogEntry],
+        stmt: &mut Statement) -> Result<(), PrepareResult> {
+        stmt.stmt_type = StatementType::StatementCreateIndex;
 
-        if line_partition.len() != 4 {
-            return PrepareResult::PrepareSyntaxError;
+        if let Some(entry) = catalog.iter().find(|entry| entry.name.eq_ignore_ascii_case(&create.name)) {
+            return Err(PrepareResult::PrepareInvalid(match entry.entry_type {
+                CatalogEntryType::CatalogTable => format!("there is already a table named {}", create.name),
+                CatalogEntryType::CatalogIndex => format!("index {} already exists", create.name),
+            }));
         }
 
-        let id = match line_partition[1].parse::<i32>() {
-            Ok(id_num) => {
-                if id_num < 0 {
-                    return PrepareResult::PrepareNegativeId;
+        let schema = self.resolve_table(Some(&create.table), catalog)?;
+        stmt.index = Some(Index::from_create_index(create, &schema)?);
+        stmt.schema = schema;
+        Ok(())
+    }
+
+    /*
+    Schema of the table a statement names, with its indexes. Statements
+    written without a table, like `insert 1 a b`, work on the table of a
+    database that holds only one.
+    */
+    fn resolve_table(&self, name: Option<&str>, catalog: &[CatalogEntry]) -> Result<Schema, PrepareResult> {
+        let mut tables = catalog
+            .iter()
+            .filter(|entry| entry.entry_type == CatalogEntryType::CatalogTable);
+        let entry = match name {
+            Some(name) => tables.find(|entry| entry.name.eq_ignore_ascii_case(name)),
+            None => match (tables.next(), tables.next()) {
+                (Some(entry), None) => Some(entry),
+                _ => return Err(PrepareResult::PrepareInvalid("no table specified".to_owned())),
+            },
+        };
+        let mut schema = match entry.and_then(Schema::load) {
+            Some(schema) => schema,
+            None => return Err(PrepareResult::PrepareNoSuchTable(name.unwrap_or_default().to_owned())),
+        };
+        schema.indexes = catalog
+            .iter()
+            .filter(|entry| entry.entry_type == CatalogEntryType::CatalogIndex && entry.table_name == schema.name)
+            .filter_map(|entry| Index::load(entry, &schema))
+            .collect();
+        Ok(schema)
+    }
+
+    fn prepare_select(&self, select: &SelectStmt, catalog: &[CatalogEntry],
+        stmt: &mut Statement) -> Result<(), PrepareResult> {
+        stmt.stmt_type = StatementType::StatementSelect;
+        let core = &select.core;
+
+        /* The tables read, the one in from first, then the joined ones */
+        let no_columns: Vec<String> = Vec::new();
+        let mut tables: Vec<(&TableRef, &[String])> = Vec::new();
+        if let Some(from) = &core.from {
+            tables.push((&from.table, &no_columns));
+            for join in &from.joins {
+                match &join.constraint {
+                    JoinConstraint::JoinUsing(columns) => tables.push((&join.table, columns)),
+                    _ => tables.push((&join.table, &no_columns)),
                 }
-                id_num as u32
             }
-            Err(_) => {
-                return PrepareResult::PrepareSyntaxError;
+        }
+        let mut schemas = Vec::new();
+        match tables.is_empty() {
+            true => schemas.push(self.resolve_table(None, catalog)?),
+            false => {
+                for (table, _) in &tables {
+                    schemas.push(self.resolve_table(Some(&table.name), catalog)?);
+                }
+            }
+        }
+        let mut scope: Vec<TableScope> = Vec::new();
+        let mut offset = 0;
+        for (i, schema) in schemas.iter().enumerate() {
+            let (name, using) = match tables.get(i) {
+                Some((table, using)) => (table.alias.as_deref().unwrap_or(&schema.name), *using),
+                None => (schema.name.as_str(), &no_columns[..]),
+            };
+            scope.push(TableScope { name, schema, offset, using });
+            offset += scope[i].width();
+        }
+
+        /* Result columns with their names, stars expanded to the columns */
+        let mut columns: Vec<(String, Expr)> = Vec::new();
+        for column in &core.columns {
+            let stars: Vec<&TableScope> = match column {
+                ResultColumn::ResultStar => scope.iter().collect(),
+                ResultColumn::ResultTableStar(table) => {
+                    match scope.iter().find(|tab| tab.name.eq_ignore_ascii_case(table)) {
+                        Some(tab) => vec![tab],
+                        None => return Err(PrepareResult::PrepareNoSuchTable(table.clone())),
+                    }
+                }
+                ResultColumn::ResultExpr(expr, alias) => {
+                    let name = match (alias, expr) {
+                        (Some(alias), _) => alias.clone(),
+                        (None, Expr::ExprColumn { name, .. }) => {
+                            /* A column is named as declared, the rowid as written */
+                            scope.iter()
+                                .find_map(|tab| tab.schema.column_index(name).map(|index| tab.schema.columns[index].name.clone()))
+                                .unwrap_or_else(|| name.clone())
+                        }
+                        (None, expr) => expr.to_string(),
+                    };
+                    columns.push((name, expr.clone()));
+                    continue;
+                }
+            };
+            for tab in stars {
+                for column in &tab.schema.columns {
+                    /* A plain star shows a column joined with USING once */
+                    let merged = tab.using.iter().any(|using| using.eq_ignore_ascii_case(&column.name));
+                    if merged && matches!(core.columns[..], [ResultColumn::ResultStar]) {
+                        continue;
+                    }
+                    let expr = Expr::ExprColumn {
+                        table: Some(tab.name.to_owned()),
+                        name: column.name.clone(),
+                    };
+                    columns.push((column.name.clone(), expr));
+                }
+            }
+        }
+
+        if let Some(predicate) = &core.where_clause {
+            self.plan_filter(&scope, predicate, stmt)?;
+        }
+
+        let joins = core.from.as_ref().map_or(&[][..], |from| &from.joins[..]);
+        for (i, join) in joins.iter().enumerate() {
+            let tab = &scope[i + 1];
+            let condition = match &join.constraint {
+                JoinConstraint::JoinNone => None,
+                JoinConstraint::JoinOn(expr) => Some(bind_expr(&scope[..i + 2], expr)?),
+                JoinConstraint::JoinUsing(names) => {
+                    let mut condition: Option<BoundExpr> = None;
+                    for name in names {
+                        let mut left = scope[..i + 1].iter().filter_map(|tab| tab.column(name));
+                        let equal = match (left.next(), left.next(), tab.column(name)) {
+                            (Some(left), None, Some(right)) => BoundExpr::BoundBinary(
+                                Box::new(BoundExpr::BoundColumn(left)),
+                                BinaryOp::BinaryEq,
+                                Box::new(BoundExpr::BoundColumn(right)),
+                            ),
+                            (Some(_), Some(_), _) => {
+                                return Err(PrepareResult::PrepareInvalid(format!("ambiguous column name: {}", name)))
+                            }
+                            _ => {
+                                return Err(PrepareResult::PrepareInvalid(format!(
+                                    "cannot join using column {} - column not present in both tables",
+                                    name
+                                )))
+                            }
+                        };
+                        condition = Some(match condition {
+                            Some(condition) => {
+                                BoundExpr::BoundBinary(Box::new(condition), BinaryOp::BinaryAnd, Box::new(equal))
+                            }
+                            None => equal,
+                        });
+                    }
+                    condition
+                }
+            };
+
+            /*
+            Equalities in the where clause can pick the rows of an inner
+            join too, the filter still checks them afterwards.
+            */
+            let mut terms = condition.as_ref().map_or(Vec::new(), split_conjuncts);
+            if join.kind != JoinKind::JoinLeft {
+                if let Some(filter) = &stmt.filter {
+                    terms.extend(split_conjuncts(filter));
+                }
+            }
+            let strategy = plan_join(tab, &terms);
+            stmt.joins.push(JoinPlan {
+                schema: tab.schema.clone(),
+                kind: join.kind,
+                condition,
+                strategy,
+            });
+        }
+
+        let is_aggregate = !core.group_by.is_empty()
+            || core.having.is_some()
+            || columns.iter().any(|(_, expr)| contains_aggregate(expr))
+            || select.order_by.iter().any(|term| contains_aggregate(&term.expr));
+        if is_aggregate {
+            /* A group by term may also name a result column by number or alias */
+            let mut group_by = Vec::new();
+            for expr in &core.group_by {
+                let expr = match result_column(&columns, expr, "GROUP BY")? {
+                    Some(index) => columns[index].1.clone(),
+                    None => expr.clone(),
+                };
+                if contains_aggregate(&expr) {
+                    return Err(PrepareResult::PrepareInvalid("aggregate functions are not allowed in the GROUP BY clause".to_owned()));
+                }
+                group_by.push(expr);
             }
-        };
 
-        let username = if line_partition[2].len() <= COLUMN_USERNAME_SIZE {
-            line_partition[2]
+            let num_keys = group_by.len();
+            let mut binder = AggregateBinder::new(&scope, group_by);
+            for (name, expr) in &columns {
+                stmt.projection.push((name.clone(), binder.bind(expr)?));
+            }
+            let having = match &core.having {
+                Some(having) => Some(binder.bind(having)?),
+                None => None,
+            };
+            for term in &select.order_by {
+                let key = match result_column(&columns, &term.expr, "ORDER BY")? {
+                    Some(index) => stmt.projection[index].1.clone(),
+                    None => binder.bind(&term.expr)?,
+                };
+                stmt.order_by.push((key, term.desc));
+            }
+            /* Groups come out in the order of their keys, as in sqlite */
+            if stmt.order_by.is_empty() {
+                stmt.order_by = (0..num_keys).map(|index| (BoundExpr::BoundColumn(index), false)).collect();
+            }
+            stmt.aggregate = Some(binder.finish(having)?);
         } else {
-            return PrepareResult::PrepareStringTooLong;
+            for (name, expr) in &columns {
+                stmt.projection.push((name.clone(), bind_expr(&scope, expr)?));
+            }
+            for term in &select.order_by {
+                let key = match result_column(&columns, &term.expr, "ORDER BY")? {
+                    Some(index) => stmt.projection[index].1.clone(),
+                    None => bind_expr(&scope, &term.expr)?,
+                };
+                stmt.order_by.push((key, term.desc));
+            }
+
+            /* The scan of a single table already returns rows in rowid order, unless through an index */
+            let rowid = schemas[0].key_column.unwrap_or(schemas[0].columns.len());
+            if let [(BoundExpr::BoundColumn(index), false)] = stmt.order_by.as_slice() {
+                if *index == rowid && scope.len() == 1 && stmt.index_scan.is_none() {
+                    stmt.order_by.clear();
+                }
+            }
+        }
+
+        if let Some(limit) = &select.limit {
+            stmt.limit = limit_value("LIMIT", limit)?;
+        }
+        if let Some(offset) = &select.offset {
+            stmt.offset = limit_value("OFFSET", offset)?.unwrap_or(0);
+        }
+        stmt.schema = schemas.swap_remove(0);
+        Ok(())
+    }
+
+    fn prepare_insert(&self, insert: &InsertStmt, catalog: &[CatalogEntry],
+        stmt: &mut Statement) -> Result<(), PrepareResult> {
+        stmt.stmt_type = StatementType::StatementInsert;
+        let schema = self.resolve_table(insert.table.as_deref(), catalog)?;
+
+        let columns: Vec<usize> = match &insert.columns {
+            Some(columns) => {
+                let mut indexes = Vec::new();
+                for column in columns {
+                    match schema.column_index(column) {
+                        Some(index) => indexes.push(index),
+                        None => return Err(PrepareResult::PrepareNoSuchColumn(column.clone())),
+                    }
+                }
+                indexes
+            }
+            None => (0..schema.columns.len()).collect(),
         };
 
-        let email_len = line_partition[3].len() - 1;
-        let email = if line_partition[3].len() <= COLUMN_EMAIL_SIZE {
-            &line_partition[3][..email_len]
-        } else {
-            return PrepareResult::PrepareStringTooLong;
+        for values in &insert.rows {
+            if values.len() != columns.len() {
+                return Err(PrepareResult::PrepareInvalid(format!(
+                    "{} values for {} columns",
+                    values.len(),
+                    columns.len()
+                )));
+            }
+
+            /* Columns left out are NULL, a NULL key gets a new rowid */
+            let mut row = Row {
+                values: vec![Value::ValueNull; schema.columns.len()],
+            };
+            for (&column, value) in columns.iter().zip(values) {
+                row.values[column] = self.parse_value(&schema, column, value)?;
+            }
+            for (index, column) in schema.columns.iter().enumerate() {
+                if column.not_null && row.values[index] == Value::ValueNull && schema.key_column != Some(index) {
+                    return Err(not_null_failed(&schema, index));
+                }
+            }
+            stmt.rows_to_insert.push(row);
+        }
+        stmt.schema = schema;
+        Ok(())
+    }
+
+    fn prepare_delete(&self, delete: &DeleteStmt, catalog: &[CatalogEntry],
+        stmt: &mut Statement) -> Result<(), PrepareResult> {
+        stmt.stmt_type = StatementType::StatementDelete;
+        let schema = self.resolve_table(delete.table.as_deref(), catalog)?;
+
+        if let Some(predicate) = &delete.where_clause {
+            let scope = [TableScope { name: &schema.name, schema: &schema, offset: 0, using: &[] }];
+            self.plan_filter(&scope, predicate, stmt)?;
+        }
+        stmt.schema = schema;
+        Ok(())
+    }
+
+    fn prepare_update(&self, update: &UpdateStmt, catalog: &[CatalogEntry],
+        stmt: &mut Statement) -> Result<(), PrepareResult> {
+        stmt.stmt_type = StatementType::StatementUpdate;
+        let schema = self.resolve_table(Some(&update.table), catalog)?;
+
+        let scope = [TableScope { name: &schema.name, schema: &schema, offset: 0, using: &[] }];
+        let mut row_update = RowUpdate::default();
+        for (column, value) in &update.assignments {
+            let index = match schema.column_index(column) {
+                Some(index) => index,
+                None => return Err(PrepareResult::PrepareNoSuchColumn(column.clone())),
+            };
+
+            /* A constant is checked against its column once, other values on every row */
+            if constant_value(value).is_none() {
+                row_update.values.push((index, bind_expr(&scope, value)?));
+                continue;
+            }
+            let value = self.parse_value(&schema, index, value)?;
+            if value == Value::ValueNull && schema.key_column == Some(index) {
+                return Err(PrepareResult::PrepareInvalid(format!("{} must be an integer", column)));
+            } else if value == Value::ValueNull && schema.columns[index].not_null {
+                return Err(not_null_failed(&schema, index));
+            }
+            row_update.values.push((index, BoundExpr::BoundLiteral(value)));
+        }
+
+        if let Some(predicate) = &update.where_clause {
+            self.plan_filter(&scope, predicate, stmt)?;
+        }
+        stmt.row_update = row_update;
+        stmt.schema = schema;
+        Ok(())
+    }
+
+    /* Convert a constant to the type of a column */
+    fn parse_value(&self, schema: &Schema, index: usize, value: &Expr) -> Result<Value, PrepareResult> {
+        let literal = match constant_value(value) {
+            Some(literal) => literal,
+            None => return Err(PrepareResult::PrepareInvalid("expected a constant value".to_owned())),
         };
+        schema.columns[index]
+            .convert_value(literal_value(&literal))
+            .map_err(PrepareResult::PrepareInvalid)
+    }
+
+    /*
+    Turn a where clause on the rowid into a key range. Supported are
+    comparisons of the rowid, or the INTEGER PRIMARY KEY naming it, with
+    a constant, `id between A and B` and AND of those.
+    */
+    fn parse_key_range(&self, schema: &Schema, predicate: &Expr) -> Result<KeyRange, PrepareResult> {
+        match predicate {
+            Expr::ExprBinary(left, BinaryOp::BinaryAnd, right) => {
+                let left = self.parse_key_range(schema, left)?;
+                let right = self.parse_key_range(schema, right)?;
+                Ok(KeyRange {
+                    start: left.start.max(right.start),
+                    end: left.end.min(right.end),
+                })
+            }
+            Expr::ExprBetween {
+                expr,
+                low,
+                high,
+                negated: false,
+            } if is_key_column(schema, expr) => {
+                let low = self.parse_key_range(schema, &compare_key(BinaryOp::BinaryGtEq, low))?;
+                let high = self.parse_key_range(schema, &compare_key(BinaryOp::BinaryLtEq, high))?;
+                Ok(KeyRange {
+                    start: low.start,
+                    end: high.end,
+                })
+            }
+            Expr::ExprBinary(left, op, right) => {
+                /* Put the column on the left: 5 < id is id > 5 */
+                let (op, value) = if is_key_column(schema, left) {
+                    (*op, right)
+                } else if is_key_column(schema, right) {
+                    let flipped = match op {
+                        BinaryOp::BinaryLt => BinaryOp::BinaryGt,
+                        BinaryOp::BinaryLtEq => BinaryOp::BinaryGtEq,
+                        BinaryOp::BinaryGt => BinaryOp::BinaryLt,
+                        BinaryOp::BinaryGtEq => BinaryOp::BinaryLtEq,
+                        op => *op,
+                    };
+                    (flipped, left)
+                } else {
+                    return Err(PrepareResult::PrepareUnsupported("A WHERE clause not on the rowid"));
+                };
 
-        stmt.row_to_insert.id = id;
-        stmt.row_to_insert.set_username(username);
-        stmt.row_to_insert.set_email(email);
+                let value = match constant_value(value) {
+                    Some(Literal::LiteralInteger(value)) => value,
+                    _ => return Err(PrepareResult::PrepareInvalid("the rowid must be compared to an integer".to_owned())),
+                };
+                let (start, end) = match op {
+                    BinaryOp::BinaryEq => (value, value),
+                    BinaryOp::BinaryLt => (i64::MIN, value.saturating_sub(1)),
+                    BinaryOp::BinaryLtEq => (i64::MIN, value),
+                    BinaryOp::BinaryGt => (value.saturating_add(1), i64::MAX),
+                    BinaryOp::BinaryGtEq => (value, i64::MAX),
+                    _ => return Err(PrepareResult::PrepareUnsupported("This comparison on the rowid")),
+                };
+                Ok(key_range(start, end))
+            }
+            _ => Err(PrepareResult::PrepareUnsupported("A WHERE clause not on the rowid")),
+        }
+    }
+
+    /*
+    Bind a where clause as the filter of the statement. On a single
+    table the rowid terms narrow the keys scanned, or else an index
+    on the filtered columns may find the rows.
+    */
+    fn plan_filter(&self, scope: &[TableScope], predicate: &Expr,
+        stmt: &mut Statement) -> Result<(), PrepareResult> {
+        let filter = bind_expr(scope, predicate)?;
+        if let [table] = scope {
+            stmt.key_range = self.plan_key_range(table.schema, predicate);
+            /* An index only helps when the rowid does not narrow the scan */
+            if stmt.key_range.is_full() {
+                stmt.index_scan = plan_index_scan(table.schema, &filter);
+            }
+        }
+        stmt.filter = Some(filter);
+        Ok(())
+    }
+
+    /*
+    Keys a statement has to scan for its where clause. Terms of a top level
+    AND on the rowid narrow the range, everything else is left to the
+    filter, so this never rejects a where clause.
+    */
+    fn plan_key_range(&self, schema: &Schema, predicate: &Expr) -> KeyRange {
+        match predicate {
+            Expr::ExprBinary(left, BinaryOp::BinaryAnd, right) => {
+                let left = self.plan_key_range(schema, left);
+                let right = self.plan_key_range(schema, right);
+                KeyRange {
+                    start: left.start.max(right.start),
+                    end: left.end.min(right.end),
+                }
+            }
+            _ => self.parse_key_range(schema, predicate).unwrap_or_default(),
+        }
+    }
+}
+
+fn is_key_column(schema: &Schema, expr: &Expr) -> bool {
+    matches!(expr, Expr::ExprColumn { name, .. } if schema.is_key_column(name))
+}
+
+fn compare_key(op: BinaryOp, value: &Expr) -> Expr {
+    let rowid = Expr::ExprColumn {
+        table: None,
+        name: "rowid".to_owned(),
+    };
+    Expr::ExprBinary(Box::new(rowid), op, Box::new(value.clone()))
+}
+
+fn not_null_failed(schema: &Schema, index: usize) -> PrepareResult {
+    PrepareResult::PrepareInvalid(format!(
+        "NOT NULL constraint failed: {}.{}",
+        schema.name, schema.columns[index].name
+    ))
+}
+
+/* Inclusive range of keys, empty when start > end */
+fn key_range(start: i64, end: i64) -> KeyRange {
+    KeyRange { start, end }
+}
+
+/*
+Result column an ORDER BY or GROUP BY term names, by its number or by
+its alias. None for other terms.
+*/
+fn result_column(columns: &[(String, Expr)], term: &Expr, clause: &str) -> Result<Option<usize>, PrepareResult> {
+    match term {
+        Expr::ExprLiteral(Literal::LiteralInteger(number)) => {
+            if *number < 1 || *number as usize > columns.len() {
+                return Err(PrepareResult::PrepareInvalid(format!(
+                    "{} term {} is not between 1 and {}",
+                    clause,
+                    number,
+                    columns.len()
+                )));
+            }
+            Ok(Some(*number as usize - 1))
+        }
+        Expr::ExprColumn { table: None, name } => {
+            Ok(columns.iter().position(|(alias, _)| alias.eq_ignore_ascii_case(name)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/* Count given to LIMIT or OFFSET, None when negative, which means no limit */
+fn limit_value(clause: &str, expr: &Expr) -> Result<Option<u64>, PrepareResult> {
+    match constant_value(expr) {
+        Some(Literal::LiteralInteger(value)) => Ok(u64::try_from(value).ok()),
+        _ => Err(PrepareResult::PrepareInvalid(format!("{} must be an integer", clause))),
+    }
+}
 
-        PrepareResult::PrepareSuccess
+/* Value of a literal, possibly signed, None for anything else */
+fn constant_value(expr: &Expr) -> Option<Literal> {
+    match expr {
+        Expr::ExprLiteral(literal) => Some(literal.clone()),
+        Expr::ExprUnary(UnaryOp::UnaryPlus, expr) => constant_value(expr),
+        Expr::ExprUnary(UnaryOp::UnaryNeg, expr) => match constant_value(expr)? {
+            Literal::LiteralInteger(value) => Some(Literal::LiteralInteger(value.checked_neg()?)),
+            Literal::LiteralFloat(value) => Some(Literal::LiteralFloat(-value)),
+            _ => None,
+        },
+        _ => None,
     }
 }
@@ -0,0 +1,180 @@
+/*
+ * Record Format
+ *
+ * A row is stored as a record, as in sqlite: a header followed by the
+ * column payloads. The header is its own size as a varint, then one
+ * varint serial type per column telling how the payload is stored.
+ *
+ *   serial type   payload
+ *   0             NULL, no payload
+ *   1 2 3 4 5 6   big-endian integer of 1, 2, 3, 4, 6 or 8 bytes
+ *   7             big-endian IEEE 754 float, 8 bytes
+ *   8 9           the integer 0 or 1, no payload
+ *   N >= 12 even  blob of (N - 12) / 2 bytes
+ *   N >= 13 odd   text of (N - 13) / 2 bytes, utf-8
+ */
+use crate::service::Value;
+
+const SERIAL_TYPE_NULL: u64 = 0;
+const SERIAL_TYPE_FLOAT: u64 = 7;
+const SERIAL_TYPE_ZERO: u64 = 8;
+const SERIAL_TYPE_ONE: u64 = 9;
+const SERIAL_TYPE_BLOB: u64 = 12;
+const SERIAL_TYPE_TEXT: u64 = 13;
+
+// payload sizes of the integer serial types 1 to 6
+const INTEGER_SIZES: [usize; 6] = [1, 2, 3, 4, 6, 8];
+
+pub const VARINT_MAX_SIZE: usize = 9;
+
+/*
+Varints take 1 to 9 bytes. The first eight bytes carry 7 bits each,
+high bit set when more bytes follow; a ninth byte carries 8 bits.
+*/
+pub fn varint_size(value: u64) -> usize {
+    let mut size = 1;
+    let mut value = value >> 7;
+    while value != 0 && size < VARINT_MAX_SIZE {
+        size += 1;
+        value >>= if size == VARINT_MAX_SIZE { 8 } else { 7 };
+    }
+    size
+}
+
+pub fn put_varint(dst: &mut Vec<u8>, value: u64) {
+    if value >> 56 != 0 {
+        /* Nine bytes, the last one takes eight bits */
+        for i in 0..8 {
+            dst.push(((value >> (57 - 7 * i)) as u8 & 0x7f) | 0x80);
+        }
+        dst.push(value as u8);
+        return;
+    }
+
+    let size = varint_size(value);
+    for i in (0..size).rev() {
+        let byte = (value >> (7 * i)) as u8 & 0x7f;
+        dst.push(if i > 0 { byte | 0x80 } else { byte });
+    }
+}
+
+/* Value and size of the varint at the start of src */
+pub fn get_varint(src: &[u8]) -> Option<(u64, usize)> {
+    let mut value: u64 = 0;
+    for i in 0..VARINT_MAX_SIZE {
+        let byte = *src.get(i)?;
+        if i == VARINT_MAX_SIZE - 1 {
+            return Some(((value << 8) | byte as u64, VARINT_MAX_SIZE));
+        }
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn integer_serial_type(value: i64) -> u64 {
+    match value {
+        0 => SERIAL_TYPE_ZERO,
+        1 => SERIAL_TYPE_ONE,
+        _ => {
+            let index = INTEGER_SIZES
+                .iter()
+                .position(|&size| {
+                    let bits = size * 8 - 1;
+                    size == 8 || (value >= -(1i64 << bits) && value < (1i64 << bits))
+                })
+                .unwrap();
+            index as u64 + 1
+        }
+    }
+}
+
+fn serial_type(value: &Value) -> u64 {
+    match value {
+        Value::ValueNull => SERIAL_TYPE_NULL,
+        Value::ValueInteger(value) => integer_serial_type(*value),
+        Value::ValueReal(_) => SERIAL_TYPE_FLOAT,
+        Value::ValueBlob(blob) => SERIAL_TYPE_BLOB + 2 * blob.len() as u64,
+        Value::ValueText(text) => SERIAL_TYPE_TEXT + 2 * text.len() as u64,
+    }
+}
+
+/* Payload size of a serial type */
+pub fn serial_type_size(serial_type: u64) -> usize {
+    match serial_type {
+        1..=6 => INTEGER_SIZES[serial_type as usize - 1],
+        SERIAL_TYPE_FLOAT => 8,
+        n if n >= SERIAL_TYPE_BLOB => ((n - SERIAL_TYPE_BLOB) / 2) as usize,
+        _ => 0,
+    }
+}
+
+/* Size of a header holding serial types that take types_size bytes */
+pub fn header_size(types_size: usize) -> usize {
+    let mut size = types_size + 1;
+    while varint_size(size as u64) + types_size > size {
+        size += 1;
+    }
+    size
+}
+
+pub fn serialize_record(values: &[Value]) -> Vec<u8> {
+    let serial_types: Vec<u64> = values.iter().map(serial_type).collect();
+    let types_size: usize = serial_types.iter().map(|&t| varint_size(t)).sum();
+    let header_size = header_size(types_size);
+    let body_size: usize = serial_types.iter().map(|&t| serial_type_size(t)).sum();
+
+    let mut record = Vec::with_capacity(header_size + body_size);
+    put_varint(&mut record, header_size as u64);
+    for &serial_type in &serial_types {
+        put_varint(&mut record, serial_type);
+    }
+    for (value, &serial_type) in values.iter().zip(&serial_types) {
+        match value {
+            Value::ValueInteger(value) => {
+                let size = serial_type_size(serial_type);
+                record.extend_from_slice(&value.to_be_bytes()[(8 - size)..]);
+            }
+            Value::ValueReal(value) => record.extend_from_slice(&value.to_be_bytes()),
+            Value::ValueBlob(blob) => record.extend_from_slice(blob),
+            Value::ValueText(text) => record.extend_from_slice(text.as_bytes()),
+            Value::ValueNull => {}
+        }
+    }
+    record
+}
+
+/* Values of the record at the start of src, None when it is malformed */
+pub fn deserialize_record(src: &[u8]) -> Option<Vec<Value>> {
+    let (header_size, mut offset) = get_varint(src)?;
+    let header_size = header_size as usize;
+    let mut body = header_size;
+
+    let mut values = Vec::new();
+    while offset < header_size {
+        let (serial_type, size) = get_varint(src.get(offset..header_size)?)?;
+        offset += size;
+
+        let payload = src.get(body..(body + serial_type_size(serial_type)))?;
+        body += payload.len();
+        values.push(match serial_type {
+            SERIAL_TYPE_NULL => Value::ValueNull,
+            1..=6 => {
+                /* Sign extend from the top byte */
+                let fill = if payload[0] & 0x80 != 0 { 0xff } else { 0 };
+                let mut bytes = [fill; 8];
+                bytes[(8 - payload.len())..].copy_from_slice(payload);
+                Value::ValueInteger(i64::from_be_bytes(bytes))
+            }
+            SERIAL_TYPE_FLOAT => Value::ValueReal(f64::from_be_bytes(payload.try_into().ok()?)),
+            SERIAL_TYPE_ZERO => Value::ValueInteger(0),
+            SERIAL_TYPE_ONE => Value::ValueInteger(1),
+            n if n >= SERIAL_TYPE_BLOB && n % 2 == 0 => Value::ValueBlob(payload.to_vec()),
+            n if n >= SERIAL_TYPE_TEXT => Value::ValueText(String::from_utf8(payload.to_vec()).ok()?),
+            _ => return None,
+        });
+    }
+    Some(values)
+}
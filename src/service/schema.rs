@@ -0,0 +1,192 @@
+use crate::db::catalog::CatalogEntry;
+use crate::service::ast::{CreateTableStmt, Stmt};
+use crate::service::index::Index;
+use crate::service::parser::parse;
+use crate::service::prepare_statement::PrepareResult;
+use crate::service::{Row, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    ColumnInteger,
+    ColumnReal,
+    ColumnText,
+    ColumnBlob,
+}
+
+// names that always refer to the rowid, as in sqlite
+const ROWID_NAMES: [&str; 3] = ["rowid", "oid", "_rowid_"];
+
+impl ColumnType {
+    /*
+    Pick the column type from the declared type name, following the
+    sqlite affinity rules: INT, then CHAR/CLOB/TEXT, then BLOB, then
+    REAL/FLOA/DOUB. A column without a type holds text. Sizes such as
+    varchar(32) are ignored, as in sqlite.
+    */
+    fn from_type_name(type_name: Option<&str>) -> ColumnType {
+        let type_name = match type_name {
+            Some(type_name) => type_name.to_uppercase(),
+            None => return ColumnType::ColumnText,
+        };
+
+        if type_name.contains("INT") {
+            ColumnType::ColumnInteger
+        } else if ["CHAR", "CLOB", "TEXT"].iter().any(|word| type_name.contains(word)) {
+            ColumnType::ColumnText
+        } else if type_name.contains("BLOB") {
+            ColumnType::ColumnBlob
+        } else {
+            ColumnType::ColumnReal
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub col_type: ColumnType,
+    pub not_null: bool,
+}
+
+impl Column {
+    /*
+    Convert a value to the type of the column. Numbers and text that
+    reads as a number convert both ways, like in sqlite, but a column
+    only ever holds values of its own type. NULL stays NULL.
+    */
+    pub fn convert_value(&self, value: Value) -> Result<Value, String> {
+        match (self.col_type, value) {
+            (_, Value::ValueNull) => Ok(Value::ValueNull),
+            (ColumnType::ColumnInteger, value) => {
+                let integer = match value {
+                    Value::ValueInteger(value) => Some(value),
+                    Value::ValueReal(value) => float_to_integer(value),
+                    Value::ValueText(text) => match text.trim().parse::<i64>() {
+                        Ok(value) => Some(value),
+                        Err(_) => text.trim().parse::<f64>().ok().and_then(float_to_integer),
+                    },
+                    _ => None,
+                };
+                integer.map(Value::ValueInteger).ok_or_else(|| format!("{} must be an integer", self.name))
+            }
+            (ColumnType::ColumnReal, Value::ValueInteger(value)) => Ok(Value::ValueReal(value as f64)),
+            (ColumnType::ColumnReal, Value::ValueReal(value)) => Ok(Value::ValueReal(value)),
+            (ColumnType::ColumnReal, Value::ValueText(text)) => match text.trim().parse::<f64>() {
+                Ok(value) => Ok(Value::ValueReal(value)),
+                Err(_) => Err(format!("{} must be a number", self.name)),
+            },
+            (ColumnType::ColumnReal, _) => Err(format!("{} must be a number", self.name)),
+            /* Numbers are stored as they print */
+            (ColumnType::ColumnText, Value::ValueText(text)) => Ok(Value::ValueText(text)),
+            (ColumnType::ColumnText, Value::ValueInteger(value)) => Ok(Value::ValueText(value.to_string())),
+            (ColumnType::ColumnText, Value::ValueReal(value)) => Ok(Value::ValueText(value.to_string())),
+            (ColumnType::ColumnText, _) => Err("expected a text value".to_owned()),
+            (ColumnType::ColumnBlob, Value::ValueBlob(blob)) => Ok(Value::ValueBlob(blob)),
+            (ColumnType::ColumnBlob, _) => Err(format!("{} must be a blob", self.name)),
+        }
+    }
+}
+
+/*
+Columns of a table, built from its create table statement. Rows are
+stored as records.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub key_column: Option<usize>, // the INTEGER PRIMARY KEY, an alias of the rowid
+    pub root_page_num: usize, // of the table tree, set once the table exists
+    pub sql: String,
+    pub indexes: Vec<Index>, // loaded from the catalog with the table
+}
+
+impl Schema {
+    pub fn from_create_table(create: &CreateTableStmt) -> Result<Schema, PrepareResult> {
+        let mut schema = Schema {
+            name: create.name.clone(),
+            columns: Vec::new(),
+            key_column: None,
+            root_page_num: 0,
+            sql: create.sql.clone(),
+            indexes: Vec::new(),
+        };
+
+        for (index, def) in create.columns.iter().enumerate() {
+            if schema.column_index(&def.name).is_some() {
+                return Err(PrepareResult::PrepareInvalid(format!(
+                    "duplicate column name: {}",
+                    def.name
+                )));
+            }
+            if def.unique {
+                return Err(PrepareResult::PrepareUnsupported("UNIQUE"));
+            }
+            if def.collation.is_some() {
+                return Err(PrepareResult::PrepareUnsupported("COLLATE"));
+            }
+
+            let col_type = ColumnType::from_type_name(def.type_name.as_deref());
+            if def.primary_key {
+                if schema.key_column.is_some() {
+                    return Err(PrepareResult::PrepareInvalid(format!(
+                        "table {} has more than one primary key",
+                        create.name
+                    )));
+                }
+                if col_type != ColumnType::ColumnInteger {
+                    return Err(PrepareResult::PrepareUnsupported("A PRIMARY KEY that is not an integer"));
+                }
+                schema.key_column = Some(index);
+            }
+
+            schema.columns.push(Column {
+                name: def.name.clone(),
+                col_type,
+                not_null: def.not_null,
+            });
+        }
+
+        Ok(schema)
+    }
+
+    /* Rebuild the schema of a table from its catalog entry */
+    pub fn load(entry: &CatalogEntry) -> Option<Schema> {
+        let mut schema = match parse(&entry.sql).ok()?.as_slice() {
+            [Stmt::StmtCreateTable(create)] => Schema::from_create_table(create).ok()?,
+            _ => return None,
+        };
+        schema.root_page_num = entry.root_page_num;
+        Some(schema)
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(name))
+    }
+
+    /* True for the names the rowid goes by in this table */
+    pub fn is_key_column(&self, name: &str) -> bool {
+        match self.column_index(name) {
+            Some(index) => self.key_column == Some(index),
+            None => ROWID_NAMES.iter().any(|rowid| rowid.eq_ignore_ascii_case(name)),
+        }
+    }
+
+    /* Key of a row given by its INTEGER PRIMARY KEY, None when it needs a new rowid */
+    pub fn row_key(&self, row: &Row) -> Option<i64> {
+        match row.values[self.key_column?] {
+            Value::ValueInteger(key) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/* Floats with an integral value convert to integers, others do not */
+pub fn float_to_integer(value: f64) -> Option<i64> {
+    if value.fract() == 0.0 && value >= i64::MIN as f64 && value < i64::MAX as f64 {
+        return Some(value as i64);
+    }
+    None
+}
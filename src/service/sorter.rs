@@ -0,0 +1,305 @@
+use std::cmp::Ordering;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem::{size_of, size_of_val};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+use crate::db::error::{DbError, DbResult};
+use crate::service::expression::compare_values;
+use crate::service::record::{deserialize_record, serialize_record};
+use crate::service::Value;
+
+pub const DEFAULT_SORT_MEMORY: usize = 8 * 1024 * 1024;
+const MAX_MERGE_RUNS: usize = 16; // runs merged at once, bounds the open files
+
+static NEXT_RUN_ID: AtomicUsize = AtomicUsize::new(0);
+
+/*
+External merge sort for ORDER BY. Entries are the sort keys followed by
+the row and are kept in memory up to memory_limit bytes. Past that the
+sorted entries are written to a temporary file as a run, and finish
+merges the runs, MAX_MERGE_RUNS at a time. Entries with equal keys
+come out in the order they were added.
+
+A run is a sequence of entries, each a u32 length and a record.
+*/
+pub struct Sorter {
+    desc: Vec<bool>, // per key, whether it sorts descending
+    memory_limit: usize,
+    memory_used: usize,
+    entries: Vec<Vec<Value>>,
+    runs: Vec<File>,
+}
+
+/* Rows of a sorter in order, read with next_row */
+pub enum SortedRows {
+    SortedMemory(std::vec::IntoIter<Vec<Value>>, usize),
+    SortedMerge(Merger),
+}
+
+pub struct Merger {
+    num_keys: usize,
+    desc: Vec<bool>,
+    readers: Vec<BufReader<File>>,
+    heads: Vec<Option<Vec<Value>>>, // next entry of each reader
+}
+
+impl Sorter {
+    pub fn new(desc: Vec<bool>, memory_limit: usize) -> Self {
+        Self {
+            desc,
+            memory_limit,
+            memory_used: 0,
+            entries: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, keys: Vec<Value>, row: Vec<Value>) -> DbResult<()> {
+        let mut entry = keys;
+        entry.extend(row);
+        self.memory_used += entry_size(&entry);
+        self.entries.push(entry);
+        if self.memory_used > self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /* Write the entries in memory as a sorted run */
+    fn spill(&mut self) -> DbResult<()> {
+        let mut entries = std::mem::take(&mut self.entries);
+        sort_entries(&mut entries, &self.desc);
+        let mut writer = BufWriter::new(temp_file()?);
+        for entry in &entries {
+            write_entry(&mut writer, entry)?;
+        }
+        self.runs.push(rewind(writer)?);
+        self.memory_used = 0;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> DbResult<SortedRows> {
+        let num_keys = self.desc.len();
+        if self.runs.is_empty() {
+            sort_entries(&mut self.entries, &self.desc);
+            return Ok(SortedRows::SortedMemory(self.entries.into_iter(), num_keys));
+        }
+        if !self.entries.is_empty() {
+            self.spill()?;
+        }
+
+        /* Merge groups of runs into longer ones until one pass is left */
+        let mut runs = self.runs;
+        while runs.len() > MAX_MERGE_RUNS {
+            let mut merged = Vec::new();
+            let mut runs_left = runs.into_iter();
+            loop {
+                let group: Vec<File> = runs_left.by_ref().take(MAX_MERGE_RUNS).collect();
+                if group.is_empty() {
+                    break;
+                }
+                let mut merger = Merger::new(group, num_keys, self.desc.clone())?;
+                let mut writer = BufWriter::new(temp_file()?);
+                while let Some(entry) = merger.next_entry()? {
+                    write_entry(&mut writer, &entry)?;
+                }
+                merged.push(rewind(writer)?);
+            }
+            runs = merged;
+        }
+        Ok(SortedRows::SortedMerge(Merger::new(runs, num_keys, self.desc)?))
+    }
+}
+
+impl SortedRows {
+    /* Values of the next row, without its sort keys */
+    pub fn next_row(&mut self) -> DbResult<Option<Vec<Value>>> {
+        let (entry, num_keys) = match self {
+            SortedRows::SortedMemory(entries, num_keys) => (entries.next(), *num_keys),
+            SortedRows::SortedMerge(merger) => (merger.next_entry()?, merger.num_keys),
+        };
+        Ok(entry.map(|mut entry| entry.split_off(num_keys)))
+    }
+}
+
+impl Merger {
+    fn new(runs: Vec<File>, num_keys: usize, desc: Vec<bool>) -> DbResult<Self> {
+        let mut readers: Vec<BufReader<File>> = runs.into_iter().map(BufReader::new).collect();
+        let mut heads = Vec::new();
+        for reader in &mut readers {
+            heads.push(read_entry(reader)?);
+        }
+        Ok(Self {
+            num_keys,
+            desc,
+            readers,
+            heads,
+        })
+    }
+
+    /* Smallest head, the earliest run wins ties so the sort stays stable */
+    fn next_entry(&mut self) -> DbResult<Option<Vec<Value>>> {
+        let mut smallest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let head = match head {
+                Some(head) => head,
+                None => continue,
+            };
+            smallest = match smallest {
+                Some(j) if compare_entries(head, self.heads[j].as_ref().unwrap(), &self.desc) != Ordering::Less => {
+                    Some(j)
+                }
+                _ => Some(i),
+            };
+        }
+
+        match smallest {
+            Some(i) => {
+                let next = read_entry(&mut self.readers[i])?;
+                Ok(std::mem::replace(&mut self.heads[i], next))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn compare_entries(left: &[Value], right: &[Value], desc: &[bool]) -> Ordering {
+    for (i, &desc) in desc.iter().enumerate() {
+        let ordering = compare_values(&left[i], &right[i]);
+        if ordering != Ordering::Equal {
+            return if desc { ordering.reverse() } else { ordering };
+        }
+    }
+    Ordering::Equal
+}
+
+fn sort_entries(entries: &mut [Vec<Value>], desc: &[bool]) {
+    entries.sort_by(|left, right| compare_entries(left, right, desc));
+}
+
+/* Memory taken by an entry, roughly */
+fn entry_size(entry: &[Value]) -> usize {
+    let heap: usize = entry
+        .iter()
+        .map(|value| match value {
+            Value::ValueText(text) => text.len(),
+            Value::ValueBlob(blob) => blob.len(),
+            _ => 0,
+        })
+        .sum();
+    size_of::<Vec<Value>>() + size_of_val(entry) + heap
+}
+
+/*
+A new temporary file for spilled entries, also used by aggregation. It
+is unlinked right away, so it goes away with its File however the
+statement ends.
+*/
+pub fn temp_file() -> DbResult<File> {
+    let id = NEXT_RUN_ID.fetch_add(1, AtomicOrdering::Relaxed);
+    let path = std::env::temp_dir().join(format!("tiny-sqlite-sort-{}-{}", process::id(), id));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/* Flush a run and seek back to its start for reading */
+pub fn rewind(writer: BufWriter<File>) -> DbResult<File> {
+    let mut file = writer.into_inner().map_err(|e| DbError::IoError(e.into_error()))?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+pub fn write_entry(writer: &mut impl Write, entry: &[Value]) -> DbResult<()> {
+    let record = serialize_record(entry);
+    writer.write_all(&(record.len() as u32).to_le_bytes())?;
+    writer.write_all(&record)?;
+    Ok(())
+}
+
+/* Next entry of a run, None at its end */
+pub fn read_entry(reader: &mut impl Read) -> DbResult<Option<Vec<Value>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut record = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut record)?;
+    match deserialize_record(&record) {
+        Some(entry) => Ok(Some(entry)),
+        None => Err(DbError::Other("Sort run is corrupt.".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Sort keys with many ties, the row holds the position it was added at */
+    fn entries(n: i64) -> Vec<(Vec<Value>, Vec<Value>)> {
+        (0..n)
+            .map(|i| {
+                let keys = vec![Value::ValueInteger((i * 7919) % 97), Value::ValueText(format!("{:04}", (i * 31) % 50))];
+                (keys, vec![Value::ValueInteger(i)])
+            })
+            .collect()
+    }
+
+    fn sorted_rows(sorter: Sorter) -> Vec<Vec<Value>> {
+        let mut rows = Vec::new();
+        let mut sorted = sorter.finish().unwrap();
+        while let Some(row) = sorted.next_row().unwrap() {
+            rows.push(row);
+        }
+        rows
+    }
+
+    fn sort(desc: Vec<bool>, memory_limit: usize, entries: &[(Vec<Value>, Vec<Value>)]) -> (Vec<Vec<Value>>, usize) {
+        let mut sorter = Sorter::new(desc, memory_limit);
+        for (keys, row) in entries {
+            sorter.add(keys.clone(), row.clone()).unwrap();
+        }
+        let num_runs = sorter.runs.len();
+        (sorted_rows(sorter), num_runs)
+    }
+
+    #[test]
+    fn spilled_runs_merge_like_an_in_memory_sort() {
+        let entries = entries(5_000);
+        for desc in [vec![false, false], vec![true, false], vec![false, true]] {
+            let (expected, num_runs) = sort(desc.clone(), DEFAULT_SORT_MEMORY, &entries);
+            assert_eq!(num_runs, 0);
+            assert_eq!(expected.len(), entries.len());
+
+            /* More runs than one merge pass takes */
+            let (rows, num_runs) = sort(desc, 2_000, &entries);
+            assert!(num_runs > MAX_MERGE_RUNS, "only {} runs", num_runs);
+            assert_eq!(rows, expected);
+        }
+    }
+
+    #[test]
+    fn ties_keep_the_order_rows_were_added_in() {
+        let entries = entries(3_000);
+        let (rows, _) = sort(vec![false, false], 1_000, &entries);
+        for pair in rows.windows(2) {
+            let (left, right) = match (&pair[0][0], &pair[1][0]) {
+                (Value::ValueInteger(left), Value::ValueInteger(right)) => (*left as usize, *right as usize),
+                _ => panic!("row is not a position"),
+            };
+            match compare_entries(&entries[left].0, &entries[right].0, &[false, false]) {
+                Ordering::Less => {}
+                Ordering::Equal => assert!(left < right, "rows {} and {} swapped", left, right),
+                Ordering::Greater => panic!("rows {} and {} out of order", left, right),
+            }
+        }
+    }
+}
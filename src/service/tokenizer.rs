@@ -0,0 +1,465 @@
+use std::fmt;
+
+/*
+Lexer for the SQL dialect. Keywords are case-insensitive, identifiers
+keep their spelling and may be quoted with "..." or `...` to use a
+keyword as a name. String literals are single-quoted with '' standing
+for a quote, blobs are written x'ABCD'. Whitespace and comments
+(-- to end of line, /* ... */) separate tokens and are dropped.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keyword {
+    KeywordAnd,
+    KeywordAs,
+    KeywordAsc,
+    KeywordBegin,
+    KeywordBetween,
+    KeywordBy,
+    KeywordCollate,
+    KeywordCommit,
+    KeywordCreate,
+    KeywordCross,
+    KeywordDelete,
+    KeywordDesc,
+    KeywordFrom,
+    KeywordGroup,
+    KeywordHaving,
+    KeywordIndex,
+    KeywordInner,
+    KeywordInsert,
+    KeywordInto,
+    KeywordIs,
+    KeywordJoin,
+    KeywordKey,
+    KeywordLeft,
+    KeywordLimit,
+    KeywordNot,
+    KeywordNull,
+    KeywordOffset,
+    KeywordOn,
+    KeywordOr,
+    KeywordOrder,
+    KeywordOuter,
+    KeywordPrimary,
+    KeywordRelease,
+    KeywordRollback,
+    KeywordSavepoint,
+    KeywordSelect,
+    KeywordSet,
+    KeywordTable,
+    KeywordTo,
+    KeywordTransaction,
+    KeywordUnique,
+    KeywordUpdate,
+    KeywordUsing,
+    KeywordValues,
+    KeywordWhere,
+}
+
+const KEYWORDS: [(&str, Keyword); 45] = [
+    ("and", Keyword::KeywordAnd),
+    ("as", Keyword::KeywordAs),
+    ("asc", Keyword::KeywordAsc),
+    ("begin", Keyword::KeywordBegin),
+    ("between", Keyword::KeywordBetween),
+    ("by", Keyword::KeywordBy),
+    ("collate", Keyword::KeywordCollate),
+    ("commit", Keyword::KeywordCommit),
+    ("create", Keyword::KeywordCreate),
+    ("cross", Keyword::KeywordCross),
+    ("delete", Keyword::KeywordDelete),
+    ("desc", Keyword::KeywordDesc),
+    ("from", Keyword::KeywordFrom),
+    ("group", Keyword::KeywordGroup),
+    ("having", Keyword::KeywordHaving),
+    ("index", Keyword::KeywordIndex),
+    ("inner", Keyword::KeywordInner),
+    ("insert", Keyword::KeywordInsert),
+    ("into", Keyword::KeywordInto),
+    ("is", Keyword::KeywordIs),
+    ("join", Keyword::KeywordJoin),
+    ("key", Keyword::KeywordKey),
+    ("left", Keyword::KeywordLeft),
+    ("limit", Keyword::KeywordLimit),
+    ("not", Keyword::KeywordNot),
+    ("null", Keyword::KeywordNull),
+    ("offset", Keyword::KeywordOffset),
+    ("on", Keyword::KeywordOn),
+    ("or", Keyword::KeywordOr),
+    ("order", Keyword::KeywordOrder),
+    ("outer", Keyword::KeywordOuter),
+    ("primary", Keyword::KeywordPrimary),
+    ("release", Keyword::KeywordRelease),
+    ("rollback", Keyword::KeywordRollback),
+    ("savepoint", Keyword::KeywordSavepoint),
+    ("select", Keyword::KeywordSelect),
+    ("set", Keyword::KeywordSet),
+    ("table", Keyword::KeywordTable),
+    ("to", Keyword::KeywordTo),
+    ("transaction", Keyword::KeywordTransaction),
+    ("unique", Keyword::KeywordUnique),
+    ("update", Keyword::KeywordUpdate),
+    ("using", Keyword::KeywordUsing),
+    ("values", Keyword::KeywordValues),
+    ("where", Keyword::KeywordWhere),
+];
+
+impl Keyword {
+    fn lookup(word: &str) -> Option<Keyword> {
+        KEYWORDS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(word))
+            .map(|&(_, keyword)| keyword)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        KEYWORDS
+            .iter()
+            .find(|(_, keyword)| keyword == self)
+            .map(|&(name, _)| name)
+            .unwrap_or("?")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    TokenKeyword(Keyword),
+    TokenIdentifier(String),
+    TokenString(String),
+    TokenBlob(Vec<u8>),
+    TokenInteger(i64),
+    TokenFloat(f64),
+    TokenLeftParen,
+    TokenRightParen,
+    TokenComma,
+    TokenSemicolon,
+    TokenDot,
+    TokenStar,
+    TokenPlus,
+    TokenMinus,
+    TokenSlash,
+    TokenPercent,
+    TokenConcat,
+    TokenEq,
+    TokenNotEq,
+    TokenLt,
+    TokenLtEq,
+    TokenGt,
+    TokenGtEq,
+    TokenEof,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::TokenKeyword(keyword) => write!(f, "{}", keyword.as_str().to_uppercase()),
+            TokenKind::TokenIdentifier(name) => write!(f, "{}", name),
+            TokenKind::TokenString(value) => write!(f, "'{}'", value.replace('\'', "''")),
+            TokenKind::TokenBlob(_) => write!(f, "blob literal"),
+            TokenKind::TokenInteger(value) => write!(f, "{}", value),
+            TokenKind::TokenFloat(value) => write!(f, "{}", value),
+            TokenKind::TokenLeftParen => write!(f, "("),
+            TokenKind::TokenRightParen => write!(f, ")"),
+            TokenKind::TokenComma => write!(f, ","),
+            TokenKind::TokenSemicolon => write!(f, ";"),
+            TokenKind::TokenDot => write!(f, "."),
+            TokenKind::TokenStar => write!(f, "*"),
+            TokenKind::TokenPlus => write!(f, "+"),
+            TokenKind::TokenMinus => write!(f, "-"),
+            TokenKind::TokenSlash => write!(f, "/"),
+            TokenKind::TokenPercent => write!(f, "%"),
+            TokenKind::TokenConcat => write!(f, "||"),
+            TokenKind::TokenEq => write!(f, "="),
+            TokenKind::TokenNotEq => write!(f, "!="),
+            TokenKind::TokenLt => write!(f, "<"),
+            TokenKind::TokenLtEq => write!(f, "<="),
+            TokenKind::TokenGt => write!(f, ">"),
+            TokenKind::TokenGtEq => write!(f, ">="),
+            TokenKind::TokenEof => write!(f, "end of input"),
+        }
+    }
+}
+
+// 1-based line and column of the first character of a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub pos: Position,
+    pub offset: usize, // index of the first character in the input
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenizeError {
+    pub message: String,
+    pub pos: Position,
+}
+
+impl fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.pos.line, self.pos.column, self.message)
+    }
+}
+
+/*
+Split a statement into tokens. The result always ends with a
+TokenEof carrying the position just past the input.
+*/
+pub fn tokenize(sql: &str) -> Result<Vec<Token>, TokenizeError> {
+    let mut tokenizer = Tokenizer::new(sql);
+    let mut tokens = Vec::new();
+    loop {
+        let token = tokenizer.next_token()?;
+        let done = token.kind == TokenKind::TokenEof;
+        tokens.push(token);
+        if done {
+            return Ok(tokens);
+        }
+    }
+}
+
+struct Tokenizer {
+    chars: Vec<char>,
+    offset: usize,
+    pos: Position,
+}
+
+impl Tokenizer {
+    fn new(sql: &str) -> Self {
+        Self {
+            chars: sql.chars().collect(),
+            offset: 0,
+            pos: Position { line: 1, column: 1 },
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.offset).copied()
+    }
+
+    fn peek_at(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.offset + ahead).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += 1;
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        } else {
+            self.pos.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error<T>(&self, message: &str, pos: Position) -> Result<T, TokenizeError> {
+        Err(TokenizeError {
+            message: message.to_owned(),
+            pos,
+        })
+    }
+
+    /* Skip whitespace and both comment styles */
+    fn skip_trivia(&mut self) -> Result<(), TokenizeError> {
+        loop {
+            match (self.peek(), self.peek_at(1)) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.bump();
+                }
+                (Some('-'), Some('-')) => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.bump();
+                    }
+                }
+                (Some('/'), Some('*')) => {
+                    let start = self.pos;
+                    self.bump();
+                    self.bump();
+                    loop {
+                        match (self.peek(), self.peek_at(1)) {
+                            (Some('*'), Some('/')) => {
+                                self.bump();
+                                self.bump();
+                                break;
+                            }
+                            (Some(_), _) => {
+                                self.bump();
+                            }
+                            (None, _) => return self.error("unterminated comment", start),
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token, TokenizeError> {
+        self.skip_trivia()?;
+
+        let pos = self.pos;
+        let offset = self.offset;
+        let c = match self.peek() {
+            Some(c) => c,
+            None => {
+                return Ok(Token {
+                    kind: TokenKind::TokenEof,
+                    pos,
+                    offset,
+                })
+            }
+        };
+
+        let kind = match c {
+            '\'' => TokenKind::TokenString(self.quoted('\'')?),
+            '"' | '`' => TokenKind::TokenIdentifier(self.quoted(c)?),
+            'x' | 'X' if self.peek_at(1) == Some('\'') => {
+                self.bump();
+                self.blob(pos)?
+            }
+            c if c.is_ascii_digit() => self.number(pos)?,
+            '.' if self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) => self.number(pos)?,
+            c if c.is_alphabetic() || c == '_' => {
+                let word = self.word();
+                match Keyword::lookup(&word) {
+                    Some(keyword) => TokenKind::TokenKeyword(keyword),
+                    None => TokenKind::TokenIdentifier(word),
+                }
+            }
+            _ => self.symbol(pos)?,
+        };
+        Ok(Token { kind, pos, offset })
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if !(c.is_alphanumeric() || c == '_' || c == '$') {
+                break;
+            }
+            word.push(c);
+            self.bump();
+        }
+        word
+    }
+
+    /* Text between quotes, a doubled quote stands for itself */
+    fn quoted(&mut self, quote: char) -> Result<String, TokenizeError> {
+        let start = self.pos;
+        self.bump();
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                Some(c) if c == quote => {
+                    if self.peek() != Some(quote) {
+                        return Ok(text);
+                    }
+                    self.bump();
+                    text.push(quote);
+                }
+                Some(c) => text.push(c),
+                None if quote == '\'' => return self.error("unterminated string literal", start),
+                None => return self.error("unterminated quoted identifier", start),
+            }
+        }
+    }
+
+    fn blob(&mut self, start: Position) -> Result<TokenKind, TokenizeError> {
+        let hex = self.quoted('\'')?;
+        if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return self.error("malformed blob literal", start);
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        Ok(TokenKind::TokenBlob(bytes))
+    }
+
+    /*
+    Integer or float literal. Integers too large for i64 become floats,
+    as in SQLite.
+    */
+    fn number(&mut self, start: Position) -> Result<TokenKind, TokenizeError> {
+        let mut text = String::new();
+        let mut is_float = false;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() {
+                text.push(c);
+            } else if c == '.' && !is_float {
+                is_float = true;
+                text.push(c);
+            } else {
+                break;
+            }
+            self.bump();
+        }
+
+        if matches!(self.peek(), Some('e') | Some('E')) {
+            let sign = matches!(self.peek_at(1), Some('+') | Some('-'));
+            let digit_at = if sign { 2 } else { 1 };
+            if self.peek_at(digit_at).is_some_and(|c| c.is_ascii_digit()) {
+                is_float = true;
+                for _ in 0..digit_at {
+                    text.push(self.bump().unwrap());
+                }
+                while let Some(c) = self.peek().filter(|c| c.is_ascii_digit()) {
+                    text.push(c);
+                    self.bump();
+                }
+            }
+        }
+
+        if self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            return self.error("malformed number", start);
+        }
+
+        if !is_float {
+            if let Ok(value) = text.parse::<i64>() {
+                return Ok(TokenKind::TokenInteger(value));
+            }
+        }
+        match text.parse::<f64>() {
+            Ok(value) => Ok(TokenKind::TokenFloat(value)),
+            Err(_) => self.error("malformed number", start),
+        }
+    }
+
+    fn symbol(&mut self, start: Position) -> Result<TokenKind, TokenizeError> {
+        let c = self.bump().unwrap();
+        let next = self.peek();
+        let (kind, two_chars) = match (c, next) {
+            ('(', _) => (TokenKind::TokenLeftParen, false),
+            (')', _) => (TokenKind::TokenRightParen, false),
+            (',', _) => (TokenKind::TokenComma, false),
+            (';', _) => (TokenKind::TokenSemicolon, false),
+            ('.', _) => (TokenKind::TokenDot, false),
+            ('*', _) => (TokenKind::TokenStar, false),
+            ('+', _) => (TokenKind::TokenPlus, false),
+            ('-', _) => (TokenKind::TokenMinus, false),
+            ('/', _) => (TokenKind::TokenSlash, false),
+            ('%', _) => (TokenKind::TokenPercent, false),
+            ('|', Some('|')) => (TokenKind::TokenConcat, true),
+            ('=', Some('=')) => (TokenKind::TokenEq, true),
+            ('=', _) => (TokenKind::TokenEq, false),
+            ('!', Some('=')) => (TokenKind::TokenNotEq, true),
+            ('<', Some('>')) => (TokenKind::TokenNotEq, true),
+            ('<', Some('=')) => (TokenKind::TokenLtEq, true),
+            ('<', _) => (TokenKind::TokenLt, false),
+            ('>', Some('=')) => (TokenKind::TokenGtEq, true),
+            ('>', _) => (TokenKind::TokenGt, false),
+            _ => return self.error(&format!("unexpected character '{}'", c), start),
+        };
+        if two_chars {
+            self.bump();
+        }
+        Ok(kind)
+    }
+}